@@ -1,27 +1,60 @@
-mod monitor;
+pub mod hart;
+pub mod icache;
+pub mod intc;
+pub mod monitor;
+pub mod paging;
+pub mod trap;
 
 use std::{
     slice,
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{self, Ordering},
+    },
     time::{Duration, SystemTime},
 };
 
 use bytemuck::{AnyBitPattern, NoUninit};
+use enumflags2::BitFlag as _;
 use log::trace;
 use strum::Display;
 use yansi::Paint;
 
 use crate::{
     BitSize,
-    cpu::monitor::{Monitor, MonitorArgs},
+    cpu::{
+        hart::Harts,
+        icache::ICache,
+        monitor::{Monitor, MonitorArgs},
+        paging::Tlb,
+        trap::TrapCause,
+    },
+    dev::{Devices, storage::StorageError},
     instruction::{Instruction, InstructionType},
-    mmu::{MemError, Mmu},
+    mmu::{MemError, Mmu, Prot, Protection},
 };
 
+/// `status` bit enabling interrupt delivery
+pub const STATUS_IE: BitSize = 1 << 0;
+/// `status` bit set while running in user mode
+pub const STATUS_USER: BitSize = 1 << 1;
+/// `status` bit holding the privilege level from before the last trap
+pub const STATUS_TPU: BitSize = 1 << 2;
+/// `status` bit holding the privilege level from before the last interrupt
+pub const STATUS_IPU: BitSize = 1 << 3;
+/// `status` bit holding [`STATUS_IE`] from before the last trap
+pub const STATUS_TIE: BitSize = 1 << 4;
+
 #[derive(Debug, thiserror::Error, PartialEq)]
 pub enum CpuError {
     #[error("Unsupported instruction: {0:?}")]
     UnsupportedInst(Instruction),
+    #[error("Unknown control register: {0}")]
+    UnknownCr(u8),
+    #[error("Can't start hart {0}, it doesn't exist or is already running")]
+    HartStart(BitSize),
+    #[error("Privileged instruction in user mode: {0}")]
+    Privileged(InstructionType),
     #[error("Stack underflow: 0x{0:0>8x}")]
     StackUnderflow(u32),
     #[error("Stack overflow: 0x{0:0>8x}")]
@@ -30,6 +63,10 @@ pub enum CpuError {
     Mem(#[from] MemError),
     #[error("{0}")]
     MiniFb(String),
+    #[error("Console: {0}")]
+    Console(String),
+    #[error("{0}")]
+    Storage(#[from] StorageError),
 }
 
 #[derive(Debug)]
@@ -42,6 +79,16 @@ pub struct Cpu {
     pub pc: BitSize,
     /// clock counter
     pub clk: u64,
+    /// control registers
+    pub cr: CtrlRegisters,
+    /// inside a trap handler, a fault here can't be vectored again
+    pub in_trap: bool,
+    /// operand of the last `hlt`
+    pub exit_code: BitSize,
+    /// cached page translations
+    pub tlb: Tlb,
+    /// cached decoded instructions
+    pub icache: ICache,
     // other stuff
     pub mon: Option<Monitor>,
 }
@@ -54,14 +101,28 @@ impl Cpu {
             gfx: 0,
             pc: 0,
             clk: 0,
+            cr: Default::default(),
+            in_trap: false,
+            exit_code: 0,
+            tlb: Tlb::default(),
+            icache: ICache::default(),
             mon: None,
         }
     }
 
+    /// Cpu for a secondary hart
+    pub fn with_hartid(id: BitSize) -> Self {
+        let mut this = Self::new();
+        this.cr.hartid = id;
+        this
+    }
+
     pub fn process(
         &mut self,
         inst: Instruction,
         mmu: &Arc<Mmu>,
+        dev: &Devices,
+        harts: &Harts,
         stop: &mut bool,
         clk: &mut u32,
     ) -> Result<(), CpuError> {
@@ -83,36 +144,45 @@ impl Cpu {
             };
         }
 
+        // hartid is readable everywhere, so user code can tell harts apart
+        let hartid = inst.ty == Rdcr && Cr::try_from(inst.a as u8) == Ok(Cr::Hartid);
+        if self.is_user() && inst.ty.is_privileged() && !hartid {
+            return Err(CpuError::Privileged(inst.ty));
+        }
+
         match inst.ty {
             Nop => (),
 
             Hlt => {
+                self.exit_code = self.gp.get_reg(inst.a);
                 *stop = true;
                 return Ok(());
             }
 
             Pr => {
-                todo!()
-                // let low = self.gp.get_reg(inst.a);
-                // let high = self.gp.get_reg(inst.b);
+                let low = self.gp.get_reg(inst.a);
+                let high = self.gp.get_reg(inst.b);
 
-                // if let Some(view) = mmu.view(low..high) {
-                //     let data = view.as_bstr();
-                //     print!("{data}");
-                //     *clk += 100;
-                // }
+                if let Some(data) = self.read_range(mmu, low, high)? {
+                    dev.console
+                        .print(&data)
+                        .map_err(|e| CpuError::Console(e.to_string()))?;
+
+                    *clk += 100;
+                }
             }
 
             Epr => {
-                todo!()
-                // let low = self.gp.get_reg(inst.a);
-                // let high = self.gp.get_reg(inst.b);
+                let low = self.gp.get_reg(inst.a);
+                let high = self.gp.get_reg(inst.b);
 
-                // if let Some(view) = mmu.view(low..high) {
-                //     let data = view.as_bstr();
-                //     eprint!("{data}");
-                //     *clk += 100;
-                // }
+                if let Some(data) = self.read_range(mmu, low, high)? {
+                    dev.console
+                        .eprint(&data)
+                        .map_err(|e| CpuError::Console(e.to_string()))?;
+
+                    *clk += 100;
+                }
             }
 
             Tme => {
@@ -137,7 +207,13 @@ impl Cpu {
             }
 
             Kbrd => {
-                unimplemented!();
+                // the window feeds the queue while it exists
+                if self.mon.is_none() {
+                    dev.kbd.poll_stdin();
+                }
+
+                let code = dev.kbd.pop().unwrap_or(0);
+                self.gp.set_reg(inst.dst, code);
             }
 
             Gfx => {
@@ -160,7 +236,7 @@ impl Cpu {
 
                     None => {
                         self.mon = Some(
-                            Monitor::new(0x80000000, mmu.clone(), args)
+                            Monitor::new(0x80000000, mmu.clone(), dev.kbd.clone(), args)
                                 .map_err(|e| CpuError::MiniFb(e.to_string()))?,
                         )
                     }
@@ -207,7 +283,69 @@ impl Cpu {
                 let val = get_imm_or!(inst.a);
                 let count = self.gp.get_reg(inst.b);
                 let dst = self.gp.get_reg(inst.dst);
-                mmu.memset(dst, val, count)?;
+
+                self.set_mem(mmu, dst, val, count)?;
+            }
+
+            Rdcr => {
+                let cr = Cr::try_from(inst.a as u8)?;
+                self.gp.set_reg(inst.dst, self.get_cr(cr));
+            }
+
+            Wrcr => {
+                let cr = Cr::try_from(inst.dst as u8)?;
+                self.set_cr(cr, get_imm_or!(inst.a));
+            }
+
+            Trapret => {
+                self.pc = self.cr.tepc;
+                self.in_trap = false;
+                self.restore_privilege(STATUS_TPU);
+
+                self.cr.status = match self.cr.status & STATUS_TIE != 0 {
+                    true => self.cr.status | STATUS_IE,
+                    false => self.cr.status & !STATUS_IE,
+                };
+
+                return Ok(());
+            }
+
+            Mprot => {
+                let low = self.gp.get_reg(inst.a);
+                let high = self.gp.get_reg(inst.b);
+                let prot: BitSize = get_imm_or!(inst.dst);
+
+                // unknown bits are ignored
+                let prot = Prot::from_bits_truncate(prot as u8);
+
+                if high > low {
+                    mmu.set_prot(low..high, prot);
+                }
+            }
+
+            Ei => {
+                self.cr.status |= STATUS_IE;
+            }
+
+            Di => {
+                self.cr.status &= !STATUS_IE;
+            }
+
+            Iret => {
+                self.pc = self.cr.iepc;
+                self.cr.status |= STATUS_IE;
+                self.restore_privilege(STATUS_IPU);
+                return Ok(());
+            }
+
+            Tlbflush => {
+                self.tlb.flush();
+            }
+
+            Hstart => {
+                let id = self.gp.get_reg(inst.a);
+                let pc = self.gp.get_reg(inst.b);
+                harts.start(id, pc, get_imm_or!(inst.dst))?;
             }
 
             #[rustfmt::skip]
@@ -216,59 +354,132 @@ impl Cpu {
             //
 
             Ld => {
-                let val = mmu.read(get_imm_or!(inst.a))?;
+                let val = self.load(mmu, get_imm_or!(inst.a), 4)?;
                 self.gp.set_reg(inst.dst, val);
             }
 
             Ldw => {
-                let val = mmu.read::<u16>(get_imm_or!(inst.a))?;
-                self.gp.set_reg(inst.dst, val as BitSize);
+                let val = self.load(mmu, get_imm_or!(inst.a), 2)?;
+                self.gp.set_reg(inst.dst, val);
             }
 
             Ldb => {
-                let val = mmu.read::<u8>(get_imm_or!(inst.a))?;
-                self.gp.set_reg(inst.dst, val as BitSize);
+                let val = self.load(mmu, get_imm_or!(inst.a), 1)?;
+                self.gp.set_reg(inst.dst, val);
             }
 
             Pld => {
-                unimplemented!()
+                let mut buf = [0; size_of::<BitSize>()];
+                dev.storage()?.read(get_imm_or!(inst.a), &mut buf)?;
+                self.gp.set_reg(inst.dst, BitSize::from_le_bytes(buf));
             }
 
             Pldw => {
-                unimplemented!()
+                let mut buf = [0; size_of::<u16>()];
+                dev.storage()?.read(get_imm_or!(inst.a), &mut buf)?;
+                self.gp
+                    .set_reg(inst.dst, u16::from_le_bytes(buf) as BitSize);
             }
 
             Pldb => {
-                unimplemented!()
+                let mut buf = [0; size_of::<u8>()];
+                dev.storage()?.read(get_imm_or!(inst.a), &mut buf)?;
+                self.gp.set_reg(inst.dst, u8::from_le_bytes(buf) as BitSize);
             }
 
             Str => {
                 let dst = self.gp.get_reg(inst.dst);
-                mmu.write(dst, get_imm_or!(inst.a))?;
+                self.store(mmu, dst, get_imm_or!(inst.a), 4)?;
             }
 
             Strw => {
                 let dst = self.gp.get_reg(inst.dst);
-                mmu.write(dst, get_imm_or!(inst.a) as u16)?;
+                self.store(mmu, dst, get_imm_or!(inst.a), 2)?;
             }
 
             Strb => {
                 let dst = self.gp.get_reg(inst.dst);
-                mmu.write(dst, get_imm_or!(inst.a) as u8)?;
+                self.store(mmu, dst, get_imm_or!(inst.a), 1)?;
             }
 
             Pstr => {
-                unimplemented!()
+                let dst = self.gp.get_reg(inst.dst);
+                let val: BitSize = get_imm_or!(inst.a);
+                dev.storage()?.write(dst, &val.to_le_bytes())?;
             }
 
             Pstrw => {
-                unimplemented!()
+                let dst = self.gp.get_reg(inst.dst);
+                let val: BitSize = get_imm_or!(inst.a);
+                dev.storage()?.write(dst, &(val as u16).to_le_bytes())?;
             }
 
             Pstrb => {
-                unimplemented!()
+                let dst = self.gp.get_reg(inst.dst);
+                let val: BitSize = get_imm_or!(inst.a);
+                dev.storage()?.write(dst, &(val as u8).to_le_bytes())?;
+            }
+
+            // [reg + imm]
+            Ldo | Ldwo | Ldbo => {
+                let addr = self.gp.get_reg(inst.a).wrapping_add(inst.imm);
+                let val = self.load(mmu, addr, inst.ty.access_size())?;
+                self.gp.set_reg(inst.dst, val);
+            }
+
+            Stro | Strwo | Strbo => {
+                let addr = self.gp.get_reg(inst.dst).wrapping_add(inst.imm);
+                let val = self.gp.get_reg(inst.a);
+                self.store(mmu, addr, val, inst.ty.access_size())?;
+            }
+
+            Ldws | Ldbs => {
+                let val = self.load(mmu, get_imm_or!(inst.a), inst.ty.access_size())?;
+                self.gp.set_reg(inst.dst, inst.ty.extend(val));
+            }
+
+            Ldwso | Ldbso => {
+                let addr = self.gp.get_reg(inst.a).wrapping_add(inst.imm);
+                let val = self.load(mmu, addr, inst.ty.access_size())?;
+                self.gp.set_reg(inst.dst, inst.ty.extend(val));
+            }
+
+            // [pc + imm]
+            Ldr | Ldwr | Ldbr => {
+                let addr = self.pc.wrapping_add(inst.imm);
+                let val = self.load(mmu, addr, inst.ty.access_size())?;
+                self.gp.set_reg(inst.dst, val);
+            }
+
+            // dst holds the expected value, and gets the old one
+            Cas => {
+                let addr = self.atomic_addr(mmu, self.gp.get_reg(inst.a))?;
+                let current = self.gp.get_reg(inst.dst);
+                let new = self.gp.get_reg(inst.b);
+
+                let old = mmu.cas_unchecked(addr, current, new)?;
+                self.gp.set_reg(inst.dst, old);
+            }
+
+            Amoadd => {
+                let addr = self.atomic_addr(mmu, self.gp.get_reg(inst.a))?;
+                let old = mmu.fetch_add_unchecked(addr, get_imm_or!(inst.b))?;
+                self.gp.set_reg(inst.dst, old);
             }
 
+            Amoswap => {
+                let addr = self.atomic_addr(mmu, self.gp.get_reg(inst.a))?;
+                let old = mmu.swap_unchecked(addr, get_imm_or!(inst.b))?;
+                self.gp.set_reg(inst.dst, old);
+            }
+
+            Fence => {
+                atomic::fence(Ordering::SeqCst);
+            }
+
+            // needs the handler table, so the hart runs these itself
+            Ecall => return Err(CpuError::UnsupportedInst(inst)),
+
             #[rustfmt::skip]
             //
             // MATH
@@ -351,6 +562,46 @@ impl Cpu {
                 self.gp.set_reg(inst.dst, a.wrapping_mul(b) as u32);
             }
 
+            // high half of the 64-bit product
+            Mulh => {
+                let a = self.gp.get_reg(inst.a) as i32 as i64;
+                let b = get_imm_or!(inst.b) as i32 as i64;
+
+                self.gp.set_reg(inst.dst, ((a * b) >> 32) as u32);
+            }
+
+            Mulhu => {
+                let a = self.gp.get_reg(inst.a) as u64;
+                let b = get_imm_or!(inst.b) as u64;
+
+                self.gp.set_reg(inst.dst, ((a * b) >> 32) as u32);
+            }
+
+            // c carries in and out, so chains of these add multi-word values
+            Addc => {
+                let a = self.gp.get_reg(inst.a);
+                let b = self.gp.get_reg(inst.b);
+                let carry = self.gp.get_reg(inst.c) & 1;
+
+                let (val, c1) = a.overflowing_add(b);
+                let (val, c2) = val.overflowing_add(carry);
+
+                self.gp.set_reg(inst.dst, val);
+                self.gp.set_reg(inst.c, (c1 | c2) as BitSize);
+            }
+
+            Subb => {
+                let a = self.gp.get_reg(inst.a);
+                let b = self.gp.get_reg(inst.b);
+                let borrow = self.gp.get_reg(inst.c) & 1;
+
+                let (val, b1) = a.overflowing_sub(b);
+                let (val, b2) = val.overflowing_sub(borrow);
+
+                self.gp.set_reg(inst.dst, val);
+                self.gp.set_reg(inst.c, (b1 | b2) as BitSize);
+            }
+
             Div => {
                 let a = self.gp.get_reg(inst.a);
                 let b = get_imm_or!(inst.b);
@@ -445,6 +696,163 @@ impl Cpu {
                 self.gp.set_reg(inst.dst, (a >> b) as u32);
             }
 
+            #[rustfmt::skip]
+            //
+            // FLOAT
+            //
+
+            Fadd => {
+                let a = f32::from_bits(self.gp.get_reg(inst.a));
+                let b = f32::from_bits(get_imm_or!(inst.b));
+
+                self.gp.set_reg(inst.dst, (a + b).to_bits());
+            }
+
+            Fsub => {
+                let a = f32::from_bits(self.gp.get_reg(inst.a));
+                let b = f32::from_bits(get_imm_or!(inst.b));
+
+                self.gp.set_reg(inst.dst, (a - b).to_bits());
+            }
+
+            Fmul => {
+                let a = f32::from_bits(self.gp.get_reg(inst.a));
+                let b = f32::from_bits(get_imm_or!(inst.b));
+
+                self.gp.set_reg(inst.dst, (a * b).to_bits());
+            }
+
+            Fdiv => {
+                let a = f32::from_bits(self.gp.get_reg(inst.a));
+                let b = f32::from_bits(get_imm_or!(inst.b));
+
+                self.gp.set_reg(inst.dst, (a / b).to_bits());
+            }
+
+            Fsqrt => {
+                let a = f32::from_bits(self.gp.get_reg(inst.a));
+                self.gp.set_reg(inst.dst, a.sqrt().to_bits());
+            }
+
+            // comparisons with NaN are always false
+            Fse => {
+                let a = f32::from_bits(self.gp.get_reg(inst.a));
+                let b = f32::from_bits(get_imm_or!(inst.b));
+
+                self.gp.set_reg(inst.dst, (a == b) as _);
+            }
+
+            Fsl => {
+                let a = f32::from_bits(self.gp.get_reg(inst.a));
+                let b = f32::from_bits(get_imm_or!(inst.b));
+
+                self.gp.set_reg(inst.dst, (a < b) as _);
+            }
+
+            Fsle => {
+                let a = f32::from_bits(self.gp.get_reg(inst.a));
+                let b = f32::from_bits(get_imm_or!(inst.b));
+
+                self.gp.set_reg(inst.dst, (a <= b) as _);
+            }
+
+            Itof => {
+                let a = get_imm_or!(inst.a) as i32;
+                self.gp.set_reg(inst.dst, (a as f32).to_bits());
+            }
+
+            Utof => {
+                let a: BitSize = get_imm_or!(inst.a);
+                self.gp.set_reg(inst.dst, (a as f32).to_bits());
+            }
+
+            // truncates toward zero, saturating. NaN converts to 0
+            Ftoi => {
+                let a = f32::from_bits(self.gp.get_reg(inst.a));
+                self.gp.set_reg(inst.dst, a as i32 as u32);
+            }
+
+            Ftou => {
+                let a = f32::from_bits(self.gp.get_reg(inst.a));
+                self.gp.set_reg(inst.dst, a as u32);
+            }
+
+            #[rustfmt::skip]
+            //
+            // BITS
+            //
+
+            Clz => {
+                let a = self.gp.get_reg(inst.a);
+                self.gp.set_reg(inst.dst, a.leading_zeros());
+            }
+
+            Ctz => {
+                let a = self.gp.get_reg(inst.a);
+                self.gp.set_reg(inst.dst, a.trailing_zeros());
+            }
+
+            Popcnt => {
+                let a = self.gp.get_reg(inst.a);
+                self.gp.set_reg(inst.dst, a.count_ones());
+            }
+
+            Rol => {
+                let a = self.gp.get_reg(inst.a);
+                let b = get_imm_or!(inst.b);
+
+                self.gp.set_reg(inst.dst, a.rotate_left(b));
+            }
+
+            Ror => {
+                let a = self.gp.get_reg(inst.a);
+                let b = get_imm_or!(inst.b);
+
+                self.gp.set_reg(inst.dst, a.rotate_right(b));
+            }
+
+            Bswap => {
+                let a = self.gp.get_reg(inst.a);
+                self.gp.set_reg(inst.dst, a.swap_bytes());
+            }
+
+            Min => {
+                let a = self.gp.get_reg(inst.a) as i32;
+                let b = get_imm_or!(inst.b) as i32;
+
+                self.gp.set_reg(inst.dst, a.min(b) as u32);
+            }
+
+            Max => {
+                let a = self.gp.get_reg(inst.a) as i32;
+                let b = get_imm_or!(inst.b) as i32;
+
+                self.gp.set_reg(inst.dst, a.max(b) as u32);
+            }
+
+            Minu => {
+                let a = self.gp.get_reg(inst.a);
+                let b = get_imm_or!(inst.b);
+
+                self.gp.set_reg(inst.dst, a.min(b));
+            }
+
+            Maxu => {
+                let a = self.gp.get_reg(inst.a);
+                let b = get_imm_or!(inst.b);
+
+                self.gp.set_reg(inst.dst, a.max(b));
+            }
+
+            // dst is the condition: a if it's non-zero, else b
+            Csel => {
+                let a = self.gp.get_reg(inst.a);
+                let b = get_imm_or!(inst.b);
+
+                let val = if self.gp.get_reg(inst.dst) != 0 { a } else { b };
+                self.gp.set_reg(inst.dst, val);
+            }
+
             #[rustfmt::skip]
             //
             // CONDITIONALS
@@ -456,6 +864,21 @@ impl Cpu {
                 return Ok(());
             }
 
+            Jmpr => {
+                self.pc = self.pc.wrapping_add(inst.imm);
+                return Ok(());
+            }
+
+            Jer | Jner | Jlr | Jger | Jler | Jgr | Jbr | Jaer | Jber | Jar => {
+                let a = self.gp.get_reg(inst.a);
+                let b = self.gp.get_reg(inst.b);
+
+                if inst.ty.branch_taken(a, b) {
+                    self.pc = self.pc.wrapping_add(inst.imm);
+                    return Ok(());
+                }
+            }
+
             Je => {
                 let dst = get_imm_or!(inst.dst);
 
@@ -584,24 +1007,25 @@ impl Cpu {
             Push => {
                 let a = self.gp.get_reg(inst.a);
 
-                self.gp.sp = self
+                let sp = self
                     .gp
                     .sp
-                    .checked_sub(3)
+                    .checked_sub(4)
                     .ok_or(CpuError::StackOverflow(self.pc))?;
 
-                mmu.write_unchecked(self.gp.sp, a)?;
+                self.stack_write(mmu, sp, a)?;
+                self.gp.sp = sp;
 
                 *clk = 2;
             }
 
             Pop => {
-                let data = mmu.read_unchecked(self.gp.sp)?;
+                let data = self.stack_read(mmu, self.gp.sp)?;
 
                 self.gp.sp = self
                     .gp
                     .sp
-                    .checked_add(3)
+                    .checked_add(4)
                     .ok_or(CpuError::StackUnderflow(self.gp.sp))?;
 
                 self.gp.set_reg(inst.dst, data);
@@ -610,14 +1034,15 @@ impl Cpu {
             }
 
             Call => {
-                self.gp.sp = self
+                let sp = self
                     .gp
                     .sp
-                    .checked_sub(3)
+                    .checked_sub(4)
                     .ok_or(CpuError::StackOverflow(self.pc))?;
 
                 // write return address to stack
-                mmu.write_unchecked(self.gp.sp, self.gp.ra)?;
+                self.stack_write(mmu, sp, self.gp.ra)?;
+                self.gp.sp = sp;
 
                 let jmp = get_imm_or!(inst.a);
 
@@ -634,16 +1059,36 @@ impl Cpu {
                 return Ok(());
             }
 
+            Callr => {
+                let sp = self
+                    .gp
+                    .sp
+                    .checked_sub(4)
+                    .ok_or(CpuError::StackOverflow(self.pc))?;
+
+                self.stack_write(mmu, sp, self.gp.ra)?;
+                self.gp.sp = sp;
+
+                self.gp.ra = self.pc.wrapping_add(8);
+                self.pc = self.pc.wrapping_add(inst.imm);
+
+                *clk = 3;
+
+                return Ok(());
+            }
+
             Ret => {
+                let ra = self.stack_read(mmu, self.gp.sp)?;
+
                 // jmp to return addr
                 self.pc = self.gp.ra;
 
-                self.gp.ra = mmu.read_unchecked(self.gp.sp)?;
+                self.gp.ra = ra;
 
                 self.gp.sp = self
                     .gp
                     .sp
-                    .checked_add(3)
+                    .checked_add(4)
                     .ok_or(CpuError::StackUnderflow(self.gp.sp))?;
 
                 *clk = 2;
@@ -657,13 +1102,228 @@ impl Cpu {
         Ok(())
     }
 
+    /// Copy guest range \[low, high) out of memory, checking it's readable.
+    /// Returns None for an empty range
+    fn read_range(
+        &mut self,
+        mmu: &Mmu,
+        low: BitSize,
+        high: BitSize,
+    ) -> Result<Option<Vec<u8>>, CpuError> {
+        if high <= low {
+            return Ok(None);
+        }
+
+        let mut data = vec![0; (high - low) as usize];
+        self.read_mem(mmu, low, &mut data, Prot::Read)?;
+
+        Ok(Some(data))
+    }
+
+    pub fn get_cr(&self, cr: Cr) -> BitSize {
+        match cr {
+            Cr::Tvec => self.cr.tvec,
+            Cr::Tepc => self.cr.tepc,
+            Cr::Tcause => self.cr.tcause,
+            Cr::Tval => self.cr.tval,
+            Cr::Status => self.cr.status,
+            Cr::Ie => self.cr.ie,
+            Cr::Ip => self.cr.ip,
+            Cr::Ivec => self.cr.ivec,
+            Cr::Iepc => self.cr.iepc,
+            Cr::Icause => self.cr.icause,
+            Cr::Ptbr => self.cr.ptbr,
+            Cr::Hartid => self.cr.hartid,
+            // cycles left until it fires
+            Cr::Timer => self.cr.timer.saturating_sub(self.clk) as BitSize,
+        }
+    }
+
+    pub fn set_cr(&mut self, cr: Cr, val: BitSize) {
+        match cr {
+            Cr::Tvec => self.cr.tvec = val,
+            Cr::Tepc => self.cr.tepc = val,
+            Cr::Tcause => self.cr.tcause = val,
+            Cr::Tval => self.cr.tval = val,
+            Cr::Status => self.cr.status = val,
+            Cr::Ie => self.cr.ie = val,
+            Cr::Ip => self.cr.ip = val,
+            Cr::Ivec => self.cr.ivec = val,
+            Cr::Iepc => self.cr.iepc = val,
+            Cr::Icause => self.cr.icause = val,
+            // a new address space, nothing cached is valid anymore
+            Cr::Ptbr => {
+                self.cr.ptbr = val;
+                self.tlb.flush();
+            }
+            // read only
+            Cr::Hartid => (),
+            // fire after val cycles, 0 disarms
+            Cr::Timer => {
+                self.cr.timer = match val {
+                    0 => 0,
+                    n => self.clk + n as u64,
+                }
+            }
+        }
+    }
+
+    /// Running in user mode
+    #[inline]
+    pub fn is_user(&self) -> bool {
+        self.cr.status & STATUS_USER != 0
+    }
+
+    /// Protection an access needs at the current privilege level
+    #[inline]
+    pub fn access(&self, prot: Prot) -> Protection {
+        if self.is_user() {
+            prot | Prot::User
+        } else {
+            prot.into()
+        }
+    }
+
+    /// Stack accesses are only checked in user mode, or when they need translating
+    pub(crate) fn stack_read(&mut self, mmu: &Mmu, addr: BitSize) -> Result<BitSize, MemError> {
+        if self.is_user() || self.paging() {
+            self.load(mmu, addr, 4)
+        } else {
+            mmu.read_unchecked(addr)
+        }
+    }
+
+    pub(crate) fn stack_write(
+        &mut self,
+        mmu: &Mmu,
+        addr: BitSize,
+        val: BitSize,
+    ) -> Result<(), MemError> {
+        if self.is_user() || self.paging() {
+            self.store(mmu, addr, val, 4)
+        } else {
+            mmu.write_unchecked(addr, val)
+        }
+    }
+
+    /// Vector to the trap handler, saving where and why we trapped.
+    /// Traps always run in supervisor mode, with interrupts disabled
+    pub fn trap(&mut self, cause: TrapCause, val: BitSize) {
+        self.cr.status = match self.is_user() {
+            true => self.cr.status | STATUS_TPU,
+            false => self.cr.status & !STATUS_TPU,
+        };
+
+        // interrupts wait until trapret, so they can't fault inside the handler
+        self.cr.status = match self.cr.status & STATUS_IE != 0 {
+            true => self.cr.status | STATUS_TIE,
+            false => self.cr.status & !STATUS_TIE,
+        };
+        self.cr.status &= !(STATUS_USER | STATUS_IE);
+
+        self.cr.tepc = self.pc;
+        self.cr.tcause = cause as BitSize;
+        self.cr.tval = val;
+        self.pc = self.cr.tvec;
+        self.in_trap = true;
+    }
+
+    /// Go back to the privilege level saved in the `prev` status bit
+    fn restore_privilege(&mut self, prev: BitSize) {
+        self.cr.status = match self.cr.status & prev != 0 {
+            true => self.cr.status | STATUS_USER,
+            false => self.cr.status & !STATUS_USER,
+        };
+    }
+
+    /// Whether a fault can be vectored to a guest handler right now
+    pub fn can_trap(&self) -> bool {
+        self.cr.tvec != 0 && !self.in_trap
+    }
+
     /// zero all registers
     #[allow(unused)]
     pub fn zeroize(&mut self) {
-        *self = Self::new();
+        *self = Self::with_hartid(self.cr.hartid);
     }
 }
 
+/// Control register index, as encoded in `rdcr` / `wrcr`
+#[derive(Copy, Clone, Debug, Display, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+pub enum Cr {
+    Tvec,
+    Tepc,
+    Tcause,
+    Tval,
+    Status,
+    Ie,
+    Ip,
+    Ivec,
+    Iepc,
+    Icause,
+    Timer,
+    Ptbr,
+    Hartid,
+}
+
+impl TryFrom<u8> for Cr {
+    type Error = CpuError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let cr = match value {
+            0x00 => Self::Tvec,
+            0x01 => Self::Tepc,
+            0x02 => Self::Tcause,
+            0x03 => Self::Tval,
+            0x04 => Self::Status,
+            0x05 => Self::Ie,
+            0x06 => Self::Ip,
+            0x07 => Self::Ivec,
+            0x08 => Self::Iepc,
+            0x09 => Self::Icause,
+            0x0a => Self::Timer,
+            0x0b => Self::Ptbr,
+            0x0c => Self::Hartid,
+
+            _ => return Err(CpuError::UnknownCr(value)),
+        };
+
+        Ok(cr)
+    }
+}
+
+/// Control registers
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CtrlRegisters {
+    /// trap handler address, 0 disables trapping
+    pub tvec: BitSize,
+    /// pc of the instruction that trapped
+    pub tepc: BitSize,
+    /// why we trapped, see [`TrapCause`]
+    pub tcause: BitSize,
+    /// faulting address
+    pub tval: BitSize,
+    /// cpu state flags, see the `STATUS_*` constants
+    pub status: BitSize,
+    /// mask of enabled interrupt sources, bit N is [`Irq`] N
+    pub ie: BitSize,
+    /// pending interrupt sources
+    pub ip: BitSize,
+    /// interrupt handler address, 0 disables interrupts
+    pub ivec: BitSize,
+    /// pc to resume at after the interrupt handler
+    pub iepc: BitSize,
+    /// [`Irq`] being handled
+    pub icause: BitSize,
+    /// clk the timer fires at, 0 when disarmed
+    pub timer: u64,
+    /// page table address, 0 turns translation off. See [`paging`]
+    pub ptbr: BitSize,
+    /// id of this hart, 0 is the boot hart
+    pub hartid: BitSize,
+}
+
 #[derive(Copy, Clone, Debug, Display, PartialEq)]
 #[strum(serialize_all = "lowercase")]
 pub enum Reg {
@@ -756,7 +1416,7 @@ impl_reg!(u8 u32);
 /// \[r\] - caller saved
 /// \[e\] - callee saved
 #[repr(C)]
-#[derive(Copy, Clone, NoUninit, AnyBitPattern, Debug)]
+#[derive(Copy, Clone, NoUninit, AnyBitPattern, Debug, PartialEq)]
 pub struct Registers {
     /// zero register
     pub zr: BitSize,
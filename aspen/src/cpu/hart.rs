@@ -0,0 +1,133 @@
+use std::sync::{
+    Condvar, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+
+use crate::{BitSize, cpu::CpuError};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+enum State {
+    /// waiting for `hstart`
+    #[default]
+    Parked,
+    /// `hstart` was called, not picked up yet
+    Start {
+        pc: BitSize,
+        arg: BitSize,
+    },
+    Running,
+    /// the emulator is finished, the thread should exit
+    Exit,
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    state: Mutex<State>,
+    cv: Condvar,
+}
+
+/// Shared between the hart threads of one run, lets harts start each other.
+/// Hart 0 is the boot hart, it's always running and can't be started
+#[derive(Debug)]
+pub struct Harts {
+    slots: Vec<Slot>,
+    /// parked harts exit instead of waiting, running ones after their next `hlt`
+    exit: AtomicBool,
+    /// every hart stops as soon as possible, something faulted
+    stop: AtomicBool,
+}
+
+impl Harts {
+    pub fn new(count: usize) -> Self {
+        Self {
+            slots: (0..count.max(1)).map(|_| Slot::default()).collect(),
+            exit: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+        }
+    }
+
+    /// Number of harts, including the boot hart
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Start a parked hart at pc, with arg in its a0
+    pub fn start(&self, id: BitSize, pc: BitSize, arg: BitSize) -> Result<(), CpuError> {
+        let slot = match id {
+            0 => None,
+            id => self.slots.get(id as usize),
+        };
+
+        let slot = slot.ok_or(CpuError::HartStart(id))?;
+        let mut state = slot.state.lock().unwrap();
+
+        if *state != State::Parked {
+            return Err(CpuError::HartStart(id));
+        }
+
+        *state = State::Start { pc, arg };
+        slot.cv.notify_one();
+
+        Ok(())
+    }
+
+    /// Block until the hart is started, giving its pc and arg.
+    /// None once the emulator is finished
+    pub fn wait(&self, id: usize) -> Option<(BitSize, BitSize)> {
+        let slot = &self.slots[id];
+        let mut state = slot.state.lock().unwrap();
+
+        loop {
+            match *state {
+                State::Start { pc, arg } => {
+                    *state = State::Running;
+                    return Some((pc, arg));
+                }
+
+                State::Exit => return None,
+
+                _ => state = slot.cv.wait(state).unwrap(),
+            }
+        }
+    }
+
+    /// The hart ran `hlt`, it can be started again
+    pub fn halted(&self, id: usize) {
+        let mut state = self.slots[id].state.lock().unwrap();
+
+        *state = match self.exit.load(Ordering::Acquire) {
+            true => State::Exit,
+            false => State::Parked,
+        };
+    }
+
+    /// The boot hart halted. Parked harts exit, running ones are left to finish
+    pub fn shutdown(&self) {
+        self.exit.store(true, Ordering::Release);
+
+        for slot in &self.slots {
+            let mut state = slot.state.lock().unwrap();
+            // a hart that was already started still gets to run
+            if *state == State::Parked {
+                *state = State::Exit;
+            }
+
+            slot.cv.notify_one();
+        }
+    }
+
+    /// Stop every hart, running or not
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Release);
+        self.shutdown();
+    }
+
+    #[inline]
+    pub fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+}
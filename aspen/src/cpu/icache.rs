@@ -0,0 +1,124 @@
+//! Decoded instruction cache.
+//!
+//! Pages of decoded instructions are keyed by physical address and tagged with the
+//! page's [`Mmu::code_gen`], so any write to a cached page (from any hart) or a
+//! change to its protection makes the whole page get decoded again.
+
+use crate::{
+    BitSize,
+    cpu::Cpu,
+    instruction::Instruction,
+    mmu::{MemError, Mmu, PAGE_SIZE, Prot, Protection},
+};
+
+const PAGE_MASK: BitSize = PAGE_SIZE as BitSize - 1;
+const SLOTS: usize = PAGE_SIZE / 4;
+const CACHE_SIZE: usize = 64;
+
+#[derive(Debug)]
+struct CodePage {
+    /// page address + 1, so that 0 is an empty slot
+    tag: BitSize,
+    generation: BitSize,
+    /// physical protection when the page was cached
+    prot: Protection,
+    insts: Box<[Option<Instruction>; SLOTS]>,
+}
+
+/// Direct-mapped cache of decoded code pages
+#[derive(Debug)]
+pub struct ICache {
+    pages: Vec<Option<CodePage>>,
+}
+
+impl Default for ICache {
+    fn default() -> Self {
+        Self {
+            pages: (0..CACHE_SIZE).map(|_| None).collect(),
+        }
+    }
+}
+
+impl ICache {
+    /// Cached page for the physical address, emptied if it went stale
+    fn page(&mut self, mmu: &Mmu, paddr: BitSize) -> &mut CodePage {
+        let base = paddr & !PAGE_MASK;
+        let slot = &mut self.pages[(paddr >> 12) as usize % CACHE_SIZE];
+
+        let fresh = slot
+            .as_ref()
+            .is_some_and(|p| p.tag == base + 1 && p.generation == mmu.code_gen(paddr));
+
+        if !fresh {
+            let generation = mmu.mark_code(paddr);
+            let prot = mmu.prot(paddr);
+
+            match slot {
+                Some(page) => {
+                    page.tag = base + 1;
+                    page.generation = generation;
+                    page.prot = prot;
+                    page.insts.fill(None);
+                }
+
+                None => {
+                    *slot = Some(CodePage {
+                        tag: base + 1,
+                        generation,
+                        prot,
+                        insts: Box::new([None; SLOTS]),
+                    })
+                }
+            }
+        }
+
+        slot.as_mut().unwrap()
+    }
+
+    /// Drop everything
+    pub fn flush(&mut self) {
+        self.pages.iter_mut().for_each(|p| *p = None);
+    }
+}
+
+impl Cpu {
+    /// Fetch and decode pc through the instruction cache.
+    /// Ok(None) if it can't be cached, fetch it the slow way instead
+    pub fn fetch_cached(&mut self, mmu: &Mmu) -> Result<Option<Instruction>, MemError> {
+        let pc = self.pc;
+
+        // unaligned, or the imm word might be on the next page
+        if !pc.is_multiple_of(4) || pc & PAGE_MASK > PAGE_MASK - 7 {
+            return Ok(None);
+        }
+
+        let paddr = match self.paging() {
+            true => self.translate(mmu, pc, Prot::Execute)?,
+            false => pc,
+        };
+
+        let req = self.access(Prot::Execute);
+        let paging = self.paging();
+        let page = self.icache.page(mmu, paddr);
+
+        // translation already checked the page table entry,
+        // otherwise let the slow path report the fault
+        if !paging && !page.prot.contains(req) {
+            return Ok(None);
+        }
+
+        let slot = &mut page.insts[(paddr & PAGE_MASK) as usize / 4];
+        if let Some(inst) = *slot {
+            return Ok(Some(inst));
+        }
+
+        let mut buf = [0; 8];
+        mmu.memcpy(paddr, &mut buf)?;
+
+        // errors aren't cached, the slow path reports them
+        let inst = Instruction::from_buf(buf).ok();
+        *slot = inst;
+
+        Ok(inst)
+    }
+}
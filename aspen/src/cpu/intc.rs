@@ -0,0 +1,75 @@
+use crate::{
+    BitSize,
+    cpu::{Cpu, STATUS_IE, STATUS_IPU, STATUS_USER},
+    dev::Devices,
+    mmu::Mmu,
+};
+
+/// Interrupt sources, the discriminant is the bit in `ie` / `ip`.
+/// Lower numbers are higher priority
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Irq {
+    /// `timer` ran out
+    Timer = 0,
+    /// keyboard queue isn't empty (level triggered)
+    Keyboard = 1,
+    /// a mapped device raised its irq (level triggered)
+    Device = 2,
+}
+
+impl Irq {
+    pub const fn bit(self) -> BitSize {
+        1 << self as u32
+    }
+}
+
+impl Cpu {
+    /// Latch interrupt sources into `ip`
+    #[inline]
+    pub fn poll_irqs(&mut self, dev: &Devices, mmu: &Mmu) {
+        if self.cr.timer != 0 && self.clk >= self.cr.timer {
+            self.cr.timer = 0;
+            self.cr.ip |= Irq::Timer.bit();
+        }
+
+        // only look at the queue when someone is listening, it takes a lock
+        if self.cr.ie & Irq::Keyboard.bit() != 0 {
+            match dev.kbd.is_empty() {
+                true => self.cr.ip &= !Irq::Keyboard.bit(),
+                false => self.cr.ip |= Irq::Keyboard.bit(),
+            }
+        }
+
+        match mmu.device_irq() {
+            true => self.cr.ip |= Irq::Device.bit(),
+            false => self.cr.ip &= !Irq::Device.bit(),
+        }
+    }
+
+    /// Enter the interrupt handler (in supervisor mode) if an enabled interrupt is pending.
+    /// The taken interrupt is acknowledged, and interrupts stay disabled until `iret`
+    #[inline]
+    pub fn interrupt(&mut self) -> bool {
+        let active = self.cr.ip & self.cr.ie;
+
+        if self.cr.status & STATUS_IE == 0 || active == 0 || self.cr.ivec == 0 {
+            return false;
+        }
+
+        // lowest bit wins
+        let irq = active.trailing_zeros();
+
+        self.cr.ip &= !(1 << irq);
+        self.cr.iepc = self.pc;
+        self.cr.icause = irq;
+        self.cr.status = match self.is_user() {
+            true => self.cr.status | STATUS_IPU,
+            false => self.cr.status & !STATUS_IPU,
+        };
+        self.cr.status &= !(STATUS_IE | STATUS_USER);
+        self.pc = self.cr.ivec;
+
+        true
+    }
+}
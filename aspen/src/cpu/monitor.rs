@@ -8,7 +8,11 @@ use std::{
 
 use minifb::{Scale, ScaleMode, Window, WindowOptions};
 
-use crate::{BitSize, mmu::Mmu};
+use crate::{
+    BitSize,
+    dev::keyboard::{Keyboard, WindowInput},
+    mmu::Mmu,
+};
 
 enum ReqCommand {
     /// Request redraw
@@ -22,7 +26,7 @@ enum Command {
     Finished,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct MonitorArgs {
     pub width: u16,
     pub height: u16,
@@ -33,13 +37,25 @@ pub struct MonitorArgs {
 pub struct Monitor {
     tx: Sender<ReqCommand>,
     rx: Receiver<Command>,
+    addr: BitSize,
+    args: MonitorArgs,
 }
 
 impl Monitor {
-    pub fn new(addr: BitSize, mmu: Arc<Mmu>, args: MonitorArgs) -> minifb::Result<Self> {
+    pub fn new(
+        addr: BitSize,
+        mmu: Arc<Mmu>,
+        kbd: Arc<Keyboard>,
+        args: MonitorArgs,
+    ) -> minifb::Result<Self> {
         let (tx, rx) = channel();
         let (reply_tx, reply_rx) = channel();
-        let this = Self { tx, rx: reply_rx };
+        let this = Self {
+            tx,
+            rx: reply_rx,
+            addr,
+            args,
+        };
 
         thread::spawn(move || {
             let mut vram = vec![0u32; args.width as usize * args.height as usize];
@@ -64,6 +80,8 @@ impl Monitor {
             ).unwrap());
 
             window.set_target_fps(args.fps as _);
+            // key events are delivered on window update, so once per draw
+            window.set_input_callback(Box::new(WindowInput(kbd)));
 
             while let Ok(c) = rx.recv() {
                 match c {
@@ -86,6 +104,15 @@ impl Monitor {
         Ok(this)
     }
 
+    /// Where the framebuffer is read from
+    pub fn addr(&self) -> BitSize {
+        self.addr
+    }
+
+    pub fn args(&self) -> MonitorArgs {
+        self.args
+    }
+
     pub fn draw(&self) {
         self.tx.send(ReqCommand::Draw).unwrap();
         self.rx.recv().unwrap();
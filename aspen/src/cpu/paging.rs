@@ -0,0 +1,369 @@
+//! Guest page tables.
+//!
+//! Writing a page table address to `ptbr` turns translation on, 0 turns it off.
+//! The table is two levels, both 1024 entries of 4 bytes (one page each):
+//!
+//! ```text
+//! vaddr: LLLLLLLL LLTTTTTT TTTTOOOO OOOOOOOO
+//!   L - index into the root table, pointed at by ptbr
+//!   T - index into the leaf table, pointed at by the root entry
+//!   O - offset into the page
+//!
+//! entry: PPPPPPPP PPPPPPPP PPPP0000 000VUXWR
+//!   P - physical page (of the leaf table, or of the mapped page)
+//!   V - valid
+//!   U/X/W/R - same bits as `Prot`, ignored in root entries
+//! ```
+//!
+//! While translating, access is decided only by the leaf entry; the physical
+//! page protection set with `mprot` doesn't apply.
+
+use enumflags2::BitFlag as _;
+
+use crate::{
+    BitSize,
+    cpu::Cpu,
+    mmu::{MemError, Mmu, PAGE_SIZE, Prot, Protection},
+};
+
+/// Page table entry is valid
+pub const PTE_VALID: BitSize = 1 << 4;
+/// Page table entry protection bits, see [`Prot`]
+pub const PTE_PROT: BitSize = 0xf;
+
+const PAGE_MASK: BitSize = PAGE_SIZE as BitSize - 1;
+const TLB_SIZE: usize = 64;
+
+#[derive(Copy, Clone, Debug, Default)]
+struct TlbEntry {
+    /// virtual page number + 1, so that 0 is an empty slot
+    tag: BitSize,
+    /// physical page address
+    page: BitSize,
+    prot: Protection,
+}
+
+/// Direct-mapped cache of leaf page table entries
+#[derive(Debug)]
+pub struct Tlb {
+    entries: [TlbEntry; TLB_SIZE],
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self {
+            entries: [TlbEntry::default(); TLB_SIZE],
+        }
+    }
+}
+
+impl Tlb {
+    fn lookup(&self, vpn: BitSize) -> Option<TlbEntry> {
+        let entry = self.entries[vpn as usize % TLB_SIZE];
+        (entry.tag == vpn + 1).then_some(entry)
+    }
+
+    fn insert(&mut self, vpn: BitSize, page: BitSize, prot: Protection) {
+        self.entries[vpn as usize % TLB_SIZE] = TlbEntry {
+            tag: vpn + 1,
+            page,
+            prot,
+        };
+    }
+
+    /// Drop all cached translations
+    pub fn flush(&mut self) {
+        self.entries = [TlbEntry::default(); TLB_SIZE];
+    }
+}
+
+impl Cpu {
+    /// Translation is on
+    #[inline]
+    pub fn paging(&self) -> bool {
+        self.cr.ptbr != 0
+    }
+
+    /// Translate a virtual address for an access needing `prot`.
+    /// Faults report the virtual address
+    pub fn translate(
+        &mut self,
+        mmu: &Mmu,
+        vaddr: BitSize,
+        prot: Prot,
+    ) -> Result<BitSize, MemError> {
+        let req = self.access(prot);
+
+        if !self.paging() {
+            mmu.check_prot(vaddr, req)?;
+            return Ok(vaddr);
+        }
+
+        let vpn = vaddr >> 12;
+        let entry = match self.tlb.lookup(vpn) {
+            Some(entry) => entry,
+            None => {
+                let pte = self.walk(mmu, vaddr)?;
+                let prot = Prot::from_bits_truncate((pte & PTE_PROT) as u8);
+                self.tlb.insert(vpn, pte & !PAGE_MASK, prot);
+                self.tlb.lookup(vpn).unwrap()
+            }
+        };
+
+        if !entry.prot.contains(req) {
+            // same reporting as the physical protection check
+            let missing = match !entry.prot & req {
+                i if i == Prot::User => req,
+                i => i,
+            };
+
+            return Err(MemError::PageFault(missing, vaddr));
+        }
+
+        Ok(entry.page | (vaddr & PAGE_MASK))
+    }
+
+    /// Find the leaf entry for vaddr
+    fn walk(&self, mmu: &Mmu, vaddr: BitSize) -> Result<BitSize, MemError> {
+        let root = self.cr.ptbr & !PAGE_MASK;
+        let pde: BitSize = mmu.read_unchecked(root + (vaddr >> 22) * 4)?;
+        if pde & PTE_VALID == 0 {
+            return Err(MemError::NotMapped(vaddr));
+        }
+
+        let leaf = pde & !PAGE_MASK;
+        let pte: BitSize = mmu.read_unchecked(leaf + ((vaddr >> 12) & 0x3ff) * 4)?;
+        if pte & PTE_VALID == 0 {
+            return Err(MemError::NotMapped(vaddr));
+        }
+
+        Ok(pte)
+    }
+
+    /// Copy guest memory at vaddr into buf, needing `prot` on every page
+    pub fn read_mem(
+        &mut self,
+        mmu: &Mmu,
+        vaddr: BitSize,
+        buf: &mut [u8],
+        prot: Prot,
+    ) -> Result<(), MemError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        if !self.paging() {
+            let end = end_of(vaddr, buf.len())?;
+            mmu.check_prot(vaddr..=end, self.access(prot))?;
+            return mmu.memcpy(vaddr, buf);
+        }
+
+        for (addr, off, len) in page_chunks(vaddr, buf.len())? {
+            let paddr = self.translate(mmu, addr, prot)?;
+            mmu.memcpy(paddr, &mut buf[off..off + len])?;
+        }
+
+        Ok(())
+    }
+
+    /// Copy buf into guest memory at vaddr. Nothing is written if any page faults
+    pub fn write_mem(&mut self, mmu: &Mmu, vaddr: BitSize, buf: &[u8]) -> Result<(), MemError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        if !self.paging() {
+            let end = end_of(vaddr, buf.len())?;
+            mmu.check_prot(vaddr..=end, self.access(Prot::Write))?;
+            return mmu.memwrite(vaddr, buf);
+        }
+
+        self.check_writable(mmu, vaddr, buf.len())?;
+
+        for (addr, off, len) in page_chunks(vaddr, buf.len())? {
+            let paddr = self.translate(mmu, addr, Prot::Write)?;
+            mmu.memwrite(paddr, &buf[off..off + len])?;
+        }
+
+        Ok(())
+    }
+
+    /// memset through translation. Nothing is written if any page faults
+    pub fn set_mem(
+        &mut self,
+        mmu: &Mmu,
+        vaddr: BitSize,
+        val: BitSize,
+        count: BitSize,
+    ) -> Result<(), MemError> {
+        if count == 0 {
+            return Ok(());
+        }
+
+        if !self.paging() {
+            let end = end_of(vaddr, count as usize)?;
+            mmu.check_prot(vaddr..=end, self.access(Prot::Write))?;
+            return mmu.memset(vaddr, val, count);
+        }
+
+        self.check_writable(mmu, vaddr, count as usize)?;
+
+        for (addr, off, len) in page_chunks(vaddr, count as usize)? {
+            let paddr = self.translate(mmu, addr, Prot::Write)?;
+            // keep the pattern lined up with the start of the whole range
+            let val = val.rotate_right(8 * (off % 4) as u32);
+            mmu.memset(paddr, val, len as BitSize)?;
+        }
+
+        Ok(())
+    }
+
+    /// Load a 1, 2 or 4 byte little endian value
+    pub fn load(&mut self, mmu: &Mmu, vaddr: BitSize, size: usize) -> Result<BitSize, MemError> {
+        if let Some(paddr) = self.io_addr(mmu, vaddr, size, Prot::Read)?
+            && let Some(val) = mmu.io_read(paddr, size)
+        {
+            return Ok(val);
+        }
+
+        if !self.paging() {
+            let req = self.access(Prot::Read);
+            return match size {
+                1 => mmu.read_with::<u8>(vaddr, req).map(Into::into),
+                2 => mmu.read_with::<u16>(vaddr, req).map(Into::into),
+                _ => mmu.read_with(vaddr, req),
+            };
+        }
+
+        let mut buf = [0; size_of::<BitSize>()];
+        self.read_mem(mmu, vaddr, &mut buf[..size], Prot::Read)?;
+        Ok(BitSize::from_le_bytes(buf))
+    }
+
+    /// Store the low 1, 2 or 4 bytes of val, little endian
+    pub fn store(
+        &mut self,
+        mmu: &Mmu,
+        vaddr: BitSize,
+        val: BitSize,
+        size: usize,
+    ) -> Result<(), MemError> {
+        if let Some(paddr) = self.io_addr(mmu, vaddr, size, Prot::Write)?
+            && mmu.io_write(paddr, size, val)
+        {
+            return Ok(());
+        }
+
+        if !self.paging() {
+            let req = self.access(Prot::Write);
+            return match size {
+                1 => mmu.write_with(vaddr, val as u8, req),
+                2 => mmu.write_with(vaddr, val as u16, req),
+                _ => mmu.write_with(vaddr, val, req),
+            };
+        }
+
+        self.write_mem(mmu, vaddr, &val.to_le_bytes()[..size])
+    }
+
+    /// Physical address of an access that may hit a device, after checking protection.
+    /// Accesses crossing a page always go to RAM
+    fn io_addr(
+        &mut self,
+        mmu: &Mmu,
+        vaddr: BitSize,
+        size: usize,
+        prot: Prot,
+    ) -> Result<Option<BitSize>, MemError> {
+        let end = end_of(vaddr, size)?;
+        if vaddr >> 12 != end >> 12 {
+            return Ok(None);
+        }
+
+        if !self.paging() {
+            if !mmu.is_io(vaddr) {
+                return Ok(None);
+            }
+
+            mmu.check_prot(vaddr..=end, self.access(prot))?;
+            return Ok(Some(vaddr));
+        }
+
+        let paddr = self.translate(mmu, vaddr, prot)?;
+        Ok(mmu.is_io(paddr).then_some(paddr))
+    }
+
+    /// Physical address of an aligned word for an atomic read-modify-write.
+    /// Needs write access, like a store
+    pub fn atomic_addr(&mut self, mmu: &Mmu, vaddr: BitSize) -> Result<BitSize, MemError> {
+        if !vaddr.is_multiple_of(4) {
+            return Err(MemError::Misaligned(vaddr));
+        }
+
+        self.translate(mmu, vaddr, Prot::Write)
+    }
+
+    /// Fetch the instruction at pc. The immediate word is only
+    /// fetched (and needs to be mapped) if the instruction has one
+    pub fn fetch(&mut self, mmu: &Mmu) -> Result<[u8; 8], MemError> {
+        let mut buf = [0; 8];
+
+        if !self.paging() {
+            mmu.memcpy(self.pc, &mut buf)?;
+            mmu.check_prot(self.pc, self.access(Prot::Execute))?;
+            return Ok(buf);
+        }
+
+        let pc = self.pc;
+        self.read_mem(mmu, pc, &mut buf[..4], Prot::Execute)?;
+
+        // imm bit of the control byte
+        if buf[0] & 0b0010_0000 != 0 {
+            let imm = pc.checked_add(4).ok_or(MemError::Overflow)?;
+            self.read_mem(mmu, imm, &mut buf[4..], Prot::Execute)?;
+        }
+
+        Ok(buf)
+    }
+
+    /// Translate every page of a write up front, so a fault on a later page
+    /// doesn't leave the earlier ones half written
+    fn check_writable(&mut self, mmu: &Mmu, vaddr: BitSize, len: usize) -> Result<(), MemError> {
+        for (addr, ..) in page_chunks(vaddr, len)?.skip(1) {
+            self.translate(mmu, addr, Prot::Write)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Last byte of a `len` byte access
+fn end_of(vaddr: BitSize, len: usize) -> Result<BitSize, MemError> {
+    vaddr
+        .checked_add(len.saturating_sub(1) as BitSize)
+        .ok_or(MemError::Overflow)
+}
+
+/// Split a `len` byte access into (vaddr, offset into the access, len) per page
+fn page_chunks(
+    vaddr: BitSize,
+    len: usize,
+) -> Result<impl Iterator<Item = (BitSize, usize, usize)>, MemError> {
+    end_of(vaddr, len)?;
+
+    let mut off = 0;
+    let chunks = std::iter::from_fn(move || {
+        if off >= len {
+            return None;
+        }
+
+        let addr = vaddr + off as BitSize;
+        let n = (PAGE_SIZE - (addr & PAGE_MASK) as usize).min(len - off);
+        let chunk = (addr, off, n);
+        off += n;
+
+        Some(chunk)
+    });
+
+    Ok(chunks)
+}
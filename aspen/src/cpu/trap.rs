@@ -0,0 +1,88 @@
+use crate::{
+    BitSize,
+    cpu::CpuError,
+    emulator::EmuError,
+    instruction::InstError,
+    mmu::{MemError, Prot},
+};
+
+/// Value of `tcause` after a trap
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TrapCause {
+    /// unknown opcode or control register
+    IllegalInstruction = 1,
+    /// fetched from a page without execute access
+    FetchFault = 2,
+    /// read from a page without read access
+    LoadFault = 3,
+    /// wrote to a page without write access
+    StoreFault = 4,
+    StackOverflow = 5,
+    StackUnderflow = 6,
+    /// access wrapped around the end of the address space
+    AddressOverflow = 7,
+    /// storage missing, read-only, or out of bounds
+    StorageFault = 8,
+    /// privileged instruction in user mode
+    PrivilegeFault = 9,
+    /// no valid page table entry for the address
+    TranslationFault = 10,
+    /// atomic access to an address that isn't word aligned
+    MisalignedAccess = 11,
+}
+
+impl EmuError {
+    /// Cause and `tval` to report to the guest, if this is a fault the guest can handle.
+    /// `pc` is used when the error itself doesn't say where it happened
+    pub fn trap_cause(&self, pc: BitSize) -> Option<(TrapCause, BitSize)> {
+        let cause = match self {
+            Self::PageFault(MemError::PageFault(_, addr), _) => (TrapCause::FetchFault, *addr),
+            Self::PageFault(e, _) => e.trap_cause(pc)?,
+            Self::Mem(e) | Self::Cpu(CpuError::Mem(e)) => e.trap_cause(pc)?,
+            Self::Inst(InstError::UnknownInstruction(..)) => (TrapCause::IllegalInstruction, pc),
+            Self::Hart(_, e) => e.trap_cause(pc)?,
+            // the host's problem, not the guest's
+            Self::Ecall(..) | Self::Snapshot(_) | Self::NotRecording => return None,
+            Self::Cpu(e) => match e {
+                CpuError::UnknownCr(_) | CpuError::HartStart(_) => {
+                    (TrapCause::IllegalInstruction, pc)
+                }
+                CpuError::Privileged(_) => (TrapCause::PrivilegeFault, pc),
+                CpuError::StackOverflow(_) => (TrapCause::StackOverflow, pc),
+                CpuError::StackUnderflow(_) => (TrapCause::StackUnderflow, pc),
+                CpuError::Storage(_) => (TrapCause::StorageFault, pc),
+                _ => return None,
+            },
+        };
+
+        Some(cause)
+    }
+}
+
+impl MemError {
+    fn trap_cause(&self, pc: BitSize) -> Option<(TrapCause, BitSize)> {
+        let cause = match self {
+            Self::PageFault(prot, addr) => {
+                let cause = if prot.contains(Prot::Execute) {
+                    TrapCause::FetchFault
+                } else if prot.contains(Prot::Write) {
+                    TrapCause::StoreFault
+                } else {
+                    TrapCause::LoadFault
+                };
+
+                (cause, *addr)
+            }
+
+            Self::NotMapped(addr) => (TrapCause::TranslationFault, *addr),
+            Self::Misaligned(addr) => (TrapCause::MisalignedAccess, *addr),
+            Self::Overflow => (TrapCause::AddressOverflow, pc),
+
+            // host failures
+            _ => return None,
+        };
+
+        Some(cause)
+    }
+}
@@ -0,0 +1,630 @@
+//! Interactive debugger, driven by lines of text so it works over any terminal
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+};
+
+use crate::{
+    BitSize,
+    cpu::Reg,
+    emulator::{
+        EmuError, Emulator, HaltReason, RunOutcome,
+        snapshot::{Snapshot, SnapshotError},
+    },
+    instruction::{Instruction, InstructionType},
+    mmu::MemError,
+};
+
+mod expr;
+
+const HELP: &str = "\
+break|b [loc]         set a breakpoint, or list them
+delete|d <loc>        remove a breakpoint
+step|s [count]        run one or more instructions
+next|n                step, running a call until it returns
+continue|c            run until a breakpoint or hlt
+regs|r                dump registers
+x <loc> [len]         hexdump memory
+dis [loc] [count]     disassemble around pc, or from loc
+print|p <expr>        evaluate an expression
+watch|w [expr]        print an expression at every stop, or list them
+unwatch <n>           remove a watch
+record [off]          start recording for going back, or stop
+reverse-step|rs [n]   undo one or more instructions
+reverse-continue|rc   go back to the last breakpoint hit
+last-write|lw <loc>   find the instruction that last wrote to loc
+save <file>           save a snapshot of the machine
+load <file>           go back to a saved snapshot
+quit|q                leave the debugger
+
+expressions are numbers, registers, pc and labels joined with + and -,
+[expr] reads a word from memory. loc is an expression without spaces.
+an empty line repeats the last command";
+
+/// How far back from pc disassembly looks for a label to start decoding from
+const DISASM_CONTEXT: BitSize = 0x100;
+/// Instructions shown before pc
+const DISASM_BEFORE: usize = 4;
+/// Instructions shown from pc on
+const DISASM_AFTER: usize = 6;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum DebugError {
+    #[error("Unknown symbol: {0}")]
+    UnknownSymbol(String),
+    #[error("Bad expression: {0}")]
+    Syntax(String),
+    #[error("Missing argument, try `help`")]
+    MissingArg,
+    #[error("{0}")]
+    Mem(#[from] MemError),
+    #[error("{0}")]
+    Snapshot(#[from] SnapshotError),
+    #[error("{0}")]
+    Emu(#[from] EmuError),
+}
+
+/// Labels and their addresses
+#[derive(Debug, Default)]
+pub struct Symbols {
+    by_name: BTreeMap<String, BitSize>,
+    by_addr: BTreeMap<BitSize, String>,
+}
+
+impl Symbols {
+    /// Parse `name = 0xaddr` lines, as written by graft
+    pub fn parse(src: &str) -> Self {
+        let mut this = Self::default();
+
+        for line in src.lines() {
+            let Some((name, addr)) = line.split_once('=') else {
+                continue;
+            };
+
+            let addr = addr.trim().strip_prefix("0x");
+            let Some(addr) = addr.and_then(|a| BitSize::from_str_radix(a, 16).ok()) else {
+                continue;
+            };
+
+            this.insert(name.trim(), addr);
+        }
+
+        this
+    }
+
+    pub fn insert(&mut self, name: &str, addr: BitSize) {
+        self.by_name.insert(name.into(), addr);
+        self.by_addr.entry(addr).or_insert_with(|| name.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<BitSize> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Closest label at or before addr, and how far past it addr is
+    pub fn lookup(&self, addr: BitSize) -> Option<(&str, BitSize)> {
+        let (start, name) = self.by_addr.range(..=addr).next_back()?;
+        Some((name, addr - start))
+    }
+}
+
+struct Watch {
+    expr: String,
+    val: Option<BitSize>,
+}
+
+pub struct Debugger<'a> {
+    emu: &'a mut Emulator,
+    symbols: Symbols,
+    watches: Vec<Watch>,
+    /// repeated on an empty line
+    last_cmd: String,
+    /// the last run that ended in `hlt` or a fault
+    finished: Option<Result<RunOutcome, EmuError>>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(emu: &'a mut Emulator, symbols: Symbols) -> Self {
+        Self {
+            emu,
+            symbols,
+            watches: Vec::new(),
+            last_cmd: String::new(),
+            finished: None,
+        }
+    }
+
+    /// Read commands until `quit` or the end of input.
+    /// Returns how the guest last finished, if it did
+    pub fn repl(
+        mut self,
+        input: impl BufRead,
+        mut out: impl Write,
+    ) -> io::Result<Option<Result<RunOutcome, EmuError>>> {
+        out.write_all(self.disasm_line(self.emu.cpu.pc).as_bytes())?;
+
+        let mut lines = input.lines();
+        loop {
+            write!(out, "(aspen) ")?;
+            out.flush()?;
+
+            let Some(line) = lines.next() else {
+                break;
+            };
+
+            let line = match line?.trim() {
+                "" => self.last_cmd.clone(),
+                line => line.to_string(),
+            };
+
+            let Some(reply) = self.command(&line) else {
+                break;
+            };
+
+            out.write_all(reply.as_bytes())?;
+            self.last_cmd = line;
+        }
+
+        Ok(self.finished)
+    }
+
+    /// Run one command and return what it printed, None to quit
+    pub fn command(&mut self, line: &str) -> Option<String> {
+        let (cmd, args) = match line.split_once(char::is_whitespace) {
+            Some((cmd, args)) => (cmd, args.trim()),
+            None => (line, ""),
+        };
+
+        let res = match cmd {
+            "" => Ok(String::new()),
+            "b" | "break" => self.cmd_break(args),
+            "d" | "delete" => self.cmd_delete(args),
+            "s" | "step" => self.cmd_step(args),
+            "n" | "next" => Ok(self.cmd_next()),
+            "c" | "continue" => {
+                let res = self.emu.run();
+                Ok(self.report(res))
+            }
+            "r" | "regs" => Ok(self.cmd_regs()),
+            "x" => self.cmd_hexdump(args),
+            "dis" => self.cmd_disasm(args),
+            "p" | "print" => self.eval(args).map(|val| format!("0x{val:08x} ({val})\n")),
+            "w" | "watch" => self.cmd_watch(args),
+            "unwatch" => Ok(self.cmd_unwatch(args)),
+            "record" => self.cmd_record(args),
+            "rs" | "reverse-step" => self.cmd_reverse_step(args),
+            "rc" | "reverse-continue" => {
+                let res = self.emu.reverse_continue();
+                Ok(self.report(res))
+            }
+            "lw" | "last-write" => self.cmd_last_write(args),
+            "save" => self.cmd_save(args),
+            "load" => self.cmd_load(args),
+            "h" | "help" => Ok(format!("{HELP}\n")),
+            "q" | "quit" => return None,
+            _ => Ok(format!("Unknown command `{cmd}`, try `help`\n")),
+        };
+
+        Some(res.unwrap_or_else(|e| format!("{e}\n")))
+    }
+
+    fn eval(&self, expr: &str) -> Result<BitSize, DebugError> {
+        match expr {
+            "" => Err(DebugError::MissingArg),
+            expr => expr::eval(expr, self.emu, &self.symbols),
+        }
+    }
+
+    /// `0x0000001c <label+4>`
+    fn describe(&self, addr: BitSize) -> String {
+        match self.symbols.lookup(addr) {
+            Some((name, 0)) => format!("0x{addr:08x} <{name}>"),
+            Some((name, off)) => format!("0x{addr:08x} <{name}+{off}>"),
+            None => format!("0x{addr:08x}"),
+        }
+    }
+
+    fn cmd_break(&mut self, args: &str) -> Result<String, DebugError> {
+        if args.is_empty() {
+            let mut out = String::new();
+            for addr in &self.emu.breakpoints {
+                writeln!(out, "{}", self.describe(*addr)).unwrap();
+            }
+
+            return Ok(out);
+        }
+
+        let addr = self.eval(args)?;
+        self.emu.breakpoints.insert(addr);
+        Ok(format!("Breakpoint at {}\n", self.describe(addr)))
+    }
+
+    fn cmd_delete(&mut self, args: &str) -> Result<String, DebugError> {
+        let addr = self.eval(args)?;
+        match self.emu.breakpoints.remove(&addr) {
+            true => Ok(String::new()),
+            false => Ok(format!("No breakpoint at {}\n", self.describe(addr))),
+        }
+    }
+
+    fn cmd_step(&mut self, args: &str) -> Result<String, DebugError> {
+        self.repeat(args, Emulator::step)
+    }
+
+    fn cmd_next(&mut self) -> String {
+        let pc = self.emu.cpu.pc;
+        let call = self
+            .decode(pc)
+            .filter(|i| matches!(i.ty, InstructionType::Call | InstructionType::Callr));
+
+        let Some(call) = call else {
+            let res = self.emu.step();
+            return self.report(res);
+        };
+
+        let ret = pc.wrapping_add(inst_size(&call));
+        let sp = self.emu.cpu.gp.sp;
+        let temp = self.emu.breakpoints.insert(ret);
+
+        let res = loop {
+            let res = self.emu.run();
+
+            // a recursive call got back to the same place
+            let deeper = temp && self.emu.cpu.pc == ret && self.emu.cpu.gp.sp != sp;
+            match res {
+                Ok(RunOutcome {
+                    reason: HaltReason::Breakpoint,
+                    ..
+                }) if deeper => continue,
+                res => break res,
+            }
+        };
+
+        if temp {
+            self.emu.breakpoints.remove(&ret);
+        }
+
+        // returning isn't worth reporting as a breakpoint
+        let res = match res {
+            Ok(outcome) if temp && self.emu.cpu.pc == ret => Ok(RunOutcome {
+                reason: match outcome.reason {
+                    HaltReason::Breakpoint => HaltReason::Step,
+                    reason => reason,
+                },
+                ..outcome
+            }),
+            res => res,
+        };
+
+        self.report(res)
+    }
+
+    fn cmd_regs(&self) -> String {
+        let mut out = String::new();
+
+        for n in 0..32u8 {
+            let reg = Reg::from(n);
+            let val = self.emu.cpu.gp.get_reg(reg);
+            let end = if n % 4 == 3 { "\n" } else { "  " };
+            write!(out, "{:<3} 0x{val:08x}{end}", reg.to_string()).unwrap();
+        }
+
+        writeln!(out, "pc  {}", self.describe(self.emu.cpu.pc)).unwrap();
+        out
+    }
+
+    fn cmd_hexdump(&self, args: &str) -> Result<String, DebugError> {
+        let mut args = args.split_whitespace();
+        let addr = self.eval(args.next().unwrap_or_default())?;
+        let len = match args.next() {
+            Some(len) => self.eval(len)?,
+            None => 64,
+        };
+
+        let mut buf = vec![0; len.min(0x1000) as usize];
+        self.emu.mmu.memcpy(addr, &mut buf)?;
+
+        let mut out = String::new();
+        for (i, line) in buf.chunks(16).enumerate() {
+            write!(out, "0x{:08x} ", addr.wrapping_add(i as BitSize * 16)).unwrap();
+
+            for b in line {
+                write!(out, " {b:02x}").unwrap();
+            }
+
+            let pad = (16 - line.len()) * 3;
+            let ascii: String = line
+                .iter()
+                .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                    true => b as char,
+                    false => '.',
+                })
+                .collect();
+
+            writeln!(out, "{:pad$}  |{ascii}|", "").unwrap();
+        }
+
+        Ok(out)
+    }
+
+    fn cmd_disasm(&self, args: &str) -> Result<String, DebugError> {
+        let mut args = args.split_whitespace();
+        let Some(loc) = args.next() else {
+            return Ok(self.disasm_around_pc());
+        };
+
+        let mut addr = self.eval(loc)?;
+        let count = match args.next() {
+            Some(count) => self.eval(count)?,
+            None => 10,
+        };
+
+        let mut out = String::new();
+        for _ in 0..count {
+            out.push_str(&self.disasm_line(addr));
+            addr = addr.wrapping_add(self.decode(addr).map_or(4, |i| inst_size(&i)));
+        }
+
+        Ok(out)
+    }
+
+    /// Instructions can't be decoded backwards, so start from a label before pc
+    fn disasm_around_pc(&self) -> String {
+        let pc = self.emu.cpu.pc;
+        let start = match self.symbols.lookup(pc) {
+            Some((_, off)) if off <= DISASM_CONTEXT => pc - off,
+            _ => pc,
+        };
+
+        let mut before = VecDeque::new();
+        let mut addr = start;
+        while addr < pc {
+            before.push_back(addr);
+            if before.len() > DISASM_BEFORE {
+                before.pop_front();
+            }
+
+            // wrapping would start over below pc
+            let size = self.decode(addr).map_or(4, |i| inst_size(&i));
+            match addr.checked_add(size) {
+                Some(next) => addr = next,
+                None => break,
+            }
+        }
+
+        // pc is in the middle of what we decoded
+        if addr != pc {
+            before.clear();
+        }
+
+        let mut out: String = before.iter().map(|a| self.disasm_line(*a)).collect();
+
+        let mut addr = pc;
+        for _ in 0..DISASM_AFTER {
+            out.push_str(&self.disasm_line(addr));
+            addr = addr.wrapping_add(self.decode(addr).map_or(4, |i| inst_size(&i)));
+        }
+
+        out
+    }
+
+    fn disasm_line(&self, addr: BitSize) -> String {
+        let marker = match addr == self.emu.cpu.pc {
+            true => "=>",
+            false => "  ",
+        };
+
+        let inst = match self.decode(addr) {
+            Some(inst) => inst.to_string(),
+            None => {
+                let mut word = [0; 4];
+                match self.emu.mmu.memcpy(addr, &mut word) {
+                    Ok(()) => format!(".word 0x{:08x}", BitSize::from_le_bytes(word)),
+                    Err(e) => e.to_string(),
+                }
+            }
+        };
+
+        format!("{marker} {}: {inst}\n", self.describe(addr))
+    }
+
+    fn decode(&self, addr: BitSize) -> Option<Instruction> {
+        let mut buf = [0; 8];
+
+        // the imm could be past the end of memory
+        if self.emu.mmu.memcpy(addr, &mut buf).is_err() {
+            self.emu.mmu.memcpy(addr, &mut buf[..4]).ok()?;
+        }
+
+        Instruction::from_buf(buf).ok()
+    }
+
+    fn cmd_watch(&mut self, args: &str) -> Result<String, DebugError> {
+        if args.is_empty() {
+            return Ok(self.watches());
+        }
+
+        let val = self.eval(args)?;
+        self.watches.push(Watch {
+            expr: args.into(),
+            val: Some(val),
+        });
+
+        Ok(format!(
+            "w{}: {args} = 0x{val:08x}\n",
+            self.watches.len() - 1
+        ))
+    }
+
+    fn cmd_unwatch(&mut self, args: &str) -> String {
+        match args.parse::<usize>() {
+            Ok(n) if n < self.watches.len() => {
+                self.watches.remove(n);
+                String::new()
+            }
+            _ => format!("No watch `{args}`\n"),
+        }
+    }
+
+    fn cmd_record(&mut self, args: &str) -> Result<String, DebugError> {
+        match args {
+            "off" => {
+                self.emu.stop_recording();
+                Ok("Stopped recording\n".into())
+            }
+            _ => {
+                self.emu.record()?;
+                Ok("Recording\n".into())
+            }
+        }
+    }
+
+    fn cmd_reverse_step(&mut self, args: &str) -> Result<String, DebugError> {
+        self.repeat(args, Emulator::step_back)
+    }
+
+    /// Step `count` times, or until something else stops the guest
+    fn repeat(
+        &mut self,
+        count: &str,
+        step: fn(&mut Emulator) -> Result<RunOutcome, EmuError>,
+    ) -> Result<String, DebugError> {
+        let count = match count {
+            "" => 1,
+            count => self.eval(count)?,
+        };
+
+        let mut res = step(self.emu);
+        for _ in 1..count {
+            if !matches!(
+                res,
+                Ok(RunOutcome {
+                    reason: HaltReason::Step,
+                    ..
+                })
+            ) {
+                break;
+            }
+
+            res = step(self.emu);
+        }
+
+        Ok(self.report(res))
+    }
+
+    fn cmd_last_write(&self, args: &str) -> Result<String, DebugError> {
+        let addr = self.eval(args)?;
+        if !self.emu.is_recording() {
+            return Err(EmuError::NotRecording.into());
+        }
+
+        let Some(write) = self.emu.last_write(addr) else {
+            return Ok(format!("0x{addr:08x} wasn't written while recording\n"));
+        };
+
+        Ok(format!(
+            "{} bytes at 0x{:08x}, written by instruction {}:\n{}",
+            write.len,
+            write.addr,
+            write.index,
+            self.disasm_line(write.pc),
+        ))
+    }
+
+    fn cmd_save(&mut self, args: &str) -> Result<String, DebugError> {
+        if args.is_empty() {
+            return Err(DebugError::MissingArg);
+        }
+
+        let snapshot = self.emu.snapshot()?;
+        let file = File::create(args).map_err(SnapshotError::from)?;
+        snapshot
+            .save(BufWriter::new(file))
+            .map_err(SnapshotError::from)?;
+
+        Ok(format!("Saved to {args}\n"))
+    }
+
+    fn cmd_load(&mut self, args: &str) -> Result<String, DebugError> {
+        if args.is_empty() {
+            return Err(DebugError::MissingArg);
+        }
+
+        let file = File::open(args).map_err(SnapshotError::from)?;
+        let snapshot = Snapshot::load(BufReader::new(file))?;
+        self.emu.restore(&snapshot)?;
+
+        Ok(self.disasm_line(self.emu.cpu.pc) + &self.watches())
+    }
+
+    /// Current value of every watch, noting the ones that changed
+    fn watches(&mut self) -> String {
+        let mut out = String::new();
+
+        for (i, watch) in self.watches.iter_mut().enumerate() {
+            let expr = &watch.expr;
+            let val = expr::eval(expr, self.emu, &self.symbols);
+
+            match (&val, watch.val) {
+                (Ok(val), Some(old)) if *val != old => {
+                    writeln!(out, "w{i}: {expr} = 0x{val:08x} (was 0x{old:08x})").unwrap()
+                }
+                (Ok(val), _) => writeln!(out, "w{i}: {expr} = 0x{val:08x}").unwrap(),
+                (Err(e), _) => writeln!(out, "w{i}: {expr}: {e}").unwrap(),
+            }
+
+            watch.val = val.ok();
+        }
+
+        out
+    }
+
+    /// Say why the guest stopped and where
+    fn report(&mut self, res: Result<RunOutcome, EmuError>) -> String {
+        let mut out = String::new();
+
+        match &res {
+            Ok(outcome) => match outcome.reason {
+                HaltReason::Hlt => writeln!(out, "Exited with code {}", outcome.exit_code).unwrap(),
+                HaltReason::Breakpoint => {
+                    writeln!(out, "Breakpoint at {}", self.describe(self.emu.cpu.pc)).unwrap()
+                }
+                HaltReason::Interrupted => writeln!(out, "Interrupted").unwrap(),
+                HaltReason::HistoryStart => {
+                    writeln!(out, "Reached the start of the recording").unwrap()
+                }
+                HaltReason::Step => (),
+            },
+            Err(e) => writeln!(out, "Fault: {e}").unwrap(),
+        }
+
+        let exited = matches!(
+            res,
+            Ok(RunOutcome {
+                reason: HaltReason::Hlt,
+                ..
+            })
+        );
+
+        if !exited {
+            out.push_str(&self.disasm_line(self.emu.cpu.pc));
+        }
+
+        if exited || res.is_err() {
+            self.finished = Some(res);
+        }
+
+        out.push_str(&self.watches());
+        out
+    }
+}
+
+fn inst_size(inst: &Instruction) -> BitSize {
+    match inst.has_imm {
+        true => 8,
+        false => 4,
+    }
+}
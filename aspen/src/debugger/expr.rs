@@ -0,0 +1,109 @@
+//! `term (('+' | '-') term)*`, where a term is a number, register, pc,
+//! label or `[expr]`
+
+use super::{DebugError, Symbols};
+use crate::{BitSize, cpu::Reg, emulator::Emulator};
+
+pub(super) fn eval(src: &str, emu: &Emulator, symbols: &Symbols) -> Result<BitSize, DebugError> {
+    let mut parser = Parser {
+        src,
+        rest: src,
+        emu,
+        symbols,
+    };
+
+    let val = parser.expr()?;
+    parser.skip_ws();
+
+    match parser.rest.is_empty() {
+        true => Ok(val),
+        false => Err(parser.syntax()),
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    rest: &'a str,
+    emu: &'a Emulator,
+    symbols: &'a Symbols,
+}
+
+impl Parser<'_> {
+    fn syntax(&self) -> DebugError {
+        DebugError::Syntax(self.src.into())
+    }
+
+    fn skip_ws(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expr(&mut self) -> Result<BitSize, DebugError> {
+        let mut val = self.term()?;
+
+        loop {
+            if self.eat('+') {
+                val = val.wrapping_add(self.term()?);
+            } else if self.eat('-') {
+                val = val.wrapping_sub(self.term()?);
+            } else {
+                return Ok(val);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<BitSize, DebugError> {
+        if self.eat('[') {
+            let addr = self.expr()?;
+            if !self.eat(']') {
+                return Err(self.syntax());
+            }
+
+            return Ok(self.emu.mmu.read::<BitSize>(addr)?);
+        }
+
+        self.skip_ws();
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(self.rest.len());
+
+        let (word, rest) = self.rest.split_at(end);
+        self.rest = rest;
+
+        if word.starts_with(|c: char| c.is_ascii_digit()) {
+            let num = match word.strip_prefix("0x") {
+                Some(hex) => BitSize::from_str_radix(hex, 16),
+                None => word.parse(),
+            };
+
+            return num.map_err(|_| self.syntax());
+        }
+
+        if word.is_empty() {
+            return Err(self.syntax());
+        }
+
+        if word == "pc" {
+            return Ok(self.emu.cpu.pc);
+        }
+
+        if let Some(reg) = (0..32u8).map(Reg::from).find(|r| r.to_string() == word) {
+            return Ok(self.emu.cpu.gp.get_reg(reg));
+        }
+
+        self.symbols
+            .get(word)
+            .ok_or_else(|| DebugError::UnknownSymbol(word.into()))
+    }
+}
@@ -0,0 +1,56 @@
+pub mod console;
+pub mod keyboard;
+pub mod storage;
+pub mod uart;
+
+use std::{fmt::Debug, sync::Arc};
+
+use console::Console;
+use keyboard::Keyboard;
+use storage::{Storage, StorageError};
+
+use crate::BitSize;
+
+/// Peripheral mapped into the physical address space, see [`crate::mmu::Mmu::map_device`].
+/// Loads and stores to its range are routed here instead of RAM
+pub trait Device: Send + Debug {
+    /// Read `size` (1, 2 or 4) bytes at `offset` from the start of the range
+    fn read(&mut self, offset: BitSize, size: usize) -> BitSize;
+
+    /// Write the low `size` bytes of val at `offset` from the start of the range
+    fn write(&mut self, offset: BitSize, size: usize, val: BitSize);
+
+    /// Called by the boot hart between instructions (or blocks) with its cycle count,
+    /// if [`Device::ticks`]
+    fn tick(&mut self, _clk: u64) {}
+
+    /// Whether to call [`Device::tick`], checked when the device is mapped.
+    /// Ticking takes the device lock on every instruction, so it's opt in
+    fn ticks(&self) -> bool {
+        false
+    }
+
+    /// Whether the device wants attention, raises [`crate::cpu::intc::Irq::Device`].
+    /// Sampled after every tick, so only devices that tick can raise it
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+/// Peripherals owned by the emulator and shared with the cpu
+#[derive(Debug, Default)]
+pub struct Devices {
+    /// output of `pr` / `epr`
+    pub console: Console,
+    /// input of `kbrd`, shared with the monitor window
+    pub kbd: Arc<Keyboard>,
+    /// backing of `pld*` / `pstr*`
+    pub storage: Option<Storage>,
+}
+
+impl Devices {
+    /// Attached storage, or an error for the guest if there is none
+    pub fn storage(&self) -> Result<&Storage, StorageError> {
+        self.storage.as_ref().ok_or(StorageError::NotAttached)
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    fs::File,
+    io::{self, Write as _},
+    mem,
+};
+
+use sayuri::sync::Mutex;
+
+/// Where console output ends up
+#[derive(Debug, Default)]
+pub enum Sink {
+    /// Host stdout
+    #[default]
+    Stdout,
+    /// Host stderr
+    Stderr,
+    /// In-memory capture buffer
+    Buffer(Vec<u8>),
+    /// Host file
+    File(File),
+    /// Discard everything
+    Null,
+}
+
+impl Sink {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Stdout => {
+                let mut out = io::stdout().lock();
+                out.write_all(data)?;
+                out.flush()
+            }
+
+            Self::Stderr => io::stderr().lock().write_all(data),
+
+            Self::Buffer(buf) => {
+                buf.extend_from_slice(data);
+                Ok(())
+            }
+
+            Self::File(file) => file.write_all(data),
+
+            Self::Null => Ok(()),
+        }
+    }
+}
+
+/// Console device backing `pr` (out) and `epr` (err)
+#[derive(Debug)]
+pub struct Console {
+    out: Mutex<Sink>,
+    err: Mutex<Sink>,
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new(Sink::Stdout, Sink::Stderr)
+    }
+}
+
+impl Console {
+    pub fn new(out: Sink, err: Sink) -> Self {
+        Self {
+            out: Mutex::new(out),
+            err: Mutex::new(err),
+        }
+    }
+
+    /// Replace the `pr` sink, returning the old one
+    pub fn set_out(&self, sink: Sink) -> Sink {
+        mem::replace(&mut *self.out.lock(), sink)
+    }
+
+    /// Replace the `epr` sink, returning the old one
+    pub fn set_err(&self, sink: Sink) -> Sink {
+        mem::replace(&mut *self.err.lock(), sink)
+    }
+
+    /// Take the captured `pr` output, if the sink is a buffer
+    pub fn take_out(&self) -> Option<Vec<u8>> {
+        match &mut *self.out.lock() {
+            Sink::Buffer(buf) => Some(mem::take(buf)),
+            _ => None,
+        }
+    }
+
+    /// Take the captured `epr` output, if the sink is a buffer
+    pub fn take_err(&self) -> Option<Vec<u8>> {
+        match &mut *self.err.lock() {
+            Sink::Buffer(buf) => Some(mem::take(buf)),
+            _ => None,
+        }
+    }
+
+    pub fn print(&self, data: &[u8]) -> io::Result<()> {
+        self.out.lock().write_all(data)
+    }
+
+    pub fn eprint(&self, data: &[u8]) -> io::Result<()> {
+        self.err.lock().write_all(data)
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read as _},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, channel},
+    },
+    thread,
+};
+
+use minifb::{InputCallback, Key};
+use sayuri::sync::Mutex;
+
+/// Keys without a character (arrows, function keys, ..) are reported
+/// as `SPECIAL | minifb::Key as u32`
+pub const SPECIAL: u32 = 0x8000_0000;
+
+/// Queue of key events read by `kbrd`
+///
+/// Scancodes are unicode codepoints, so the window and stdin produce the same values
+/// (stdin is decoded as UTF-8, invalid input becomes U+FFFD).
+/// 0 is never queued, since `kbrd` uses it to signal an empty queue
+#[derive(Debug, Default)]
+pub struct Keyboard {
+    queue: Mutex<VecDeque<u32>>,
+    /// read stdin when there's no window
+    stdin: AtomicBool,
+    stdin_rx: OnceLock<Mutex<Receiver<char>>>,
+}
+
+impl Keyboard {
+    /// Queue a key event
+    pub fn push(&self, code: u32) {
+        if code != 0 {
+            self.queue.lock().push_back(code);
+        }
+    }
+
+    /// Queue a list of key events, for scripted headless runs
+    pub fn script(&self, events: impl IntoIterator<Item = u32>) {
+        let mut queue = self.queue.lock();
+        queue.extend(events.into_iter().filter(|&c| c != 0));
+    }
+
+    /// Pop the next key event
+    pub fn pop(&self) -> Option<u32> {
+        self.queue.lock().pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().is_empty()
+    }
+
+    /// Drop all queued events
+    pub fn clear(&self) {
+        self.queue.lock().clear();
+    }
+
+    /// Feed stdin into the queue when running without a window
+    pub fn set_stdin(&self, enable: bool) {
+        self.stdin.store(enable, Ordering::Relaxed);
+    }
+
+    /// Move any characters read from stdin into the queue.
+    /// The reader thread is only started the first time this is called with stdin enabled
+    pub fn poll_stdin(&self) {
+        if !self.stdin.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let rx = self.stdin_rx.get_or_init(|| {
+            let (tx, rx) = channel();
+
+            thread::spawn(move || {
+                // a utf-8 sequence can arrive split across reads
+                let mut buf = Vec::with_capacity(4);
+
+                for byte in io::stdin().lock().bytes() {
+                    let Ok(byte) = byte else {
+                        break;
+                    };
+
+                    buf.push(byte);
+                    let c = match str::from_utf8(&buf) {
+                        Ok(s) => s.chars().next(),
+                        Err(e) if e.error_len().is_some() => Some(char::REPLACEMENT_CHARACTER),
+                        // incomplete
+                        Err(_) => continue,
+                    };
+
+                    buf.clear();
+                    if let Some(c) = c
+                        && tx.send(c).is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+
+            Mutex::new(rx)
+        });
+
+        let rx = rx.lock();
+        let mut queue = self.queue.lock();
+        queue.extend(rx.try_iter().map(u32::from).filter(|&c| c != 0));
+    }
+}
+
+/// Forwards window key events into the keyboard queue
+pub struct WindowInput(pub Arc<Keyboard>);
+
+impl InputCallback for WindowInput {
+    fn add_char(&mut self, uni_char: u32) {
+        // the window sends \r for enter, stdin sends \n
+        let code = match uni_char {
+            0x0d => b'\n' as u32,
+            c => c,
+        };
+
+        self.0.push(code);
+    }
+
+    fn set_key_state(&mut self, key: Key, state: bool) {
+        // add_char handles everything that produces a character,
+        // including enter, backspace, tab, escape and delete
+        if !state {
+            return;
+        }
+
+        let code = match key {
+            Key::Up
+            | Key::Down
+            | Key::Left
+            | Key::Right
+            | Key::Home
+            | Key::End
+            | Key::PageUp
+            | Key::PageDown
+            | Key::Insert
+            | Key::F1
+            | Key::F2
+            | Key::F3
+            | Key::F4
+            | Key::F5
+            | Key::F6
+            | Key::F7
+            | Key::F8
+            | Key::F9
+            | Key::F10
+            | Key::F11
+            | Key::F12
+            | Key::F13
+            | Key::F14
+            | Key::F15 => SPECIAL | key as u32,
+
+            _ => return,
+        };
+
+        self.0.push(code);
+    }
+}
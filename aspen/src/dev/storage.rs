@@ -0,0 +1,245 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fs::{File, OpenOptions},
+    io::{self, Read as _, Seek as _, SeekFrom, Write as _},
+    iter,
+    ops::Range,
+    path::Path,
+};
+
+use log::warn;
+use sayuri::sync::Mutex;
+
+use crate::BitSize;
+
+/// Granularity blocks are loaded from and flushed to the image
+pub const BLOCK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, thiserror::Error, PartialEq)]
+pub enum StorageError {
+    #[error("No storage attached")]
+    NotAttached,
+    #[error("Storage is read-only: 0x{0:0>8x}")]
+    ReadOnly(BitSize),
+    #[error("Storage access out of bounds: 0x{0:0>8x}")]
+    OutOfBounds(BitSize),
+    #[error("Storage I/O: {0}")]
+    Io(String),
+}
+
+impl From<io::Error> for StorageError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value.to_string())
+    }
+}
+
+#[derive(Debug)]
+struct Block {
+    data: Box<[u8; BLOCK_SIZE]>,
+    dirty: bool,
+}
+
+/// Disk image backing `pld*` / `pstr*`
+///
+/// Blocks are read from the image the first time they're touched, and
+/// changed blocks are only written back on [`Storage::flush`] (or drop)
+#[derive(Debug)]
+pub struct Storage {
+    /// None for a purely in-memory image
+    file: Option<Mutex<File>>,
+    len: u64,
+    read_only: bool,
+    blocks: Mutex<HashMap<u64, Block>>,
+}
+
+impl Storage {
+    /// Open an existing image. Its size is fixed to the file size
+    pub fn open(path: impl AsRef<Path>, read_only: bool) -> Result<Self, StorageError> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+
+        let len = file.metadata()?.len();
+
+        Ok(Self::with_file(Some(file), len, read_only))
+    }
+
+    /// Create (or truncate) an image of `len` bytes. The file is sparse
+    /// until blocks get flushed to it
+    pub fn create(path: impl AsRef<Path>, len: u64) -> Result<Self, StorageError> {
+        let file = File::create_new(&path).or_else(|_| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .truncate(true)
+                .open(&path)
+        })?;
+
+        file.set_len(len)?;
+
+        Ok(Self::with_file(Some(file), len, false))
+    }
+
+    /// Image without a backing file, everything is lost on drop
+    pub fn memory(len: u64) -> Self {
+        Self::with_file(None, len, false)
+    }
+
+    fn with_file(file: Option<File>, len: u64, read_only: bool) -> Self {
+        // storage is addressed with BitSize, anything past that is unreachable
+        let len = len.min(BitSize::MAX as u64 + 1);
+
+        Self {
+            file: file.map(Mutex::new),
+            len,
+            read_only,
+            blocks: Mutex::default(),
+        }
+    }
+
+    /// Size of the image in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_bounds(&self, addr: BitSize, len: usize) -> Result<(), StorageError> {
+        if addr as u64 + len as u64 > self.len {
+            return Err(StorageError::OutOfBounds(addr));
+        }
+
+        Ok(())
+    }
+
+    /// Get a block, loading it from the image if it isn't yet
+    fn block<'a>(
+        &self,
+        blocks: &'a mut HashMap<u64, Block>,
+        idx: u64,
+    ) -> Result<&'a mut Block, StorageError> {
+        let block = match blocks.entry(idx) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(self.load(idx)?),
+        };
+
+        Ok(block)
+    }
+
+    fn load(&self, idx: u64) -> Result<Block, StorageError> {
+        let mut data = Box::new([0; BLOCK_SIZE]);
+
+        if let Some(file) = &self.file {
+            let mut file = file.lock();
+            let offset = idx * BLOCK_SIZE as u64;
+            let len = (self.len - offset).min(BLOCK_SIZE as u64) as usize;
+
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut data[..len])?;
+        }
+
+        Ok(Block { data, dirty: false })
+    }
+
+    /// Read buf.len bytes starting at addr
+    pub fn read(&self, addr: BitSize, buf: &mut [u8]) -> Result<(), StorageError> {
+        self.check_bounds(addr, buf.len())?;
+
+        let mut blocks = self.blocks.lock();
+        let mut pos = addr as u64;
+
+        for chunk in Self::chunks(pos, buf.len()) {
+            let idx = pos / BLOCK_SIZE as u64;
+            let off = (pos % BLOCK_SIZE as u64) as usize;
+
+            let block = self.block(&mut blocks, idx)?;
+
+            buf[chunk.clone()].copy_from_slice(&block.data[off..off + chunk.len()]);
+            pos += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Write buf starting at addr
+    pub fn write(&self, addr: BitSize, buf: &[u8]) -> Result<(), StorageError> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly(addr));
+        }
+
+        self.check_bounds(addr, buf.len())?;
+
+        let mut blocks = self.blocks.lock();
+        let mut pos = addr as u64;
+
+        for chunk in Self::chunks(pos, buf.len()) {
+            let idx = pos / BLOCK_SIZE as u64;
+            let off = (pos % BLOCK_SIZE as u64) as usize;
+
+            let block = self.block(&mut blocks, idx)?;
+
+            block.data[off..off + chunk.len()].copy_from_slice(&buf[chunk.clone()]);
+            block.dirty = true;
+            pos += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Split `len` bytes starting at `pos` into per block ranges of the buffer
+    fn chunks(pos: u64, len: usize) -> impl Iterator<Item = Range<usize>> {
+        let mut start = 0;
+
+        iter::from_fn(move || {
+            if start >= len {
+                return None;
+            }
+
+            let off = ((pos + start as u64) % BLOCK_SIZE as u64) as usize;
+            let end = (start + BLOCK_SIZE - off).min(len);
+            let range = start..end;
+            start = end;
+
+            Some(range)
+        })
+    }
+
+    /// Write all changed blocks back to the image
+    pub fn flush(&self) -> Result<(), StorageError> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        let mut file = file.lock();
+        let mut blocks = self.blocks.lock();
+
+        for (idx, block) in blocks.iter_mut().filter(|(_, b)| b.dirty) {
+            let offset = idx * BLOCK_SIZE as u64;
+            let len = (self.len - offset).min(BLOCK_SIZE as u64) as usize;
+
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&block.data[..len])?;
+            block.dirty = false;
+        }
+
+        file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+
+        if let Err(e) = self.flush() {
+            warn!("failed to flush storage: {e}");
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read as _, Write as _},
+    sync::{
+        Arc, OnceLock,
+        mpsc::{Receiver, channel},
+    },
+    thread,
+};
+
+use log::warn;
+use sayuri::sync::Mutex;
+
+use crate::{BitSize, dev::Device};
+
+#[cfg(target_os = "linux")]
+pub mod pty;
+
+/// Where the uart is mapped by default
+pub const BASE: BitSize = 0xf000_0000;
+/// Bytes of address space the registers take up
+pub const SIZE: BitSize = 0x10;
+
+/// Read: next received byte, 0 if there's none. Write: send a byte
+pub const DATA: BitSize = 0x0;
+/// [`STATUS_RX`] | [`STATUS_TX`]
+pub const STATUS: BitSize = 0x4;
+/// [`IE_RX`]
+pub const IE: BitSize = 0x8;
+
+/// A received byte is waiting in `DATA`
+pub const STATUS_RX: BitSize = 1 << 0;
+/// `DATA` can take a byte, always set
+pub const STATUS_TX: BitSize = 1 << 1;
+
+/// Raise the device irq while a received byte is waiting
+pub const IE_RX: BitSize = 1 << 0;
+
+/// How many cycles pass between looking for host input
+const POLL_INTERVAL: u64 = 1024;
+
+/// In-memory host side of a uart, for tests and embedding
+#[derive(Debug, Clone, Default)]
+pub struct Pipe(Arc<Mutex<PipeBuf>>);
+
+#[derive(Debug, Default)]
+struct PipeBuf {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl Pipe {
+    /// Queue bytes for the guest to receive
+    pub fn send(&self, data: &[u8]) {
+        self.0.lock().input.extend(data);
+    }
+
+    /// Take everything the guest sent so far
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().output)
+    }
+}
+
+/// What the other end of the uart is connected to
+#[derive(Debug)]
+pub enum Host {
+    /// Host stdout, input from stdin
+    Stdio,
+    /// Output to a host file, no input
+    File(File),
+    /// In-memory buffers
+    Pipe(Pipe),
+    /// Linux pseudo-terminal, for `screen` or `minicom`
+    #[cfg(target_os = "linux")]
+    Pty(pty::Pty),
+}
+
+impl Host {
+    /// Open a new pseudo-terminal, see [`pty::Pty::path`] for where to attach
+    #[cfg(target_os = "linux")]
+    pub fn pty() -> io::Result<Self> {
+        pty::Pty::open().map(Self::Pty)
+    }
+
+    fn send(&mut self, byte: u8) -> io::Result<()> {
+        match self {
+            Self::Stdio => {
+                let mut out = io::stdout().lock();
+                out.write_all(&[byte])?;
+                out.flush()
+            }
+
+            Self::File(file) => file.write_all(&[byte]),
+
+            Self::Pipe(pipe) => {
+                pipe.0.lock().output.push(byte);
+                Ok(())
+            }
+
+            #[cfg(target_os = "linux")]
+            Self::Pty(pty) => pty.send(byte),
+        }
+    }
+
+    /// Move whatever input is available into rx, without blocking
+    fn poll(&mut self, rx: &mut VecDeque<u8>) -> io::Result<()> {
+        match self {
+            Self::Stdio => rx.extend(stdin().lock().try_iter()),
+
+            Self::File(_) => (),
+
+            Self::Pipe(pipe) => rx.extend(pipe.0.lock().input.drain(..)),
+
+            #[cfg(target_os = "linux")]
+            Self::Pty(pty) => pty.recv(rx)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Bytes read from host stdin. The reader thread is only started
+/// once a guest looks for input
+fn stdin() -> &'static Mutex<Receiver<u8>> {
+    static STDIN: OnceLock<Mutex<Receiver<u8>>> = OnceLock::new();
+
+    STDIN.get_or_init(|| {
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                let Ok(byte) = byte else {
+                    break;
+                };
+
+                if tx.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Mutex::new(rx)
+    })
+}
+
+/// Serial port, see [`DATA`], [`STATUS`] and [`IE`] for the registers
+#[derive(Debug)]
+pub struct Uart {
+    host: Host,
+    rx: VecDeque<u8>,
+    ie: BitSize,
+    next_poll: u64,
+}
+
+impl Uart {
+    pub fn new(host: Host) -> Self {
+        Self {
+            host,
+            rx: VecDeque::new(),
+            ie: 0,
+            next_poll: 0,
+        }
+    }
+
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    fn poll(&mut self) {
+        if let Err(e) = self.host.poll(&mut self.rx) {
+            warn!(target: "aspen::uart", "failed to read host input: {e}");
+        }
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: BitSize, _size: usize) -> BitSize {
+        // guests busy waiting on input shouldn't have to wait for a tick
+        if self.rx.is_empty() && matches!(offset, DATA | STATUS) {
+            self.poll();
+        }
+
+        match offset {
+            DATA => self.rx.pop_front().unwrap_or(0).into(),
+            STATUS => match self.rx.is_empty() {
+                true => STATUS_TX,
+                false => STATUS_TX | STATUS_RX,
+            },
+            IE => self.ie,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: BitSize, _size: usize, val: BitSize) {
+        match offset {
+            DATA => {
+                if let Err(e) = self.host.send(val as u8) {
+                    warn!(target: "aspen::uart", "failed to send: {e}");
+                }
+            }
+            IE => self.ie = val,
+            _ => (),
+        }
+    }
+
+    fn ticks(&self) -> bool {
+        true
+    }
+
+    fn tick(&mut self, clk: u64) {
+        if clk >= self.next_poll {
+            self.next_poll = clk + POLL_INTERVAL;
+            self.poll();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.ie & IE_RX != 0 && !self.rx.is_empty()
+    }
+}
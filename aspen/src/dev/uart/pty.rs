@@ -0,0 +1,105 @@
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{self, Read as _, Write as _},
+    os::{
+        fd::{AsRawFd as _, FromRawFd as _},
+        unix::fs::OpenOptionsExt as _,
+    },
+    path::{Path, PathBuf},
+};
+
+/// Master side of a pseudo-terminal. The slave side is kept open,
+/// so reads don't fail while nobody is attached
+#[derive(Debug)]
+pub struct Pty {
+    master: File,
+    _slave: File,
+    path: PathBuf,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Self> {
+        let flags = libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK;
+
+        // SAFETY: plain libc calls, the fd is owned by the File right away
+        let master = unsafe {
+            let fd = libc::posix_openpt(flags);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            File::from_raw_fd(fd)
+        };
+
+        let fd = master.as_raw_fd();
+        let mut name = [0; 64];
+
+        // SAFETY: fd is a valid pty master, and name is big enough for the path
+        let path = unsafe {
+            if libc::grantpt(fd) != 0
+                || libc::unlockpt(fd) != 0
+                || libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            let name = CStr::from_ptr(name.as_ptr());
+            PathBuf::from(name.to_string_lossy().into_owned())
+        };
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+
+        // bytes go through untouched, no echo or line editing
+        // SAFETY: slave is a valid terminal fd, termios is filled in by tcgetattr
+        unsafe {
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            libc::cfmakeraw(&mut termios);
+
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Self {
+            master,
+            _slave: slave,
+            path,
+        })
+    }
+
+    /// Slave device to attach a terminal program to, e.g. `/dev/pts/3`
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(super) fn send(&mut self, byte: u8) -> io::Result<()> {
+        match self.master.write(&[byte]) {
+            // nobody is reading and the buffer is full, like a real line
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    pub(super) fn recv(&mut self, rx: &mut VecDeque<u8>) -> io::Result<()> {
+        let mut buf = [0; 256];
+
+        loop {
+            match self.master.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => rx.extend(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
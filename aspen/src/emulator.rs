@@ -1,15 +1,33 @@
+pub mod ecall;
+pub mod history;
+#[cfg(feature = "jit")]
+mod jit;
+pub mod snapshot;
 #[cfg(test)]
 mod tests;
+mod threaded;
 
-use std::sync::Arc;
+use std::{
+    collections::BTreeSet,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
 
-use log::{Level, trace};
+use log::{Level, debug, trace};
 use yansi::Paint as _;
 
 use crate::BitSize;
-use crate::cpu::{Cpu, CpuError};
-use crate::instruction::{InstError, Instruction};
-use crate::mmu::{MemError, Mmu, PAGE_SIZE, Prot};
+use crate::cpu::{Cpu, CpuError, hart::Harts};
+use crate::dev::{Device, Devices};
+use crate::instruction::{InstError, Instruction, InstructionType};
+use crate::mmu::{AddressRange, DeviceRef, Mapping, MemError, Mmu, PAGE_SIZE, Prot};
+use ecall::Ecalls;
+use history::{History, WriteRecord};
+use snapshot::{HartState, Snapshot, SnapshotError};
+use threaded::{Blocks, Env};
 
 #[derive(Debug, thiserror::Error, PartialEq)]
 pub enum EmuError {
@@ -21,19 +39,97 @@ pub enum EmuError {
     Inst(#[from] InstError),
     #[error("{0}")]
     Cpu(#[from] CpuError),
+    #[error("hart {0}: {1}")]
+    Hart(BitSize, Box<EmuError>),
+    #[error("ecall {0}: {1}")]
+    Ecall(BitSize, String),
+    #[error("{0}")]
+    Snapshot(#[from] SnapshotError),
+    #[error("Not recording, reverse execution needs a recording")]
+    NotRecording,
+}
+
+/// How guest code is executed
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Engine {
+    /// decode and run one instruction at a time
+    #[default]
+    Interpreter,
+    /// translate basic blocks to closures once and run those, see [`threaded`]
+    Threaded,
+    /// threaded, with hot blocks compiled to native code, see [`jit`]
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+/// Why [`Emulator::run`] returned
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HaltReason {
+    /// the boot hart ran `hlt`
+    Hlt,
+    /// the boot hart reached a breakpoint, without running it
+    Breakpoint,
+    /// [`Emulator::step`] ran its instruction
+    Step,
+    /// stopped through [`Emulator::interrupter`]
+    Interrupted,
+    /// going back reached the state recording started in
+    HistoryStart,
+}
+
+/// How a run ended, faults are returned as [`EmuError`] instead
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RunOutcome {
+    pub reason: HaltReason,
+    /// operand of the last `hlt`
+    pub exit_code: BitSize,
 }
 
 #[derive(Debug)]
 pub struct Emulator {
+    /// boot hart
     pub cpu: Cpu,
+    /// secondary harts, parked until the guest starts them with `hstart`
+    pub harts: Vec<Cpu>,
     pub mmu: Arc<Mmu>,
+    pub dev: Devices,
+    pub engine: Engine,
+    /// host functions for `ecall`
+    pub ecalls: Ecalls,
+    /// pcs the boot hart stops at. Runs use the interpreter while there are any
+    pub breakpoints: BTreeSet<BitSize>,
+    interrupt: Arc<AtomicBool>,
+    history: Option<History>,
+}
+
+/// What the boot hart checks before each instruction, to stop early
+#[derive(Copy, Clone)]
+struct Stops<'a> {
+    breakpoints: &'a BTreeSet<BitSize>,
+    interrupt: &'a AtomicBool,
 }
 
 impl Emulator {
     pub fn new(program: &[u8]) -> Result<Self, EmuError> {
+        Self::with_devices(program, Devices::default())
+    }
+
+    /// Create with custom devices, e.g. to attach storage
+    pub fn with_devices(program: &[u8], dev: Devices) -> Result<Self, EmuError> {
+        Self::with_engine(program, dev, Engine::default())
+    }
+
+    pub fn with_engine(program: &[u8], dev: Devices, engine: Engine) -> Result<Self, EmuError> {
         let this = Self {
             cpu: Cpu::new(),
+            harts: Vec::new(),
             mmu: Arc::new(Mmu::new()?),
+            dev,
+            engine,
+            ecalls: Ecalls::default(),
+            breakpoints: BTreeSet::new(),
+            interrupt: Arc::default(),
+            history: None,
         };
 
         this.write_program(program)?;
@@ -45,6 +141,34 @@ impl Emulator {
         Ok(this)
     }
 
+    /// Set the total number of harts, including the boot hart
+    pub fn set_harts(&mut self, count: usize) {
+        self.harts = (1..count.max(1))
+            .map(|id| Cpu::with_hartid(id as BitSize))
+            .collect();
+    }
+
+    /// Map a device to a physical address range, so the guest reaches it with loads and stores.
+    /// Returns a handle for driving or inspecting it from outside
+    pub fn add_device<D: Device + 'static>(
+        &self,
+        range: impl Into<AddressRange>,
+        dev: D,
+    ) -> Result<Arc<Mutex<D>>, EmuError> {
+        let dev = Arc::new(Mutex::new(dev));
+        self.mmu.map_device(range, dev.clone())?;
+        Ok(dev)
+    }
+
+    /// Unmap the device covering addr
+    pub fn remove_device(&self, addr: BitSize) -> Option<DeviceRef> {
+        self.mmu.unmap_device(addr)
+    }
+
+    pub fn devices(&self) -> Vec<Mapping> {
+        self.mmu.devices()
+    }
+
     pub fn write_program(&self, program: &[u8]) -> Result<(), MemError> {
         let len = program.len() as BitSize;
         self.mmu.memwrite(0, program)?;
@@ -54,41 +178,389 @@ impl Emulator {
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), EmuError> {
+    /// Copy the machine state, for [`Emulator::restore`] or [`Snapshot::save`].
+    /// Only pages that were written are looked at
+    pub fn snapshot(&self) -> Result<Snapshot, EmuError> {
+        Snapshot::capture(self)
+    }
+
+    /// Go back to a snapshot's state. Memory that isn't in it is zeroed
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), EmuError> {
+        let res = snapshot.apply(self);
+
+        // the next recorded instruction starts from here
+        if let Some(history) = &mut self.history {
+            history.restored = true;
+        }
+
+        res
+    }
+
+    /// Setting this stops the boot hart with [`HaltReason::Interrupted`],
+    /// right away or at the start of the next run
+    pub fn interrupter(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Start recording, for going back with [`Emulator::step_back`] and
+    /// [`Emulator::reverse_continue`]. See [`history`] for what that costs
+    pub fn record(&mut self) -> Result<(), EmuError> {
+        self.record_with(history::DEFAULT_CHECKPOINTS)
+    }
+
+    /// [`Emulator::record`], keeping at most `checkpoints` checkpoints
+    pub fn record_with(&mut self, checkpoints: usize) -> Result<(), EmuError> {
+        let start = self.snapshot()?;
+        self.mmu.take_writes();
+        self.mmu.log_writes(true);
+        self.history = Some(History::new(
+            start,
+            checkpoints,
+            HartState::capture(&self.cpu),
+        ));
+        Ok(())
+    }
+
+    /// Stop recording and drop the history
+    pub fn stop_recording(&mut self) {
+        self.mmu.log_writes(false);
+        self.mmu.take_writes();
+        self.history = None;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Undo the last recorded instruction
+    pub fn step_back(&mut self) -> Result<RunOutcome, EmuError> {
+        history::step_back(self)
+    }
+
+    /// Go back to the last time the boot hart was at a breakpoint,
+    /// or to the start of the recording
+    pub fn reverse_continue(&mut self) -> Result<RunOutcome, EmuError> {
+        history::reverse_continue(self)
+    }
+
+    /// The last recorded store that wrote to addr
+    pub fn last_write(&self, addr: BitSize) -> Option<WriteRecord> {
+        self.history.as_ref()?.last_write(addr)
+    }
+
+    /// Run one instruction on the boot hart, with the interpreter. Other harts don't run,
+    /// so `hstart` fails with [`CpuError::HartStart`]
+    pub fn step(&mut self) -> Result<RunOutcome, EmuError> {
+        history::step(self)
+    }
+
+    fn step_hart(&mut self) -> Result<RunOutcome, EmuError> {
+        // nothing would run a started hart
+        let ctl = Harts::new(1);
+        let mut hart = Hart::new(
+            &mut self.cpu,
+            &self.mmu,
+            &self.dev,
+            &ctl,
+            Engine::Interpreter,
+            &self.ecalls,
+        );
+
         let mut stop = false;
+        hart.cycle(&mut stop)?;
 
-        loop {
-            let mut clk = 1u32;
-            let inst = self.next_inst()?;
+        let reason = match stop {
+            true => HaltReason::Hlt,
+            false => HaltReason::Step,
+        };
+
+        Ok(RunOutcome {
+            reason,
+            exit_code: self.cpu.exit_code,
+        })
+    }
+
+    /// Run until the boot hart halts, then wait for any other running harts.
+    /// The boot hart also stops at breakpoints (other than the one it starts at) and
+    /// when interrupted, which stops the other harts for good.
+    /// With more than one hart, errors are wrapped in [`EmuError::Hart`].
+    /// While recording, only the boot hart runs, see [`Emulator::step`]
+    pub fn run(&mut self) -> Result<RunOutcome, EmuError> {
+        if self.is_recording() {
+            return history::run(self);
+        }
+
+        let ctl = Harts::new(self.harts.len() + 1);
+        let (mmu, dev, ecalls) = (&self.mmu, &self.dev, &self.ecalls);
+
+        // blocks would run past breakpoints
+        let engine = match self.breakpoints.is_empty() {
+            true => self.engine,
+            false => Engine::Interpreter,
+        };
+
+        let stops = Stops {
+            breakpoints: &self.breakpoints,
+            interrupt: &self.interrupt,
+        };
+
+        let res = thread::scope(|s| {
+            let handles = self
+                .harts
+                .iter_mut()
+                .enumerate()
+                .map(|(i, cpu)| {
+                    let mut hart = Hart::new(cpu, mmu, dev, &ctl, engine, ecalls);
+                    s.spawn(move || (i + 1, hart.park(i + 1)))
+                })
+                .collect::<Vec<_>>();
 
-            if let Err(e) = self.mmu.check_prot(self.cpu.pc, Prot::Execute) {
-                return Err(EmuError::PageFault(e, self.cpu.pc));
+            let res = Hart::new(&mut self.cpu, mmu, dev, &ctl, engine, ecalls).run(Some(stops));
+
+            match &res {
+                Ok(HaltReason::Hlt) => ctl.shutdown(),
+                _ => ctl.stop(),
+            }
+
+            let mut res = res.map_err(|e| (0, e));
+            for handle in handles {
+                let (id, hart_res) = handle.join().expect("hart thread panicked");
+                res = res.and_then(|r| hart_res.map(|()| r).map_err(|e| (id, e)));
             }
 
-            if log::log_enabled!(Level::Trace) {
-                #[cold]
-                fn trace(pc: u32, i: &Instruction) {
-                    trace!(target: "aspen::cpu", "{}: {i}", format_args!("0x{pc:0>8x}").bright_green());
+            res
+        });
+
+        let reason = res.map_err(|(id, e)| match self.harts.is_empty() {
+            true => e,
+            false => EmuError::Hart(id as BitSize, Box::new(e)),
+        })?;
+
+        Ok(RunOutcome {
+            reason,
+            exit_code: self.cpu.exit_code,
+        })
+    }
+}
+
+/// One hart's view of the machine while running
+struct Hart<'a> {
+    cpu: &'a mut Cpu,
+    mmu: &'a Arc<Mmu>,
+    dev: &'a Devices,
+    ctl: &'a Harts,
+    engine: Engine,
+    ecalls: &'a Ecalls,
+    /// translated code, with the threaded engine
+    blocks: Option<Blocks>,
+    #[cfg(feature = "jit")]
+    jit: Option<jit::Jit>,
+}
+
+impl<'a> Hart<'a> {
+    fn new(
+        cpu: &'a mut Cpu,
+        mmu: &'a Arc<Mmu>,
+        dev: &'a Devices,
+        ctl: &'a Harts,
+        engine: Engine,
+        ecalls: &'a Ecalls,
+    ) -> Self {
+        let blocks = (engine != Engine::Interpreter).then(Blocks::default);
+        Self {
+            cpu,
+            mmu,
+            dev,
+            ctl,
+            engine,
+            ecalls,
+            blocks,
+            #[cfg(feature = "jit")]
+            jit: (engine == Engine::Jit).then(jit::Jit::new),
+        }
+    }
+
+    /// Secondary hart thread, runs every time the guest starts it
+    fn park(&mut self, id: usize) -> Result<(), EmuError> {
+        while let Some((pc, arg)) = self.ctl.wait(id) {
+            *self.cpu = Cpu::with_hartid(id as BitSize);
+            self.cpu.pc = pc;
+            self.cpu.gp.a0 = arg;
+
+            let res = self.run(None);
+            self.ctl.halted(id);
+
+            if let Err(e) = res {
+                self.ctl.stop();
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run(&mut self, stops: Option<Stops>) -> Result<HaltReason, EmuError> {
+        let mut stop = false;
+        // resuming at a breakpoint runs it
+        let mut first = true;
+
+        loop {
+            if let Some(stops) = stops {
+                if stops.interrupt.load(Ordering::Relaxed) {
+                    stops.interrupt.store(false, Ordering::Relaxed);
+                    return Ok(HaltReason::Interrupted);
+                }
+
+                if !first && stops.breakpoints.contains(&self.cpu.pc) {
+                    return Ok(HaltReason::Breakpoint);
                 }
 
-                trace(self.cpu.pc, &inst);
+                first = false;
             }
 
-            self.cpu.process(inst, &self.mmu, &mut stop, &mut clk)?;
+            self.cycle(&mut stop)?;
+
+            // another hart faulted
+            stop |= self.ctl.stopped();
 
             #[rustfmt::skip]
             if stop { break; };
+        }
+
+        Ok(HaltReason::Hlt)
+    }
 
-            // clock cycles we've been powered on for
+    /// Run one instruction, or a block of them
+    fn cycle(&mut self, stop: &mut bool) -> Result<(), EmuError> {
+        // devices keep the boot hart's time
+        if self.cpu.cr.hartid == 0 {
+            self.mmu.tick_devices(self.cpu.clk);
+        }
+
+        self.cpu.poll_irqs(self.dev, self.mmu);
+        self.cpu.interrupt();
+
+        match self.engine {
+            Engine::Interpreter => self.tick(stop),
+            Engine::Threaded => self.run_block(stop),
+            #[cfg(feature = "jit")]
+            Engine::Jit => self.run_native(stop),
+        }
+    }
+
+    /// Run one instruction
+    fn tick(&mut self, stop: &mut bool) -> Result<(), EmuError> {
+        let mut clk = 1u32;
+
+        if let Err(e) = self.step(stop, &mut clk) {
+            self.trap(e)?;
+        }
+
+        // clock cycles we've been powered on for
+        if !*stop {
             self.cpu.clk += clk as u64;
         }
 
         Ok(())
     }
 
-    fn next_inst(&self) -> Result<Instruction, EmuError> {
-        let mut buf = [0u8; 8];
-        self.mmu.memcpy(self.cpu.pc, &mut buf)?;
+    /// Run one translated block, or one instruction if there's none at pc
+    fn run_block(&mut self, stop: &mut bool) -> Result<(), EmuError> {
+        let Some(blocks) = self.blocks.as_mut() else {
+            return self.tick(stop);
+        };
+
+        let env = Env {
+            mmu: self.mmu,
+            dev: self.dev,
+            harts: self.ctl,
+        };
+
+        match blocks.run(self.cpu, &env, stop) {
+            Ok(true) => Ok(()),
+            Ok(false) => self.tick(stop),
+            // the faulting instruction still takes its cycle
+            Err(e) => {
+                self.trap(e)?;
+                self.cpu.clk += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn step(&mut self, stop: &mut bool, clk: &mut u32) -> Result<(), EmuError> {
+        let inst = self.next_inst()?;
+
+        if log::log_enabled!(Level::Trace) {
+            #[cold]
+            fn trace(hart: BitSize, pc: u32, i: &Instruction) {
+                trace!(target: "aspen::cpu", "[{hart}] {}: {i}", format_args!("0x{pc:0>8x}").bright_green());
+            }
+
+            trace(self.cpu.cr.hartid, self.cpu.pc, &inst);
+        }
+
+        // the cpu doesn't know about the host side
+        if inst.ty == InstructionType::Ecall {
+            self.ecalls.call(&mut self.cpu.gp, self.mmu)?;
+            self.cpu.pc = self.cpu.pc.wrapping_add(if inst.has_imm { 8 } else { 4 });
+            return Ok(());
+        }
+
+        self.cpu
+            .process(inst, self.mmu, self.dev, self.ctl, stop, clk)?;
+
+        Ok(())
+    }
+
+    /// Run a compiled block, or a threaded one if there's none at pc
+    #[cfg(feature = "jit")]
+    fn run_native(&mut self, stop: &mut bool) -> Result<(), EmuError> {
+        let Some(jit) = self.jit.as_mut() else {
+            return self.run_block(stop);
+        };
+
+        match jit.run(self.cpu, self.mmu, stop) {
+            Ok(true) => Ok(()),
+            Ok(false) => self.run_block(stop),
+            // the faulting instruction still takes its cycle
+            Err(e) => {
+                self.trap(e)?;
+                self.cpu.clk += 1;
+                Ok(())
+            }
+        }
+    }
+
+    /// Vector a fault to the guest trap handler, or give it back if the guest can't handle it
+    fn trap(&mut self, e: EmuError) -> Result<(), EmuError> {
+        if !self.cpu.can_trap() {
+            return Err(e);
+        }
+
+        let Some((cause, val)) = e.trap_cause(self.cpu.pc) else {
+            return Err(e);
+        };
+
+        debug!(target: "aspen::cpu", "trap {cause:?} @ 0x{:0>8x}: {e}", self.cpu.pc);
+
+        self.cpu.trap(cause, val);
+
+        Ok(())
+    }
+
+    fn next_inst(&mut self) -> Result<Instruction, EmuError> {
+        let pc = self.cpu.pc;
+        match self.cpu.fetch_cached(self.mmu) {
+            Ok(Some(inst)) => return Ok(inst),
+            Ok(None) => (),
+            Err(e) => return Err(EmuError::PageFault(e, pc)),
+        }
+
+        let buf = self
+            .cpu
+            .fetch(self.mmu)
+            .map_err(|e| EmuError::PageFault(e, self.cpu.pc))?;
         let i = Instruction::from_buf(buf)?;
 
         Ok(i)
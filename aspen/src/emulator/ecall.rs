@@ -0,0 +1,72 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use sayuri::sync::Mutex;
+
+use crate::{BitSize, cpu::Registers, emulator::EmuError, mmu::Mmu};
+
+/// What a handler puts in `a0` / `a1`
+pub type EcallResult = Result<(BitSize, BitSize), Box<dyn Error + Send + Sync>>;
+
+type Handler = Box<dyn FnMut(&mut Registers, &Mmu) -> EcallResult + Send>;
+
+/// Host functions the guest reaches with `ecall`, keyed by the number in `a7`
+///
+/// Handlers get the physical [`Mmu`]. Once the guest turns paging on, the pointers it
+/// passes are virtual addresses, and have to be translated before use
+#[derive(Default)]
+pub struct Ecalls {
+    handlers: Mutex<HashMap<BitSize, Handler>>,
+    /// handlers that returned, so a recording can tell which steps were ecalls
+    calls: AtomicU64,
+}
+
+impl fmt::Debug for Ecalls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut nums = self.handlers.lock().keys().copied().collect::<Vec<_>>();
+        nums.sort_unstable();
+        f.debug_tuple("Ecalls").field(&nums).finish()
+    }
+}
+
+impl Ecalls {
+    /// Handle ecall `num`, replacing any previous handler
+    pub fn register(
+        &self,
+        num: BitSize,
+        f: impl FnMut(&mut Registers, &Mmu) -> EcallResult + Send + 'static,
+    ) {
+        self.handlers.lock().insert(num, Box::new(f));
+    }
+
+    /// Remove the handler for `num`, returning whether there was one
+    pub fn unregister(&self, num: BitSize) -> bool {
+        self.handlers.lock().remove(&num).is_some()
+    }
+
+    /// Run the handler picked by `a7`
+    pub(super) fn call(&self, regs: &mut Registers, mmu: &Mmu) -> Result<(), EmuError> {
+        let num = regs.a7;
+        let mut handlers = self.handlers.lock();
+
+        let Some(f) = handlers.get_mut(&num) else {
+            return Err(EmuError::Ecall(num, "no handler registered".into()));
+        };
+
+        let (a0, a1) = f(regs, mmu).map_err(|e| EmuError::Ecall(num, e.to_string()))?;
+        regs.a0 = a0;
+        regs.a1 = a1;
+        self.calls.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// How many ecalls were handled so far
+    pub(super) fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+}
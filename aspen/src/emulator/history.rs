@@ -0,0 +1,311 @@
+//! Recording for reverse execution.
+//!
+//! While recording, the boot hart runs one instruction at a time and its pc and stores are
+//! logged, with a [`Snapshot`] every [`CHECKPOINT_INTERVAL`] instructions. Going back restores
+//! the closest checkpoint and replays up to the target, which drops the history after it.
+//! Replays are only exact without other harts or device input, and devices see
+//! replayed accesses again.
+//!
+//! Ecall handlers aren't run again by replays. The registers and memory a handler left
+//! behind are recorded and put back instead, so host side effects happen once and a handler
+//! answering differently can't change the past. Going forward from the past calls the handler.
+//!
+//! Edits from outside (registers, memory, restoring a snapshot) between instructions get a
+//! checkpoint of their own, so replays keep them and they aren't blamed on the guest.
+//!
+//! Only the last few checkpoints are kept, see [`DEFAULT_CHECKPOINTS`]. Past that, the oldest
+//! one is dropped along with the log before it, and going back stops there as if the
+//! recording started there. Memory use is at most that many snapshots, and the log of
+//! as many intervals
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::atomic::Ordering,
+};
+
+use super::{
+    EmuError, Emulator, HaltReason, RunOutcome,
+    snapshot::{HartState, Snapshot},
+};
+use crate::BitSize;
+
+/// Instructions between checkpoints, going back replays at most this many
+pub const CHECKPOINT_INTERVAL: u64 = 10_000;
+
+/// Checkpoints kept by [`Emulator::record`], so going back reaches at least
+/// `(DEFAULT_CHECKPOINTS - 1) * CHECKPOINT_INTERVAL` instructions when nothing is edited
+pub const DEFAULT_CHECKPOINTS: usize = 32;
+
+/// A store to memory, and the instruction that did it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WriteRecord {
+    /// how many instructions into the recording it ran
+    pub index: u64,
+    pub pc: BitSize,
+    pub addr: BitSize,
+    pub len: BitSize,
+}
+
+/// What an ecall left behind, to replay it without the host
+#[derive(Debug, Clone)]
+struct EcallRecord {
+    after: HartState,
+    /// physical address and contents of everything the handler wrote
+    mem: Vec<(BitSize, Box<[u8]>)>,
+}
+
+impl EcallRecord {
+    fn replay(&self, emu: &mut Emulator) -> Result<RunOutcome, EmuError> {
+        self.after.apply_boot(emu)?;
+        for (addr, bytes) in &self.mem {
+            emu.mmu.memwrite(*addr, bytes)?;
+        }
+
+        Ok(RunOutcome {
+            reason: HaltReason::Step,
+            exit_code: emu.cpu.exit_code,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct History {
+    /// oldest first, going back stops at the first one
+    checkpoints: VecDeque<(u64, Snapshot)>,
+    /// most checkpoints kept
+    limit: usize,
+    /// pc of every instruction run since the first checkpoint
+    pcs: VecDeque<BitSize>,
+    /// in the order they happened
+    writes: VecDeque<WriteRecord>,
+    /// by the index of the instruction
+    ecalls: BTreeMap<u64, EcallRecord>,
+    /// boot hart after the last instruction, to notice edits
+    after: HartState,
+    /// a snapshot was restored from outside
+    pub(super) restored: bool,
+}
+
+impl History {
+    pub(super) fn new(start: Snapshot, limit: usize, cpu: HartState) -> Self {
+        Self {
+            checkpoints: VecDeque::from([(0, start)]),
+            limit: limit.max(1),
+            pcs: VecDeque::new(),
+            writes: VecDeque::new(),
+            ecalls: BTreeMap::new(),
+            after: cpu,
+            restored: false,
+        }
+    }
+
+    /// Index of the first checkpoint
+    fn start(&self) -> u64 {
+        self.checkpoints[0].0
+    }
+
+    fn now(&self) -> u64 {
+        self.start() + self.pcs.len() as u64
+    }
+
+    pub(super) fn last_write(&self, addr: BitSize) -> Option<WriteRecord> {
+        self.writes
+            .iter()
+            .rev()
+            .find(|w| addr.wrapping_sub(w.addr) < w.len)
+            .copied()
+    }
+
+    /// Add a checkpoint, replacing one at the same index and dropping the oldest past the limit
+    fn checkpoint(&mut self, index: u64, snapshot: Snapshot) {
+        if self.checkpoints.back().is_some_and(|(i, _)| *i == index) {
+            self.checkpoints.pop_back();
+        }
+
+        self.checkpoints.push_back((index, snapshot));
+
+        let old = self.start();
+        while self.checkpoints.len() > self.limit {
+            self.checkpoints.pop_front();
+        }
+
+        let start = self.start();
+        self.pcs.drain(..(start - old) as usize);
+        let gone = self.writes.partition_point(|w| w.index < start);
+        self.writes.drain(..gone);
+        self.ecalls = self.ecalls.split_off(&start);
+    }
+}
+
+/// [`Emulator::step`], logging the instruction
+pub(super) fn step(emu: &mut Emulator) -> Result<RunOutcome, EmuError> {
+    let Some(history) = &emu.history else {
+        return emu.step_hart();
+    };
+
+    // nothing the guest did writes between instructions
+    let edited = !emu.mmu.take_writes().is_empty()
+        || history.restored
+        || history.after != HartState::capture(&emu.cpu);
+
+    let now = history.now();
+    let last = history.checkpoints.back().map_or(0, |(index, _)| *index);
+    if edited || now - last >= CHECKPOINT_INTERVAL {
+        let snapshot = emu.snapshot()?;
+        history_mut(emu).checkpoint(now, snapshot);
+    }
+
+    let pc = emu.cpu.pc;
+    let calls = emu.ecalls.calls();
+    let res = match history_mut(emu).ecalls.get(&now).cloned() {
+        Some(ecall) => ecall.replay(emu),
+        None => emu.step_hart(),
+    };
+
+    let writes = emu.mmu.take_writes();
+    let after = HartState::capture(&emu.cpu);
+
+    // the guest doesn't write during an ecall, all of it is the handler's
+    let ecall = (emu.ecalls.calls() != calls).then(|| EcallRecord {
+        after: after.clone(),
+        mem: writes
+            .iter()
+            .filter_map(|&(addr, len)| {
+                let mut bytes = vec![0; len as usize];
+                emu.mmu.memcpy(addr, &mut bytes).ok()?;
+                Some((addr, bytes.into_boxed_slice()))
+            })
+            .collect(),
+    });
+
+    let history = history_mut(emu);
+    if let Some(ecall) = ecall {
+        history.ecalls.insert(now, ecall);
+    }
+
+    history.pcs.push_back(pc);
+    history.after = after;
+    history.restored = false;
+    history
+        .writes
+        .extend(writes.into_iter().map(|(addr, len)| WriteRecord {
+            index: now,
+            pc,
+            addr,
+            len,
+        }));
+
+    res
+}
+
+/// [`Emulator::run`] for the boot hart alone, one logged instruction at a time
+pub(super) fn run(emu: &mut Emulator) -> Result<RunOutcome, EmuError> {
+    // resuming at a breakpoint runs it
+    let mut first = true;
+
+    loop {
+        let reason = if emu.interrupt.swap(false, Ordering::Relaxed) {
+            Some(HaltReason::Interrupted)
+        } else if !first && emu.breakpoints.contains(&emu.cpu.pc) {
+            Some(HaltReason::Breakpoint)
+        } else {
+            None
+        };
+
+        if let Some(reason) = reason {
+            return Ok(RunOutcome {
+                reason,
+                exit_code: emu.cpu.exit_code,
+            });
+        }
+
+        first = false;
+
+        let outcome = step(emu)?;
+        if outcome.reason == HaltReason::Hlt {
+            return Ok(outcome);
+        }
+    }
+}
+
+pub(super) fn step_back(emu: &mut Emulator) -> Result<RunOutcome, EmuError> {
+    let history = emu.history.as_ref().ok_or(EmuError::NotRecording)?;
+    let now = history.now();
+
+    let reason = match now > history.start() {
+        true => {
+            goto(emu, now - 1)?;
+            HaltReason::Step
+        }
+        false => HaltReason::HistoryStart,
+    };
+
+    Ok(RunOutcome {
+        reason,
+        exit_code: emu.cpu.exit_code,
+    })
+}
+
+pub(super) fn reverse_continue(emu: &mut Emulator) -> Result<RunOutcome, EmuError> {
+    let history = emu.history.as_ref().ok_or(EmuError::NotRecording)?;
+
+    let hit = history
+        .pcs
+        .iter()
+        .rposition(|pc| emu.breakpoints.contains(pc));
+
+    let (target, reason) = match hit {
+        Some(offset) => (history.start() + offset as u64, HaltReason::Breakpoint),
+        None => (history.start(), HaltReason::HistoryStart),
+    };
+
+    goto(emu, target)?;
+
+    Ok(RunOutcome {
+        reason,
+        exit_code: emu.cpu.exit_code,
+    })
+}
+
+/// Go back to the state before instruction `target` ran
+fn goto(emu: &mut Emulator, target: u64) -> Result<(), EmuError> {
+    let history = history_mut(emu);
+    let pos = history
+        .checkpoints
+        .iter()
+        .rposition(|(index, _)| *index <= target)
+        .expect("target to be after the first checkpoint");
+
+    history.checkpoints.truncate(pos + 1);
+    let (start, snapshot) = history.checkpoints[pos].clone();
+
+    let kept = (start - history.start()) as usize;
+    history.pcs.truncate(kept);
+    let kept = history.writes.partition_point(|w| w.index < start);
+    history.writes.truncate(kept);
+    // what comes after the target is a new run, its ecalls go to the host
+    history.ecalls.split_off(&target);
+
+    // restoring isn't something the guest did
+    emu.mmu.log_writes(false);
+    let res = snapshot.apply(emu);
+    emu.mmu.take_writes();
+    emu.mmu.log_writes(true);
+    res?;
+
+    let after = HartState::capture(&emu.cpu);
+    let history = history_mut(emu);
+    history.after = after;
+    history.restored = false;
+
+    for _ in start..target {
+        // faults were recorded too, they happen again the same way
+        let _ = step(emu);
+    }
+
+    Ok(())
+}
+
+fn history_mut(emu: &mut Emulator) -> &mut History {
+    emu.history.as_mut().expect("recording")
+}
@@ -0,0 +1,1058 @@
+//! Native code for hot blocks, through Cranelift.
+//!
+//! Blocks start out on the threaded engine. Once one has been entered [`HOT`] times,
+//! the straight-line part of it is compiled to a native function taking a [`Context`].
+//! Guest registers live in the context while the function runs, and are kept in host
+//! registers in between; memory and stack accesses call back into the cpu, so they get
+//! the same protection checks and translation as the interpreter.
+//!
+//! Only plain computation, memory, stack and control flow instructions are compiled.
+//! A block stops short of anything else (devices like `gfx` and `draw`, control
+//! registers, division), and the threaded engine runs it.
+//!
+//! Code memory is freed all at once. After [`MAX_COMPILED`] compiles, counting the ones
+//! replaced after self-modifying code or evicted from the cache, everything compiled is
+//! thrown away and blocks warm up again.
+
+use std::{
+    mem::{ManuallyDrop, offset_of},
+    sync::Arc,
+};
+
+use cranelift_codegen::{
+    Context as Func,
+    ir::{AbiParam, FuncRef, InstBuilder as _, MemFlags, Type, Value, condcodes::IntCC, types},
+    settings::{self, Configurable as _},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Linkage, Module as _, default_libcall_names};
+use log::debug;
+
+use crate::{
+    BitSize,
+    cpu::{Cpu, CpuError, Reg, Registers},
+    emulator::EmuError,
+    instruction::{Instruction, InstructionType},
+    mmu::{Mmu, PAGE_SIZE, Prot, Protection},
+};
+
+/// Entries into a block before it's compiled
+pub const HOT: u32 = 32;
+
+const PAGE_MASK: BitSize = PAGE_SIZE as BitSize - 1;
+/// Longest block, in instructions
+const MAX_BLOCK: usize = 64;
+const CACHE_SIZE: usize = 4096;
+/// Compiles before the code memory is freed
+pub const MAX_COMPILED: usize = 2 * CACHE_SIZE;
+const REGS: usize = size_of::<Registers>() / size_of::<BitSize>();
+
+/// Native function return values
+const EXIT_OK: i64 = 0;
+const EXIT_STOP: i64 = 1;
+const EXIT_FAULT: i64 = 2;
+
+/// Callback results with this bit set faulted, the error is in [`Context::err`]
+const FAULT: u64 = 1 << 32;
+
+/// State shared with native code
+#[repr(C)]
+pub struct Context {
+    gp: Registers,
+    /// pc after the block
+    pc: BitSize,
+    clk: u64,
+    /// `hlt` operand, when leaving with [`EXIT_STOP`]
+    exit_code: BitSize,
+    cpu: *mut Cpu,
+    mmu: *const Mmu,
+    err: Option<CpuError>,
+}
+
+type NativeFn = unsafe extern "C" fn(*mut Context) -> i64;
+
+#[derive(Copy, Clone, Default)]
+struct Slot {
+    /// physical address + 1, 0 is an empty slot
+    tag: BitSize,
+    pc: BitSize,
+    generation: BitSize,
+    /// physical protection when the block was first seen
+    prot: Protection,
+    hits: u32,
+    code: Option<NativeFn>,
+}
+
+/// Compiler and compiled code of one hart
+pub struct Jit {
+    /// freed on drop, along with every function in it
+    module: ManuallyDrop<JITModule>,
+    builder: FunctionBuilderContext,
+    func: Func,
+    callbacks: Callbacks,
+    slots: Vec<Slot>,
+    /// functions in the module, live or not
+    compiled: usize,
+}
+
+// SAFETY: the module owns its code memory, nothing in it is tied to the thread that made it
+unsafe impl Send for Jit {}
+
+#[derive(Copy, Clone)]
+struct Callbacks {
+    load: FuncId,
+    store: FuncId,
+    push: FuncId,
+    pop: FuncId,
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").unwrap();
+        flags.set("use_colocated_libcalls", "false").unwrap();
+        flags.set("is_pic", "false").unwrap();
+
+        let isa = cranelift_native::builder()
+            .expect("host to be supported")
+            .finish(settings::Flags::new(flags))
+            .expect("isa to build");
+
+        let mut jb = JITBuilder::with_isa(isa, default_libcall_names());
+        jb.symbol("aspen_load", jit_load as *const u8)
+            .symbol("aspen_store", jit_store as *const u8)
+            .symbol("aspen_push", jit_push as *const u8)
+            .symbol("aspen_pop", jit_pop as *const u8);
+
+        let mut module = JITModule::new(jb);
+
+        let ptr = module.target_config().pointer_type();
+        let mut declare = |name, params: &[Type], ret: Type| {
+            let mut sig = module.make_signature();
+            sig.params.push(AbiParam::new(ptr));
+            sig.params.extend(params.iter().map(|&t| AbiParam::new(t)));
+            sig.returns.push(AbiParam::new(ret));
+
+            module
+                .declare_function(name, Linkage::Import, &sig)
+                .expect("callback to declare")
+        };
+
+        let callbacks = Callbacks {
+            load: declare("aspen_load", &[types::I32, types::I32], types::I64),
+            store: declare(
+                "aspen_store",
+                &[types::I32, types::I32, types::I32],
+                types::I64,
+            ),
+            push: declare("aspen_push", &[types::I32, types::I32], types::I64),
+            pop: declare("aspen_pop", &[], types::I64),
+        };
+
+        let func = module.make_context();
+
+        Self {
+            module: ManuallyDrop::new(module),
+            builder: FunctionBuilderContext::new(),
+            func,
+            callbacks,
+            slots: vec![Slot::default(); CACHE_SIZE],
+            compiled: 0,
+        }
+    }
+
+    /// Run native code for the block at pc, if it's hot enough to have some.
+    /// Ok(false) if there's none, another engine should run it
+    pub fn run(
+        &mut self,
+        cpu: &mut Cpu,
+        mmu: &Arc<Mmu>,
+        stop: &mut bool,
+    ) -> Result<bool, EmuError> {
+        let pc = cpu.pc;
+        if !pc.is_multiple_of(4) {
+            return Ok(false);
+        }
+
+        if self.compiled >= MAX_COMPILED {
+            debug!(target: "aspen::jit", "freeing {} functions", self.compiled);
+            // drops the old module
+            *self = Self::new();
+        }
+
+        let paddr = match cpu.paging() {
+            true => match cpu.translate(mmu, pc, Prot::Execute) {
+                Ok(paddr) => paddr,
+                Err(_) => return Ok(false),
+            },
+            false => pc,
+        };
+
+        let idx = (paddr >> 2) as usize % CACHE_SIZE;
+        let slot = &mut self.slots[idx];
+
+        let fresh =
+            slot.tag == paddr + 1 && slot.pc == pc && slot.generation == mmu.code_gen(paddr);
+        if !fresh {
+            *slot = Slot {
+                tag: paddr + 1,
+                pc,
+                generation: mmu.mark_code(paddr),
+                prot: mmu.prot(paddr),
+                hits: 0,
+                code: None,
+            };
+        }
+
+        if !cpu.paging() && !slot.prot.contains(cpu.access(Prot::Execute)) {
+            return Ok(false);
+        }
+
+        if slot.code.is_none() {
+            slot.hits = slot.hits.saturating_add(1);
+            if slot.hits != HOT {
+                return Ok(false);
+            }
+
+            let code = self.compile(mmu, paddr, pc);
+            self.slots[idx].code = code;
+        }
+
+        let Some(code) = self.slots[idx].code else {
+            return Ok(false);
+        };
+
+        let mut ctx = Context {
+            gp: cpu.gp,
+            pc,
+            clk: cpu.clk,
+            exit_code: 0,
+            cpu: &raw mut *cpu,
+            mmu: Arc::as_ptr(mmu),
+            err: None,
+        };
+
+        // SAFETY: the code was compiled for this context layout, and the cpu and mmu
+        // pointers stay valid for the call. Nothing else touches the cpu meanwhile
+        let exit = unsafe { code(&mut ctx) };
+
+        cpu.gp = ctx.gp;
+        cpu.pc = ctx.pc;
+        cpu.clk = ctx.clk;
+
+        match exit {
+            EXIT_OK => Ok(true),
+
+            EXIT_STOP => {
+                cpu.exit_code = ctx.exit_code;
+                *stop = true;
+                Ok(true)
+            }
+
+            _ => {
+                let e = ctx.err.take().expect("fault to set an error");
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Compile the block at paddr, pc being its guest address.
+    /// None if it starts with something that can't be compiled
+    fn compile(&mut self, mmu: &Mmu, paddr: BitSize, pc: BitSize) -> Option<NativeFn> {
+        let insts = decode(mmu, paddr, pc);
+        if insts.is_empty() {
+            return None;
+        }
+
+        self.module.clear_context(&mut self.func);
+
+        let ptr = self.module.target_config().pointer_type();
+        self.func.func.signature.params.push(AbiParam::new(ptr));
+        self.func
+            .func
+            .signature
+            .returns
+            .push(AbiParam::new(types::I64));
+
+        let id = self
+            .module
+            .declare_anonymous_function(&self.func.func.signature)
+            .ok()?;
+
+        let b = FunctionBuilder::new(&mut self.func.func, &mut self.builder);
+        let mut e = Emitter::new(b, &mut self.module, self.callbacks, &insts);
+
+        for &(inst, pc) in &insts {
+            e.inst(inst, pc);
+        }
+
+        e.finish();
+
+        if let Err(e) = self.module.define_function(id, &mut self.func) {
+            debug!(target: "aspen::jit", "0x{pc:0>8x}: {e}");
+            return None;
+        }
+
+        self.module.finalize_definitions().ok()?;
+        let code = self.module.get_finalized_function(id);
+        self.compiled += 1;
+
+        debug!(target: "aspen::jit", "compiled 0x{pc:0>8x}, {} instructions", insts.len());
+
+        // SAFETY: the function was built with the NativeFn signature
+        Some(unsafe { std::mem::transmute::<*const u8, NativeFn>(code) })
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        // SAFETY: the only pointers into the code are in the slots, which go away too
+        unsafe { ManuallyDrop::take(&mut self.module).free_memory() };
+    }
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decode the compilable instructions from paddr on, with their guest address
+fn decode(mmu: &Mmu, paddr: BitSize, pc: BitSize) -> Vec<(Instruction, BitSize)> {
+    let mut insts = Vec::new();
+    let (mut paddr, mut pc) = (paddr, pc);
+
+    while insts.len() < MAX_BLOCK {
+        // the imm word might be on the next page
+        if paddr & PAGE_MASK > PAGE_MASK - 7 {
+            break;
+        }
+
+        let mut buf = [0; 8];
+        if mmu.memcpy(paddr, &mut buf).is_err() {
+            break;
+        }
+
+        let Ok(inst) = Instruction::from_buf(buf) else {
+            break;
+        };
+
+        if !supported(inst.ty) {
+            break;
+        }
+
+        insts.push((inst, pc));
+
+        if ends_block(inst.ty) {
+            break;
+        }
+
+        let len = if inst.has_imm { 8 } else { 4 };
+        paddr += len;
+        pc = pc.wrapping_add(len);
+    }
+
+    insts
+}
+
+fn supported(ty: InstructionType) -> bool {
+    use InstructionType::*;
+
+    matches!(
+        ty,
+        Nop | Hlt
+            | Nand
+            | Or
+            | And
+            | Nor
+            | Add
+            | Sub
+            | Xor
+            | Lsl
+            | Lsr
+            | Mul
+            | Imul
+            | Mulh
+            | Mulhu
+            | Addc
+            | Subb
+            | Se
+            | Sne
+            | Sl
+            | Sle
+            | Sg
+            | Sge
+            | Asr
+            | Fadd
+            | Fsub
+            | Fmul
+            | Fdiv
+            | Clz
+            | Ctz
+            | Popcnt
+            | Rol
+            | Ror
+            | Bswap
+            | Min
+            | Max
+            | Minu
+            | Maxu
+            | Csel
+            | Mov
+            | Inc
+            | Dec
+            | Ld
+            | Ldw
+            | Ldb
+            | Str
+            | Strw
+            | Strb
+            | Ldo
+            | Ldwo
+            | Ldbo
+            | Stro
+            | Strwo
+            | Strbo
+            | Ldr
+            | Ldwr
+            | Ldbr
+            | Ldws
+            | Ldbs
+            | Ldwso
+            | Ldbso
+            | Push
+            | Pop
+            | Call
+            | Callr
+            | Ret
+            | Jmp
+            | Jmpr
+            | Je
+            | Jne
+            | Jl
+            | Jge
+            | Jle
+            | Jg
+            | Jb
+            | Jae
+            | Jbe
+            | Ja
+            | Jer
+            | Jner
+            | Jlr
+            | Jger
+            | Jler
+            | Jgr
+            | Jbr
+            | Jaer
+            | Jber
+            | Jar
+    )
+}
+
+fn ends_block(ty: InstructionType) -> bool {
+    use InstructionType::*;
+
+    #[rustfmt::skip]
+    let ends = matches!(
+        ty,
+        Hlt | Call | Callr | Ret | Jmp | Jmpr
+            | Je | Jne | Jl | Jge | Jle | Jg | Jb | Jae | Jbe | Ja
+            | Jer | Jner | Jlr | Jger | Jler | Jgr | Jbr | Jaer | Jber | Jar
+    );
+
+    ends
+}
+
+/// Builds the native function for one block
+struct Emitter<'a> {
+    b: FunctionBuilder<'a>,
+    ctx: Value,
+    load: FuncRef,
+    store: FuncRef,
+    push: FuncRef,
+    pop: FuncRef,
+    /// registers written so far, stored back on every exit
+    dirty: u32,
+    /// cycles of the instructions so far
+    clk: u64,
+    /// the last instruction left the block
+    done: bool,
+    /// pc after the last instruction
+    end: BitSize,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(
+        mut b: FunctionBuilder<'a>,
+        module: &mut JITModule,
+        callbacks: Callbacks,
+        insts: &[(Instruction, BitSize)],
+    ) -> Self {
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        let ctx = b.block_params(entry)[0];
+
+        let mut import = |id| module.declare_func_in_func(id, b.func);
+        let (load, store, push, pop) = (
+            import(callbacks.load),
+            import(callbacks.store),
+            import(callbacks.push),
+            import(callbacks.pop),
+        );
+
+        // only load the registers the block uses
+        let mut used = 0u32;
+        for (inst, _) in insts {
+            for reg in [inst.dst, inst.a, inst.b] {
+                used |= 1 << reg as u32;
+            }
+
+            // the carry/borrow in and out
+            if matches!(inst.ty, InstructionType::Addc | InstructionType::Subb) {
+                used |= 1 << inst.c as u32;
+            }
+
+            if matches!(
+                inst.ty,
+                InstructionType::Call | InstructionType::Callr | InstructionType::Ret
+            ) {
+                used |= 1 << Reg::Ra as u32;
+            }
+
+            if matches!(
+                inst.ty,
+                InstructionType::Push
+                    | InstructionType::Pop
+                    | InstructionType::Call
+                    | InstructionType::Callr
+                    | InstructionType::Ret
+            ) {
+                used |= 1 << Reg::Sp as u32;
+            }
+        }
+
+        for i in 0..REGS {
+            b.declare_var(Variable::from_u32(i as u32), types::I32);
+
+            if used & (1 << i) != 0 {
+                let val = b
+                    .ins()
+                    .load(types::I32, MemFlags::trusted(), ctx, reg_offset(i));
+                b.def_var(Variable::from_u32(i as u32), val);
+            }
+        }
+
+        let (last, pc) = insts[insts.len() - 1];
+
+        Self {
+            b,
+            ctx,
+            load,
+            store,
+            push,
+            pop,
+            dirty: 0,
+            clk: 0,
+            done: false,
+            end: pc.wrapping_add(if last.has_imm { 8 } else { 4 }),
+        }
+    }
+
+    fn get(&mut self, reg: Reg) -> Value {
+        self.b.use_var(Variable::from_u32(reg as u32))
+    }
+
+    fn set(&mut self, reg: Reg, val: Value) {
+        // zr is a noop
+        if matches!(reg, Reg::Zr) {
+            return;
+        }
+
+        self.b.def_var(Variable::from_u32(reg as u32), val);
+        self.dirty |= 1 << reg as u32;
+    }
+
+    fn imm(&mut self, val: BitSize) -> Value {
+        self.b.ins().iconst(types::I32, val as i64)
+    }
+
+    /// imm or reg
+    fn imm_or(&mut self, inst: Instruction, reg: Reg) -> Value {
+        match inst.has_imm {
+            true => self.imm(inst.imm),
+            false => self.get(reg),
+        }
+    }
+
+    /// Store registers back and leave with pc, having run `clk` cycles
+    fn exit(&mut self, pc: Value, clk: u64, code: i64) {
+        for i in 0..REGS {
+            if self.dirty & (1 << i) != 0 {
+                let val = self.b.use_var(Variable::from_u32(i as u32));
+                self.b
+                    .ins()
+                    .store(MemFlags::trusted(), val, self.ctx, reg_offset(i));
+            }
+        }
+
+        self.b.ins().store(
+            MemFlags::trusted(),
+            pc,
+            self.ctx,
+            offset_of!(Context, pc) as i32,
+        );
+
+        if clk != 0 {
+            let off = offset_of!(Context, clk) as i32;
+            let old = self
+                .b
+                .ins()
+                .load(types::I64, MemFlags::trusted(), self.ctx, off);
+            let new = self.b.ins().iadd_imm(old, clk as i64);
+            self.b.ins().store(MemFlags::trusted(), new, self.ctx, off);
+        }
+
+        let code = self.b.ins().iconst(types::I64, code);
+        self.b.ins().return_(&[code]);
+    }
+
+    /// Call a callback, leaving through a fault exit at pc if it faulted
+    fn call(&mut self, f: FuncRef, args: &[Value], pc: BitSize) -> Value {
+        let mut call_args = vec![self.ctx];
+        call_args.extend_from_slice(args);
+
+        let call = self.b.ins().call(f, &call_args);
+        let res = self.b.inst_results(call)[0];
+
+        let fault_block = self.b.create_block();
+        let next = self.b.create_block();
+
+        let fault = self.b.ins().band_imm(res, FAULT as i64);
+        self.b.ins().brif(fault, fault_block, &[], next, &[]);
+
+        self.b.switch_to_block(fault_block);
+        let pc = self.imm(pc);
+        self.exit(pc, self.clk, EXIT_FAULT);
+
+        self.b.switch_to_block(next);
+
+        self.b.ins().ireduce(types::I32, res)
+    }
+
+    /// The callbacks work on sp in the context
+    fn flush_sp(&mut self) {
+        let sp = self.get(Reg::Sp);
+        self.b.ins().store(
+            MemFlags::trusted(),
+            sp,
+            self.ctx,
+            reg_offset(Reg::Sp as usize),
+        );
+    }
+
+    fn reload_sp(&mut self) {
+        let sp = self.b.ins().load(
+            types::I32,
+            MemFlags::trusted(),
+            self.ctx,
+            reg_offset(Reg::Sp as usize),
+        );
+        self.set(Reg::Sp, sp);
+    }
+
+    fn inst(&mut self, inst: Instruction, pc: BitSize) {
+        use InstructionType::*;
+
+        let next = pc.wrapping_add(if inst.has_imm { 8 } else { 4 });
+
+        // dst = f(reg a, imm or reg b)
+        macro_rules! alu {
+            (|$e:ident, $a:ident, $b:ident| $body:expr) => {{
+                let $a = self.get(inst.a);
+                let $b = self.imm_or(inst, inst.b);
+                let $e = &mut self.b;
+                let val = $body;
+                self.set(inst.dst, val);
+            }};
+        }
+
+        macro_rules! set_if {
+            ($cc:expr) => {
+                alu!(|e, a, b| {
+                    let c = e.ins().icmp($cc, a, b);
+                    e.ins().uextend(types::I32, c)
+                })
+            };
+        }
+
+        macro_rules! float {
+            ($op:ident) => {
+                alu!(|e, a, b| {
+                    let a = e.ins().bitcast(types::F32, MemFlags::new(), a);
+                    let b = e.ins().bitcast(types::F32, MemFlags::new(), b);
+                    let v = e.ins().$op(a, b);
+                    e.ins().bitcast(types::I32, MemFlags::new(), v)
+                })
+            };
+        }
+
+        let cost = match inst.ty {
+            Push | Pop | Ret => 2,
+            Call | Callr => 3,
+            _ => 1,
+        };
+
+        match inst.ty {
+            Nop => (),
+
+            Hlt => {
+                let code = self.get(inst.a);
+                self.b.ins().store(
+                    MemFlags::trusted(),
+                    code,
+                    self.ctx,
+                    offset_of!(Context, exit_code) as i32,
+                );
+
+                let pc = self.imm(pc);
+                self.exit(pc, self.clk, EXIT_STOP);
+                self.done = true;
+                return;
+            }
+
+            Nand => alu!(|e, a, b| {
+                let v = e.ins().band(a, b);
+                e.ins().bnot(v)
+            }),
+            Or => alu!(|e, a, b| e.ins().bor(a, b)),
+            And => alu!(|e, a, b| e.ins().band(a, b)),
+            Nor => alu!(|e, a, b| {
+                let v = e.ins().bor(a, b);
+                e.ins().bnot(v)
+            }),
+            Add => alu!(|e, a, b| e.ins().iadd(a, b)),
+            Sub => alu!(|e, a, b| e.ins().isub(a, b)),
+            Xor => alu!(|e, a, b| e.ins().bxor(a, b)),
+            Lsl => alu!(|e, a, b| e.ins().ishl(a, b)),
+            Lsr => alu!(|e, a, b| e.ins().ushr(a, b)),
+            Asr => alu!(|e, a, b| e.ins().sshr(a, b)),
+            Mul | Imul => alu!(|e, a, b| e.ins().imul(a, b)),
+            Mulh => alu!(|e, a, b| e.ins().smulhi(a, b)),
+            Mulhu => alu!(|e, a, b| e.ins().umulhi(a, b)),
+
+            // in 64 bits, the carry / borrow ends up in the high half
+            Addc | Subb => {
+                let a = self.get(inst.a);
+                let b = self.get(inst.b);
+                let c = self.get(inst.c);
+                let c = self.b.ins().band_imm(c, 1);
+
+                let a = self.b.ins().uextend(types::I64, a);
+                let b = self.b.ins().uextend(types::I64, b);
+                let c = self.b.ins().uextend(types::I64, c);
+
+                let (wide, shift) = match inst.ty {
+                    Addc => {
+                        let v = self.b.ins().iadd(a, b);
+                        (self.b.ins().iadd(v, c), 32)
+                    }
+                    _ => {
+                        let v = self.b.ins().isub(a, b);
+                        (self.b.ins().isub(v, c), 63)
+                    }
+                };
+
+                let val = self.b.ins().ireduce(types::I32, wide);
+                let out = self.b.ins().ushr_imm(wide, shift);
+                let out = self.b.ins().ireduce(types::I32, out);
+
+                self.set(inst.dst, val);
+                self.set(inst.c, out);
+            }
+
+            Se => set_if!(IntCC::Equal),
+            Sne => set_if!(IntCC::NotEqual),
+            Sl => set_if!(IntCC::SignedLessThan),
+            Sle => set_if!(IntCC::SignedLessThanOrEqual),
+            Sg => set_if!(IntCC::SignedGreaterThan),
+            Sge => set_if!(IntCC::SignedGreaterThanOrEqual),
+
+            Fadd => float!(fadd),
+            Fsub => float!(fsub),
+            Fmul => float!(fmul),
+            Fdiv => float!(fdiv),
+
+            Rol => alu!(|e, a, b| e.ins().rotl(a, b)),
+            Ror => alu!(|e, a, b| e.ins().rotr(a, b)),
+            Min => alu!(|e, a, b| e.ins().smin(a, b)),
+            Max => alu!(|e, a, b| e.ins().smax(a, b)),
+            Minu => alu!(|e, a, b| e.ins().umin(a, b)),
+            Maxu => alu!(|e, a, b| e.ins().umax(a, b)),
+
+            Clz | Ctz | Popcnt | Bswap => {
+                let a = self.get(inst.a);
+                let val = match inst.ty {
+                    Clz => self.b.ins().clz(a),
+                    Ctz => self.b.ins().ctz(a),
+                    Popcnt => self.b.ins().popcnt(a),
+                    _ => self.b.ins().bswap(a),
+                };
+                self.set(inst.dst, val);
+            }
+
+            Csel => {
+                let cond = self.get(inst.dst);
+                let a = self.get(inst.a);
+                let b = self.imm_or(inst, inst.b);
+                let val = self.b.ins().select(cond, a, b);
+                self.set(inst.dst, val);
+            }
+
+            Mov => {
+                let val = self.imm_or(inst, inst.a);
+                self.set(inst.dst, val);
+            }
+
+            Inc | Dec => {
+                let a = self.get(inst.dst);
+                let n = if inst.ty == Inc { 1 } else { -1 };
+                let val = self.b.ins().iadd_imm(a, n);
+                self.set(inst.dst, val);
+            }
+
+            Ld | Ldw | Ldb | Ldo | Ldwo | Ldbo | Ldr | Ldwr | Ldbr | Ldws | Ldbs | Ldwso
+            | Ldbso => {
+                let addr = match inst.ty {
+                    Ld | Ldw | Ldb | Ldws | Ldbs => self.imm_or(inst, inst.a),
+                    Ldo | Ldwo | Ldbo | Ldwso | Ldbso => {
+                        let base = self.get(inst.a);
+                        self.b.ins().iadd_imm(base, inst.imm as i64)
+                    }
+                    _ => self.imm(pc.wrapping_add(inst.imm)),
+                };
+
+                let size = self.imm(inst.ty.access_size() as BitSize);
+                let mut val = self.call(self.load, &[addr, size], pc);
+                let narrow = match inst.ty {
+                    Ldws | Ldwso => Some(types::I16),
+                    Ldbs | Ldbso => Some(types::I8),
+                    _ => None,
+                };
+
+                if let Some(ty) = narrow {
+                    let v = self.b.ins().ireduce(ty, val);
+                    val = self.b.ins().sextend(types::I32, v);
+                }
+
+                self.set(inst.dst, val);
+            }
+
+            Str | Strw | Strb | Stro | Strwo | Strbo => {
+                let (addr, val) = match inst.ty {
+                    Str | Strw | Strb => (self.get(inst.dst), self.imm_or(inst, inst.a)),
+                    _ => {
+                        let base = self.get(inst.dst);
+                        let addr = self.b.ins().iadd_imm(base, inst.imm as i64);
+                        (addr, self.get(inst.a))
+                    }
+                };
+
+                let size = self.imm(inst.ty.access_size() as BitSize);
+                self.call(self.store, &[addr, val, size], pc);
+            }
+
+            Push => {
+                let val = self.get(inst.a);
+                self.flush_sp();
+                let pc_val = self.imm(pc);
+                self.call(self.push, &[val, pc_val], pc);
+                self.reload_sp();
+            }
+
+            Pop => {
+                self.flush_sp();
+                let val = self.call(self.pop, &[], pc);
+                self.reload_sp();
+                self.set(inst.dst, val);
+            }
+
+            Call | Callr => {
+                let ra = self.get(Reg::Ra);
+                self.flush_sp();
+                let pc_val = self.imm(pc);
+                self.call(self.push, &[ra, pc_val], pc);
+                self.reload_sp();
+
+                let target = match inst.ty {
+                    Call => self.imm_or(inst, inst.a),
+                    _ => self.imm(pc.wrapping_add(inst.imm)),
+                };
+                let next = self.imm(next);
+                self.set(Reg::Ra, next);
+
+                self.exit(target, self.clk + cost, EXIT_OK);
+                self.done = true;
+                return;
+            }
+
+            Ret => {
+                self.flush_sp();
+                let ra = self.call(self.pop, &[], pc);
+                self.reload_sp();
+
+                let target = self.get(Reg::Ra);
+                self.set(Reg::Ra, ra);
+
+                self.exit(target, self.clk + cost, EXIT_OK);
+                self.done = true;
+                return;
+            }
+
+            Jmp | Jmpr => {
+                let target = match inst.ty {
+                    Jmp => self.imm_or(inst, inst.dst),
+                    _ => self.imm(pc.wrapping_add(inst.imm)),
+                };
+
+                self.exit(target, self.clk + cost, EXIT_OK);
+                self.done = true;
+                return;
+            }
+
+            Je | Jne | Jl | Jge | Jle | Jg | Jb | Jae | Jbe | Ja | Jer | Jner | Jlr | Jger
+            | Jler | Jgr | Jbr | Jaer | Jber | Jar => {
+                let cc = match inst.ty {
+                    Je | Jer => IntCC::Equal,
+                    Jne | Jner => IntCC::NotEqual,
+                    Jl | Jlr => IntCC::SignedLessThan,
+                    Jge | Jger => IntCC::SignedGreaterThanOrEqual,
+                    Jle | Jler => IntCC::SignedLessThanOrEqual,
+                    Jg | Jgr => IntCC::SignedGreaterThan,
+                    Jb | Jbr => IntCC::UnsignedLessThan,
+                    Jae | Jaer => IntCC::UnsignedGreaterThanOrEqual,
+                    Jbe | Jber => IntCC::UnsignedLessThanOrEqual,
+                    _ => IntCC::UnsignedGreaterThan,
+                };
+
+                let a = self.get(inst.a);
+                let b = self.get(inst.b);
+                let target = match inst.ty {
+                    Je | Jne | Jl | Jge | Jle | Jg | Jb | Jae | Jbe | Ja => {
+                        self.imm_or(inst, inst.dst)
+                    }
+                    _ => self.imm(pc.wrapping_add(inst.imm)),
+                };
+                let cond = self.b.ins().icmp(cc, a, b);
+
+                let taken = self.b.create_block();
+                let not_taken = self.b.create_block();
+                self.b.ins().brif(cond, taken, &[], not_taken, &[]);
+
+                self.b.switch_to_block(taken);
+                self.exit(target, self.clk + cost, EXIT_OK);
+
+                self.b.switch_to_block(not_taken);
+                let next = self.imm(next);
+                self.exit(next, self.clk + cost, EXIT_OK);
+
+                self.done = true;
+                return;
+            }
+
+            _ => unreachable!("{} isn't compiled", inst.ty),
+        }
+
+        self.clk += cost;
+    }
+
+    fn finish(mut self) {
+        // fell off the end, or stopped before something that isn't compiled
+        if !self.done {
+            let end = self.imm(self.end);
+            self.exit(end, self.clk, EXIT_OK);
+        }
+
+        self.b.seal_all_blocks();
+        self.b.finalize();
+    }
+}
+
+fn reg_offset(i: usize) -> i32 {
+    (offset_of!(Context, gp) + i * size_of::<BitSize>()) as i32
+}
+
+//
+// Callbacks from native code
+//
+
+/// Load `size` bytes at addr, the value is in the low 32 bits
+unsafe extern "C" fn jit_load(ctx: *mut Context, addr: u32, size: u32) -> u64 {
+    // SAFETY: only called from native code with the context it was given
+    let ctx = unsafe { &mut *ctx };
+    let (cpu, mmu) = unsafe { (&mut *ctx.cpu, &*ctx.mmu) };
+
+    match cpu.load(mmu, addr, size as usize) {
+        Ok(val) => val as u64,
+        Err(e) => fault(ctx, e.into()),
+    }
+}
+
+unsafe extern "C" fn jit_store(ctx: *mut Context, addr: u32, val: u32, size: u32) -> u64 {
+    // SAFETY: only called from native code with the context it was given
+    let ctx = unsafe { &mut *ctx };
+    let (cpu, mmu) = unsafe { (&mut *ctx.cpu, &*ctx.mmu) };
+
+    match cpu.store(mmu, addr, val, size as usize) {
+        Ok(()) => 0,
+        Err(e) => fault(ctx, e.into()),
+    }
+}
+
+/// Push val, sp is in the context. pc is for the overflow error
+unsafe extern "C" fn jit_push(ctx: *mut Context, val: u32, pc: u32) -> u64 {
+    // SAFETY: only called from native code with the context it was given
+    let ctx = unsafe { &mut *ctx };
+    let (cpu, mmu) = unsafe { (&mut *ctx.cpu, &*ctx.mmu) };
+
+    let Some(sp) = ctx.gp.sp.checked_sub(4) else {
+        return fault(ctx, CpuError::StackOverflow(pc));
+    };
+
+    match cpu.stack_write(mmu, sp, val) {
+        Ok(()) => {
+            ctx.gp.sp = sp;
+            0
+        }
+
+        Err(e) => fault(ctx, e.into()),
+    }
+}
+
+/// Pop a value, sp is in the context
+unsafe extern "C" fn jit_pop(ctx: *mut Context) -> u64 {
+    // SAFETY: only called from native code with the context it was given
+    let ctx = unsafe { &mut *ctx };
+    let (cpu, mmu) = unsafe { (&mut *ctx.cpu, &*ctx.mmu) };
+
+    let sp = ctx.gp.sp;
+    let data = match cpu.stack_read(mmu, sp) {
+        Ok(data) => data,
+        Err(e) => return fault(ctx, e.into()),
+    };
+
+    let Some(sp) = sp.checked_add(4) else {
+        return fault(ctx, CpuError::StackUnderflow(sp));
+    };
+
+    ctx.gp.sp = sp;
+
+    data as u64
+}
+
+fn fault(ctx: &mut Context, e: CpuError) -> u64 {
+    ctx.err = Some(e);
+    FAULT
+}
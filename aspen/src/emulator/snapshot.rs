@@ -0,0 +1,450 @@
+//! Machine snapshots, and the file format they're saved in.
+//!
+//! A file is [`MAGIC`] and a [`VERSION`], then the harts, the protection runs and the
+//! pages, each section starting with its length. Everything is little endian
+
+use std::{
+    collections::BTreeSet,
+    io::{self, ErrorKind, Read, Write},
+};
+
+use enumflags2::BitFlag as _;
+
+use crate::{
+    BitSize,
+    cpu::{
+        Cpu, CpuError, CtrlRegisters, Registers,
+        icache::ICache,
+        monitor::{Monitor, MonitorArgs},
+        paging::Tlb,
+    },
+    mmu::{MEM_SIZE, PAGE_SIZE, Prot, Protection},
+};
+
+use super::{EmuError, Emulator};
+
+pub const MAGIC: [u8; 8] = *b"ASPNSNAP";
+/// Bumped whenever the layout changes, older files are rejected
+pub const VERSION: u32 = 1;
+
+const PAGE_COUNT: usize = MEM_SIZE / PAGE_SIZE;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum SnapshotError {
+    #[error("Not a snapshot file")]
+    BadMagic,
+    #[error("Unsupported snapshot version {0}, expected {VERSION}")]
+    Version(u32),
+    #[error("Snapshot is truncated")]
+    Truncated,
+    #[error("Invalid snapshot: {0}")]
+    Invalid(&'static str),
+    #[error("Snapshot I/O: {0}")]
+    Io(String),
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(value: io::Error) -> Self {
+        match value.kind() {
+            ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(value.to_string()),
+        }
+    }
+}
+
+/// Architectural state of a hart. Translation and decode caches refill on their own
+#[derive(Debug, Clone, PartialEq)]
+pub struct HartState {
+    pub gp: Registers,
+    pub pc: BitSize,
+    pub clk: u64,
+    pub cr: CtrlRegisters,
+    pub gfx: BitSize,
+    pub in_trap: bool,
+    pub exit_code: BitSize,
+    /// the window is reopened on restore
+    pub monitor: Option<MonitorState>,
+}
+
+/// What a hart's `gfx` window showed
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MonitorState {
+    pub addr: BitSize,
+    pub args: MonitorArgs,
+}
+
+/// Consecutive pages with the same protection
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ProtRun {
+    /// index of the first page
+    pub start: BitSize,
+    pub count: BitSize,
+    pub prot: Protection,
+}
+
+/// Machine state, see [`Emulator::snapshot`].
+/// Devices, breakpoints and ecall handlers aren't included
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// the boot hart, then the secondary harts
+    pub harts: Vec<HartState>,
+    /// covers every page, in order
+    pub prot: Vec<ProtRun>,
+    /// address and contents of the pages that aren't all zeroes
+    pub pages: Vec<(BitSize, Box<[u8]>)>,
+}
+
+impl HartState {
+    pub(super) fn capture(cpu: &Cpu) -> Self {
+        Self {
+            gp: cpu.gp,
+            pc: cpu.pc,
+            clk: cpu.clk,
+            cr: cpu.cr,
+            gfx: cpu.gfx,
+            in_trap: cpu.in_trap,
+            exit_code: cpu.exit_code,
+            monitor: cpu.mon.as_ref().map(|mon| MonitorState {
+                addr: mon.addr(),
+                args: mon.args(),
+            }),
+        }
+    }
+
+    fn apply(&self, cpu: &mut Cpu, emu: &Emulator) -> Result<(), EmuError> {
+        let current = cpu.mon.as_ref().map(|mon| MonitorState {
+            addr: mon.addr(),
+            args: mon.args(),
+        });
+
+        // an identical window can stay open
+        if current != self.monitor {
+            if let Some(mon) = cpu.mon.take() {
+                mon.stop();
+            }
+
+            if let Some(state) = self.monitor {
+                let mon =
+                    Monitor::new(state.addr, emu.mmu.clone(), emu.dev.kbd.clone(), state.args)
+                        .map_err(|e| CpuError::MiniFb(e.to_string()))?;
+
+                cpu.mon = Some(mon);
+            }
+        }
+
+        cpu.gp = self.gp;
+        cpu.pc = self.pc;
+        cpu.clk = self.clk;
+        cpu.cr = self.cr;
+        cpu.gfx = self.gfx;
+        cpu.in_trap = self.in_trap;
+        cpu.exit_code = self.exit_code;
+        cpu.tlb = Tlb::default();
+        cpu.icache = ICache::default();
+
+        Ok(())
+    }
+
+    /// [`HartState::apply`] to the boot hart
+    pub(super) fn apply_boot(&self, emu: &mut Emulator) -> Result<(), EmuError> {
+        let mut cpu = std::mem::replace(&mut emu.cpu, Cpu::new());
+        let res = self.apply(&mut cpu, emu);
+        emu.cpu = cpu;
+        res
+    }
+
+    fn save(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(bytemuck::bytes_of(&self.gp))?;
+        w.write_all(&self.pc.to_le_bytes())?;
+        w.write_all(&self.clk.to_le_bytes())?;
+
+        let cr = &self.cr;
+        for reg in [
+            cr.tvec, cr.tepc, cr.tcause, cr.tval, cr.status, cr.ie, cr.ip, cr.ivec, cr.iepc,
+            cr.icause,
+        ] {
+            w.write_all(&reg.to_le_bytes())?;
+        }
+
+        w.write_all(&cr.timer.to_le_bytes())?;
+        w.write_all(&cr.ptbr.to_le_bytes())?;
+        w.write_all(&cr.hartid.to_le_bytes())?;
+
+        w.write_all(&self.gfx.to_le_bytes())?;
+        w.write_all(&[self.in_trap as u8])?;
+        w.write_all(&self.exit_code.to_le_bytes())?;
+
+        match self.monitor {
+            Some(MonitorState { addr, args }) => {
+                w.write_all(&[1])?;
+                w.write_all(&addr.to_le_bytes())?;
+                w.write_all(&args.width.to_le_bytes())?;
+                w.write_all(&args.height.to_le_bytes())?;
+                w.write_all(&args.fps.to_le_bytes())
+            }
+            None => w.write_all(&[0]),
+        }
+    }
+
+    fn load(r: &mut impl Read) -> Result<Self, SnapshotError> {
+        let mut gp = [0; size_of::<Registers>()];
+        r.read_exact(&mut gp)?;
+        let gp = bytemuck::pod_read_unaligned(&gp);
+
+        let pc = read_u32(r)?;
+        let clk = read_u64(r)?;
+
+        let mut cr = CtrlRegisters::default();
+        for reg in [
+            &mut cr.tvec,
+            &mut cr.tepc,
+            &mut cr.tcause,
+            &mut cr.tval,
+            &mut cr.status,
+            &mut cr.ie,
+            &mut cr.ip,
+            &mut cr.ivec,
+            &mut cr.iepc,
+            &mut cr.icause,
+        ] {
+            *reg = read_u32(r)?;
+        }
+
+        cr.timer = read_u64(r)?;
+        cr.ptbr = read_u32(r)?;
+        cr.hartid = read_u32(r)?;
+
+        let gfx = read_u32(r)?;
+        let in_trap = read_bool(r)?;
+        let exit_code = read_u32(r)?;
+
+        let monitor = match read_bool(r)? {
+            true => Some(MonitorState {
+                addr: read_u32(r)?,
+                args: MonitorArgs {
+                    width: read_u16(r)?,
+                    height: read_u16(r)?,
+                    fps: read_u16(r)?,
+                },
+            }),
+            false => None,
+        };
+
+        Ok(Self {
+            gp,
+            pc,
+            clk,
+            cr,
+            gfx,
+            in_trap,
+            exit_code,
+            monitor,
+        })
+    }
+}
+
+impl Snapshot {
+    pub(super) fn capture(emu: &Emulator) -> Result<Self, EmuError> {
+        let harts = [&emu.cpu]
+            .into_iter()
+            .chain(&emu.harts)
+            .map(HartState::capture)
+            .collect();
+
+        let mut prot: Vec<ProtRun> = Vec::new();
+        for idx in 0..PAGE_COUNT {
+            let page_prot = emu.mmu.prot((idx * PAGE_SIZE) as BitSize);
+
+            match prot.last_mut() {
+                Some(run) if run.prot == page_prot => run.count += 1,
+                _ => prot.push(ProtRun {
+                    start: idx as BitSize,
+                    count: 1,
+                    prot: page_prot,
+                }),
+            }
+        }
+
+        let mut pages = Vec::new();
+        for addr in emu.mmu.dirty_pages() {
+            let mut page = vec![0; PAGE_SIZE].into_boxed_slice();
+            emu.mmu.memcpy(addr, &mut page)?;
+
+            if page.iter().any(|b| *b != 0) {
+                pages.push((addr, page));
+            }
+        }
+
+        Ok(Self { harts, prot, pages })
+    }
+
+    pub(super) fn apply(&self, emu: &mut Emulator) -> Result<(), EmuError> {
+        let (boot, secondary) = self
+            .harts
+            .split_first()
+            .ok_or(EmuError::Snapshot(SnapshotError::Invalid("no boot hart")))?;
+
+        emu.set_harts(self.harts.len());
+
+        boot.apply_boot(emu)?;
+
+        let mut harts = std::mem::take(&mut emu.harts);
+        let res = secondary
+            .iter()
+            .zip(&mut harts)
+            .try_for_each(|(state, cpu)| state.apply(cpu, emu));
+        emu.harts = harts;
+        res?;
+
+        // everything that isn't in the snapshot is zero
+        let kept: BTreeSet<BitSize> = self.pages.iter().map(|(addr, _)| *addr).collect();
+        let stale: Vec<BitSize> = emu
+            .mmu
+            .dirty_pages()
+            .filter(|addr| !kept.contains(addr))
+            .collect();
+
+        let zeroes = [0; PAGE_SIZE];
+        for addr in stale {
+            emu.mmu.memwrite(addr, &zeroes)?;
+        }
+
+        for (addr, page) in &self.pages {
+            emu.mmu.memwrite(*addr, page)?;
+        }
+
+        for run in &self.prot {
+            let start = run.start * PAGE_SIZE as BitSize;
+            let end = start + (run.count * PAGE_SIZE as BitSize - 1);
+            emu.mmu.set_prot(start..=end, run.prot);
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, mut w: impl Write) -> io::Result<()> {
+        w.write_all(&MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+
+        w.write_all(&(self.harts.len() as u32).to_le_bytes())?;
+        for hart in &self.harts {
+            hart.save(&mut w)?;
+        }
+
+        w.write_all(&(self.prot.len() as u32).to_le_bytes())?;
+        for run in &self.prot {
+            w.write_all(&run.start.to_le_bytes())?;
+            w.write_all(&run.count.to_le_bytes())?;
+            w.write_all(&[run.prot.bits()])?;
+        }
+
+        w.write_all(&(self.pages.len() as u32).to_le_bytes())?;
+        for (addr, page) in &self.pages {
+            w.write_all(&addr.to_le_bytes())?;
+            w.write_all(page)?;
+        }
+
+        w.flush()
+    }
+
+    pub fn load(mut r: impl Read) -> Result<Self, SnapshotError> {
+        let mut magic = [0; MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let version = read_u32(&mut r)?;
+        if version != VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+
+        let mut harts = Vec::new();
+        for id in 0..read_u32(&mut r)? {
+            let hart = HartState::load(&mut r)?;
+            if hart.cr.hartid != id {
+                return Err(SnapshotError::Invalid("harts out of order"));
+            }
+
+            harts.push(hart);
+        }
+
+        if harts.is_empty() {
+            return Err(SnapshotError::Invalid("no boot hart"));
+        }
+
+        let mut prot = Vec::new();
+        let mut next = 0;
+        for _ in 0..read_u32(&mut r)? {
+            let start = read_u32(&mut r)?;
+            let count = read_u32(&mut r)?;
+            let bits = read_u8(&mut r)?;
+
+            if start != next || count == 0 || count as usize > PAGE_COUNT - start as usize {
+                return Err(SnapshotError::Invalid("protection runs don't cover memory"));
+            }
+
+            let prot_bits =
+                Prot::from_bits(bits).map_err(|_| SnapshotError::Invalid("protection bits"))?;
+
+            prot.push(ProtRun {
+                start,
+                count,
+                prot: prot_bits,
+            });
+            next = start + count;
+        }
+
+        if next as usize != PAGE_COUNT {
+            return Err(SnapshotError::Invalid("protection runs don't cover memory"));
+        }
+
+        let mut pages: Vec<(BitSize, Box<[u8]>)> = Vec::new();
+        for _ in 0..read_u32(&mut r)? {
+            let addr = read_u32(&mut r)?;
+
+            let sorted = pages.last().is_none_or(|(last, _)| *last < addr);
+            if !(addr as usize).is_multiple_of(PAGE_SIZE) || !sorted {
+                return Err(SnapshotError::Invalid("page addresses"));
+            }
+
+            let mut page = vec![0; PAGE_SIZE].into_boxed_slice();
+            r.read_exact(&mut page)?;
+            pages.push((addr, page));
+        }
+
+        Ok(Self { harts, prot, pages })
+    }
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_bool(r: &mut impl Read) -> Result<bool, SnapshotError> {
+    match read_u8(r)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(SnapshotError::Invalid("bool")),
+    }
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
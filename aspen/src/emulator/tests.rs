@@ -1,11 +1,26 @@
 mod emu;
 
 use enumflags2::BitFlag as _;
+use minifb::{InputCallback as _, Key};
 use serial_test::serial;
+use std::panic::{self, AssertUnwindSafe};
 
 pub use super::*;
+use crate::cpu::{
+    STATUS_IE, STATUS_TIE, STATUS_TPU, STATUS_USER, intc::Irq, paging::PTE_VALID, trap::TrapCause,
+};
+use crate::dev::{
+    Device,
+    console::Sink,
+    keyboard::{SPECIAL, WindowInput},
+    storage::{Storage, StorageError},
+    uart::{self, Host, Pipe, Uart},
+};
+use crate::mmu::Protection;
 use emu::macros::*;
 
+fn handle_none(_: &mut Emulator) {}
+
 #[test]
 #[serial]
 fn test_prot() {
@@ -23,7 +38,7 @@ fn test_prot() {
     // test execution can't execute
     let res = res.map(|_| ());
     let e = Err(EmuError::PageFault(
-        MemError::PageFault(Prot::Execute.into()),
+        MemError::PageFault(Prot::Execute.into(), 0),
         0,
     ));
     assert_eq!(e, res);
@@ -45,6 +60,7 @@ fn test_prot() {
     let res = res.map(|_| ());
     let e = Err(EmuError::Cpu(CpuError::Mem(MemError::PageFault(
         Prot::Write.into(),
+        0x12345678,
     ))));
     assert_eq!(e, res);
 }
@@ -273,3 +289,2363 @@ fn test_strb() {
     assert_eq!(a, 0x12);
     assert_eq!(b, 0x13);
 }
+
+#[test]
+#[serial]
+fn test_pr() {
+    let handle = |emu: &mut Emulator| {
+        emu.dev.console.set_out(Sink::Buffer(Vec::new()));
+        emu.dev.console.set_err(Sink::Buffer(Vec::new()));
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        mov t0, hello
+        mov t1, hello + 6
+        pr t0, t1
+        mov t1, hello + 13
+        epr t0, t1
+        rdclk t2, t3
+        hlt
+
+        hello:
+            #d "hello world!\n"
+    }
+    .unwrap();
+
+    assert_eq!(emu.dev.console.take_out().unwrap(), b"hello ");
+    assert_eq!(emu.dev.console.take_err().unwrap(), b"hello world!\n");
+    // 5 instructions + 2 * 100 for the prints
+    assert_eq!(emu.cpu.gp.t2, 205);
+}
+
+#[test]
+#[serial]
+fn test_pr_prot() {
+    let handle = |emu: &mut Emulator| {
+        emu.dev.console.set_out(Sink::Buffer(Vec::new()));
+        emu.mmu.set_prot(0x10000, Prot::Write);
+    };
+
+    let res = try_run_with! {
+        handle,
+
+        mov t0, 0x10000
+        mov t1, 0x10010
+        pr t0, t1
+    };
+
+    let res = res.map(|_| ());
+    let e = Err(EmuError::Cpu(CpuError::Mem(MemError::PageFault(
+        Prot::Read.into(),
+        0x10000,
+    ))));
+    assert_eq!(e, res);
+}
+
+#[test]
+#[serial]
+fn test_kbrd() {
+    let handle = |emu: &mut Emulator| {
+        emu.dev.kbd.script([b'h' as u32, b'i' as u32]);
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        kbrd t0
+        kbrd t1
+        kbrd t2 ; empty queue
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.t0, b'h' as u32);
+    assert_eq!(emu.cpu.gp.t1, b'i' as u32);
+    assert_eq!(emu.cpu.gp.t2, 0);
+}
+
+#[test]
+#[serial]
+fn test_pld_pstr() {
+    let handle = |emu: &mut Emulator| {
+        emu.dev.storage = Some(Storage::memory(0x2000));
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        mov t0, 0xffe ; crosses a block
+        pstr [t0], 0x12345678
+        pld t1, [t0]
+
+        mov t0, 0x10
+        mov t2, 0xabcd
+        pstr.w [t0], t2
+        pld.w t2, [0x10]
+
+        pstr.b [t0], 0x1ff
+        pld.b t3, [t0]
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.t1, 0x12345678);
+    assert_eq!(emu.cpu.gp.t2, 0xabcd);
+    assert_eq!(emu.cpu.gp.t3, 0xff);
+
+    let mut buf = [0; 2];
+    let storage = emu.dev.storage.as_ref().unwrap();
+    storage.read(0x10, &mut buf).unwrap();
+    assert_eq!(buf, [0xff, 0xab]);
+}
+
+#[test]
+#[serial]
+fn test_storage_errors() {
+    let res = try_run_with! {
+        handle_none,
+
+        pld t0, [0x0]
+    };
+
+    let res = res.map(|_| ());
+    let e = Err(EmuError::Cpu(CpuError::Storage(StorageError::NotAttached)));
+    assert_eq!(e, res);
+
+    // --
+
+    let handle = |emu: &mut Emulator| {
+        emu.dev.storage = Some(Storage::memory(0x10));
+    };
+
+    let res = try_run_with! {
+        handle,
+
+        pld t0, [0xe]
+    };
+
+    let res = res.map(|_| ());
+    let e = Err(EmuError::Cpu(CpuError::Storage(StorageError::OutOfBounds(
+        0xe,
+    ))));
+    assert_eq!(e, res);
+}
+
+#[test]
+fn test_storage_image() {
+    let path = std::env::temp_dir().join(format!("aspen-storage-{}.img", std::process::id()));
+
+    let storage = Storage::create(&path, 0x3000).unwrap();
+    storage.write(0x1ffe, &[1, 2, 3, 4]).unwrap();
+    // nothing hits the image before a flush
+    assert_eq!(std::fs::read(&path).unwrap()[0x1ffe..0x2002], [0; 4]);
+    storage.flush().unwrap();
+    drop(storage);
+
+    let storage = Storage::open(&path, true).unwrap();
+    let mut buf = [0; 4];
+    storage.read(0x1ffe, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(storage.len(), 0x3000);
+    assert_eq!(storage.write(0, &[1]), Err(StorageError::ReadOnly(0)));
+    drop(storage);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[serial]
+fn test_trap() {
+    let handle = |emu: &mut Emulator| {
+        emu.mmu.set_prot(0x10000, Prot::Read);
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        wrcr tvec, handler
+        mov t0, 0x10000
+        str [t0], 0x1234 ; faults
+        mov t1, 0x1      ; resumed here
+        hlt
+
+        handler:
+            rdcr s0, tepc
+            rdcr s1, tcause
+            rdcr s2, tval
+            ; skip the faulting instruction
+            add s0, s0, 8
+            wrcr tepc, s0
+            trapret
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, 0x18);
+    assert_eq!(emu.cpu.gp.s1, TrapCause::StoreFault as u32);
+    assert_eq!(emu.cpu.gp.s2, 0x10000);
+    assert_eq!(emu.cpu.gp.t1, 0x1);
+    assert!(!emu.cpu.in_trap);
+}
+
+#[test]
+#[serial]
+fn test_trap_illegal_instruction() {
+    let emu = try_run_with! {
+        handle_none,
+
+        wrcr tvec, handler
+        #d 0x00ff0000 ; unknown opcode
+        hlt
+
+        handler:
+            rdcr s1, tcause
+            rdcr s2, tval
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s1, TrapCause::IllegalInstruction as u32);
+    assert_eq!(emu.cpu.gp.s2, 0x8);
+}
+
+#[test]
+#[serial]
+fn test_double_fault() {
+    let res = try_run_with! {
+        handle_none,
+
+        wrcr tvec, handler
+        #d 0x00ff0000 ; unknown opcode
+
+        handler:
+            #d 0x00fe0000 ; unknown opcode
+    };
+
+    let res = res.map(|_| ());
+    let e = Err(EmuError::Inst(InstError::UnknownInstruction(0, 0xfe)));
+    assert_eq!(e, res);
+}
+
+#[test]
+#[serial]
+fn test_timer_interrupt() {
+    let emu = try_run_with! {
+        handle_none,
+
+        wrcr ivec, handler
+        wrcr ie, 0b01
+        wrcr timer, 20
+        ei
+
+        wait:
+            inc s2
+            jez s0, wait
+            hlt
+
+        handler:
+            rdcr s1, icause
+            rdcr s3, ip
+            mov s0, 1
+            iret
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, 1);
+    assert_eq!(emu.cpu.gp.s1, Irq::Timer as u32);
+    // acknowledged on entry
+    assert_eq!(emu.cpu.gp.s3, 0);
+    assert!(emu.cpu.gp.s2 > 1);
+    assert_eq!(emu.cpu.cr.timer, 0);
+}
+
+#[test]
+#[serial]
+fn test_interrupt_in_trap() {
+    let handle = |emu: &mut Emulator| emu.mmu.set_prot(0x1000000, Prot::empty());
+
+    // the timer comes due inside the trap handler, and its handler faults too
+    let emu = try_run_with! {
+        handle,
+
+        wrcr tvec, trap
+        wrcr ivec, irq
+        wrcr ie, 0b01
+        ei
+        wrcr timer, 3
+        ld t0, [0x1000000]
+        hlt
+
+        trap:
+            jnez s2, again
+            rdcr s0, status
+
+        again:
+            inc s1
+            inc s1
+            inc s1
+            inc s1
+            rdcr t3, tepc
+            add t3, t3, 8
+            wrcr tepc, t3
+            inc s2
+            trapret
+
+        irq:
+            ld t1, [0x1000000]
+            inc s3
+            iret
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0 & (STATUS_IE | STATUS_TIE), STATUS_TIE);
+    assert_eq!(emu.cpu.gp.s2, 2);
+    assert_eq!(emu.cpu.gp.s3, 1);
+    assert_eq!(emu.cpu.cr.status & STATUS_IE, STATUS_IE);
+}
+
+#[test]
+#[serial]
+fn test_keyboard_interrupt() {
+    let handle = |emu: &mut Emulator| {
+        emu.dev.kbd.script([b'a' as u32, b'b' as u32]);
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        wrcr ivec, handler
+        wrcr ie, 0b10
+
+        ; nothing delivered while disabled
+        mov t0, 0x1
+        mov t0, 0x2
+        mov s1, t0
+
+        mov t2, 2
+        ei
+        wait:
+            jne s0, t2, wait
+        hlt
+
+        handler:
+            kbrd t1
+            add s2, s2, t1
+            inc s0
+            iret
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s1, 0x2);
+    assert_eq!(emu.cpu.gp.s2, (b'a' + b'b') as u32);
+    assert!(emu.dev.kbd.is_empty());
+}
+
+#[test]
+#[serial]
+fn test_window_keys() {
+    let handle = |emu: &mut Emulator| {
+        let mut input = WindowInput(emu.dev.kbd.clone());
+
+        // one enter press, as the window reports it
+        input.set_key_state(Key::Enter, true);
+        input.add_char(b'\r' as u32);
+        input.set_key_state(Key::Enter, false);
+
+        input.set_key_state(Key::Left, true);
+        input.set_key_state(Key::Left, false);
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        kbrd s0
+        kbrd s1
+        kbrd s2
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, b'\n' as u32);
+    assert_eq!(emu.cpu.gp.s1, SPECIAL | Key::Left as u32);
+    assert_eq!(emu.cpu.gp.s2, 0);
+}
+
+#[test]
+#[serial]
+fn test_user_mode() {
+    let handle = |emu: &mut Emulator| {
+        emu.mmu
+            .set_prot(0..0x1000, Prot::Read | Prot::Execute | Prot::User);
+        emu.mmu
+            .set_prot(0x10000, Prot::Read | Prot::Write | Prot::User);
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        wrcr tvec, handler
+        ; drop to user mode through trapret
+        wrcr tepc, user
+        wrcr status, 0b100
+        trapret
+
+        user:
+            mov s5, 0xff
+            rdcr s5, hartid  ; readable by anyone
+            mov t0, 0x10000
+            str [t0], 0x1234 ; user page
+            mov t0, 0x20000
+            str [t0], 0x1234 ; supervisor page
+            wrcr tvec, zr    ; privileged
+
+        handler:
+            inc s0
+            rdcr s1, tcause
+            rdcr s2, tval
+            rdcr s3, status
+            rdcr s4, tepc
+
+            mov t1, 2
+            je s0, t1, done
+
+            add s4, s4, 8
+            wrcr tepc, s4
+            trapret
+
+        done:
+            hlt
+    }
+    .unwrap();
+
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x10000).unwrap(), 0x1234);
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x20000).unwrap(), 0);
+    assert_eq!(emu.cpu.gp.s0, 2);
+    assert_eq!(emu.cpu.gp.s1, TrapCause::PrivilegeFault as u32);
+    assert_eq!(emu.cpu.gp.s3 & (STATUS_USER | STATUS_TPU), STATUS_TPU);
+    assert_eq!(emu.cpu.gp.s5, 0);
+    assert!(!emu.cpu.is_user());
+}
+
+#[test]
+#[serial]
+fn test_user_store_fault() {
+    let handle = |emu: &mut Emulator| {
+        emu.mmu
+            .set_prot(0..0x1000, Prot::Read | Prot::Execute | Prot::User);
+    };
+
+    let res = try_run_with! {
+        handle,
+
+        wrcr status, 0b10
+        mov t0, 0x20000
+        str [t0], 0x1234
+    };
+
+    let res = res.map(|_| ());
+    let e = Err(EmuError::Cpu(CpuError::Mem(MemError::PageFault(
+        Prot::Write | Prot::User,
+        0x20000,
+    ))));
+    assert_eq!(e, res);
+}
+
+#[test]
+#[serial]
+fn test_mprot() {
+    let emu = try_run_with! {
+        handle_none,
+
+        wrcr tvec, handler
+
+        ; read only data page
+        mov t0, 0x10000
+        mov t1, 0x10001
+        mprot t0, t1, 0b0001
+        str [t0], 0x1234 ; faults
+
+        ; make it writable + executable, for a tiny jit
+        mov t2, 0b0111
+        mprot t0, t1, t2
+        str [t0], 0x00000100 ; hlt
+        jmp t0
+
+        handler:
+            rdcr s1, tcause
+            rdcr s2, tval
+            rdcr s0, tepc
+            add s0, s0, 8
+            wrcr tepc, s0
+            trapret
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s1, TrapCause::StoreFault as u32);
+    assert_eq!(emu.cpu.gp.s2, 0x10000);
+    assert_eq!(emu.cpu.pc, 0x10000);
+    assert_eq!(
+        emu.mmu.prot(0x10fff),
+        Prot::Read | Prot::Write | Prot::Execute
+    );
+    assert_eq!(emu.mmu.prot(0x11000), Prot::Read | Prot::Write);
+}
+
+#[test]
+#[serial]
+fn test_paging() {
+    let handle = |emu: &mut Emulator| {
+        let pte = |page: u32, prot: Protection| page | PTE_VALID | prot.bits() as u32;
+        let mmu = &emu.mmu;
+
+        // root table @ 0x100000, one leaf table @ 0x101000 covering the low 4 MiB
+        mmu.write_unchecked(0x100000u32, pte(0x101000, Prot::empty()))
+            .unwrap();
+        // root entry for 0x400000 - 0x7fffff, leaf table @ 0x102000
+        mmu.write_unchecked(0x100004u32, pte(0x102000, Prot::empty()))
+            .unwrap();
+
+        // code, identity mapped
+        mmu.write_unchecked(0x101000u32, pte(0, Prot::Read | Prot::Execute))
+            .unwrap();
+        // 0x402000 -> 0x200000
+        mmu.write_unchecked(0x102008u32, pte(0x200000, Prot::Read | Prot::Write))
+            .unwrap();
+        // the leaf table itself @ 0x401000, so the guest can remap 0x402000
+        mmu.write_unchecked(0x102004u32, pte(0x102000, Prot::Read | Prot::Write))
+            .unwrap();
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        wrcr tvec, handler
+        mov sp, 0x403000 ; grows down into the 0x402000 page
+        wrcr ptbr, 0x100000
+
+        mov t0, 0x402000
+        str [t0], 0x11
+        push t0
+
+        ; point 0x402000 at 0x300000, the stale entry is still used until a flush
+        mov t1, 0x401008
+        str [t1], 0x300013
+        str [t0], 0x22
+        tlbflush
+        str [t0], 0x33
+        ld s3, [t0]
+
+        ; nothing mapped here
+        mov t2, 0x800010
+        ld s4, [t2]
+        hlt
+
+        handler:
+            rdcr s1, tcause
+            rdcr s2, tval
+            rdcr s0, tepc
+            add s0, s0, 4
+            wrcr tepc, s0
+            trapret
+    }
+    .unwrap();
+
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x200000).unwrap(), 0x22);
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x200ffc).unwrap(), 0x402000);
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x300000).unwrap(), 0x33);
+    assert_eq!(emu.cpu.gp.s3, 0x33);
+    assert_eq!(emu.cpu.gp.s4, 0);
+
+    // faults report the virtual address
+    assert_eq!(emu.cpu.gp.s1, TrapCause::TranslationFault as u32);
+    assert_eq!(emu.cpu.gp.s2, 0x800010);
+}
+
+#[test]
+#[serial]
+fn test_paging_prot() {
+    let handle = |emu: &mut Emulator| {
+        let pte = |page: u32, prot: Protection| page | PTE_VALID | prot.bits() as u32;
+        let mmu = &emu.mmu;
+
+        mmu.write_unchecked(0x100000u32, pte(0x101000, Prot::empty()))
+            .unwrap();
+        mmu.write_unchecked(0x101000u32, pte(0, Prot::Read | Prot::Execute))
+            .unwrap();
+        mmu.write_unchecked(0x101004u32, pte(0x200000, Prot::Read | Prot::Write))
+            .unwrap();
+        // read only, even though the physical page is writable
+        mmu.write_unchecked(0x101008u32, pte(0x201000, Prot::Read.into()))
+            .unwrap();
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        wrcr tvec, handler
+        wrcr ptbr, 0x100000
+        mov t0, 0x1ffe
+        str [t0], 0xffffffff
+        mov t1, 0x1ffc
+        ld s3, [t1]
+        hlt
+
+        handler:
+            rdcr s1, tcause
+            rdcr s2, tval
+            rdcr s0, tepc
+            add s0, s0, 8
+            wrcr tepc, s0
+            trapret
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s1, TrapCause::StoreFault as u32);
+    assert_eq!(emu.cpu.gp.s2, 0x2000);
+    // the writable half wasn't written either
+    assert_eq!(emu.cpu.gp.s3, 0);
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x200ffc).unwrap(), 0);
+}
+
+#[test]
+#[serial]
+fn test_float() {
+    let emu = run! {
+        itof t0, 3
+        itof t1, -2
+        fadd s0, t0, t1 ; 1.0
+        fsub s1, t1, t0 ; -5.0
+        fmul s2, t0, 0x3f000000 ; 3 * 0.5
+        fdiv s3, t0, t1 ; -1.5
+        itof t2, 16
+        fsqrt s4, t2
+
+        fsl s5, t1, t0
+        fsle s6, t0, t0
+        fse s7, t0, t1
+
+        ; 0.0 / 0.0
+        fdiv t3, zr, zr
+        fse s8, t3, t3
+
+        ftoi a0, s3
+        mov t4, 0xbf800000 ; -1.0
+        ftou a1, t4
+        utof a2, 0xffffffff
+        ftoi a3, t3
+    };
+
+    let f = |bits: u32| f32::from_bits(bits);
+
+    assert_eq!(f(emu.cpu.gp.s0), 1.0);
+    assert_eq!(f(emu.cpu.gp.s1), -5.0);
+    assert_eq!(f(emu.cpu.gp.s2), 1.5);
+    assert_eq!(f(emu.cpu.gp.s3), -1.5);
+    assert_eq!(f(emu.cpu.gp.s4), 4.0);
+
+    assert_eq!(emu.cpu.gp.s5, 1);
+    assert_eq!(emu.cpu.gp.s6, 1);
+    assert_eq!(emu.cpu.gp.s7, 0);
+    // NaN != NaN
+    assert_eq!(emu.cpu.gp.s8, 0);
+
+    assert_eq!(emu.cpu.gp.a0 as i32, -1);
+    assert_eq!(emu.cpu.gp.a1, 0);
+    assert_eq!(f(emu.cpu.gp.a2), 4294967296.0);
+    assert_eq!(emu.cpu.gp.a3, 0);
+}
+
+#[test]
+#[serial]
+fn test_harts() {
+    let handle = |emu: &mut Emulator| {
+        emu.set_harts(4);
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        mov t4, worker
+        mov t3, 1
+        hstart t3, t4, 100
+        mov t3, 2
+        hstart t3, t4, 200
+        mov t3, 3
+        hstart t3, t4, 300
+
+        rdcr s0, hartid
+        hlt
+
+        ; write hartid * 10 + a0 to 0x10000 + hartid * 4
+        worker:
+            rdcr t0, hartid
+            lsl t1, t0, 2
+            add t1, t1, 0x10000
+            mul t2, t0, 10
+            add t2, t2, a0
+            str [t1], t2
+            hlt
+    }
+    .unwrap();
+
+    // harts are all joined before returning
+    for id in 1..4 {
+        let val = emu.mmu.read_unchecked::<u32>(0x10000 + id * 4).unwrap();
+        assert_eq!(val, id * 10 + id * 100);
+        assert_eq!(emu.harts[id as usize - 1].cr.hartid, id);
+    }
+
+    assert_eq!(emu.cpu.gp.s0, 0);
+}
+
+#[test]
+#[serial]
+fn test_hart_errors() {
+    let handle = |emu: &mut Emulator| {
+        emu.set_harts(2);
+    };
+
+    // no such hart
+    let res = try_run_with! {
+        handle,
+
+        mov t0, 5
+        hstart t0, zr, 0
+    };
+
+    let res = res.map(|_| ());
+    let e = Err(EmuError::Hart(
+        0,
+        Box::new(EmuError::Cpu(CpuError::HartStart(5))),
+    ));
+    assert_eq!(e, res);
+
+    // faults are reported with the hart they happened on
+    let res = try_run_with! {
+        handle,
+
+        mov t0, 1
+        mov t1, 0x20000 ; not executable
+        hstart t0, t1, 0
+    };
+
+    let res = res.map(|_| ());
+    let e = Err(EmuError::Hart(
+        1,
+        Box::new(EmuError::PageFault(
+            MemError::PageFault(Prot::Execute.into(), 0x20000),
+            0x20000,
+        )),
+    ));
+    assert_eq!(e, res);
+}
+
+#[test]
+#[serial]
+fn test_step_hstart() {
+    let handle = |emu: &mut Emulator| {
+        emu.set_harts(2);
+    };
+
+    let mut emu = emu::_try_run_with(
+        handle,
+        r"
+        mov t4, worker
+        mov t3, 1
+        hstart t3, t4, 0
+        hlt
+
+        worker:
+            hlt
+    ",
+    )
+    .unwrap();
+
+    // the started hart would never run
+    emu.cpu.zeroize();
+    emu.step().unwrap();
+    emu.step().unwrap();
+    let res = emu.step().map(|_| ());
+    assert_eq!(res, Err(EmuError::Cpu(CpuError::HartStart(1))));
+
+    emu.cpu.zeroize();
+    emu.record().unwrap();
+    let res = emu.run().map(|_| ());
+    assert_eq!(res, Err(EmuError::Cpu(CpuError::HartStart(1))));
+}
+
+#[test]
+#[serial]
+fn test_atomics() {
+    let emu = try_run_with! {
+        handle_none,
+
+        wrcr tvec, handler
+
+        mov t0, 0x10000
+        str [t0], 5
+
+        ; fails, [t0] is 5
+        mov s0, 4
+        cas s0, [t0], t1
+        ; succeeds
+        mov s1, 5
+        mov t1, 7
+        cas s1, [t0], t1
+
+        amoadd s2, [t0], 3
+        amoswap s3, [t0], 100
+        ld s4, [t0]
+        fence
+
+        mov t2, 0x10002
+        amoadd s5, [t2], 1
+
+        hlt
+
+        handler:
+            rdcr s6, tcause
+            rdcr s7, tval
+            rdcr s8, tepc
+            add s8, s8, 8
+            wrcr tepc, s8
+            trapret
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, 5);
+    assert_eq!(emu.cpu.gp.s1, 5);
+    assert_eq!(emu.cpu.gp.s2, 7);
+    assert_eq!(emu.cpu.gp.s3, 10);
+    assert_eq!(emu.cpu.gp.s4, 100);
+
+    assert_eq!(emu.cpu.gp.s6, TrapCause::MisalignedAccess as u32);
+    assert_eq!(emu.cpu.gp.s7, 0x10002);
+    assert_eq!(emu.cpu.gp.s5, 0);
+    drop(emu);
+
+    // --
+
+    let handle = |emu: &mut Emulator| {
+        emu.mmu.set_prot(0x10000..0x10004, Prot::Read);
+    };
+
+    let res = try_run_with! {
+        handle,
+
+        mov t0, 0x10000
+        amoswap t1, [t0], 1
+    };
+
+    let res = res.map(|_| ());
+    let e = Err(EmuError::Cpu(CpuError::Mem(MemError::PageFault(
+        Prot::Write.into(),
+        0x10000,
+    ))));
+    assert_eq!(e, res);
+}
+
+#[test]
+#[serial]
+fn test_atomics_harts() {
+    let handle = |emu: &mut Emulator| {
+        emu.set_harts(4);
+    };
+
+    let emu = try_run_with! {
+        handle,
+
+        mov t4, worker
+        mov t3, 1
+        hstart t3, t4, 0
+        mov t3, 2
+        hstart t3, t4, 0
+        mov t3, 3
+        hstart t3, t4, 0
+        jmp worker
+
+        ; 0x10000 - amoadd counter
+        ; 0x10004 - spinlock
+        ; 0x10008 - counter guarded by the lock
+        worker:
+            mov s0, 0x10000
+            mov s1, 0x10004
+            mov s2, 0x10008
+            mov s3, 1000
+
+        loop:
+            amoadd zr, [s0], 1
+
+        lock:
+            mov t0, 0
+            mov t1, 1
+            cas t0, [s1], t1
+            jne t0, zr, lock
+
+            ld t2, [s2]
+            inc t2
+            str [s2], t2
+            amoswap zr, [s1], 0
+
+            dec s3
+            jne s3, zr, loop
+
+            hlt
+    }
+    .unwrap();
+
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x10000).unwrap(), 4000);
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x10008).unwrap(), 4000);
+}
+
+#[test]
+#[serial]
+fn test_atomics_mixed_sizes() {
+    let handle = |emu: &mut Emulator| {
+        emu.set_harts(4);
+    };
+
+    // byte stores to a word share it with amoadds from other harts
+    let emu = try_run_with! {
+        handle,
+
+        mov t4, adder
+        mov t3, 1
+        hstart t3, t4, 0
+        mov t3, 2
+        hstart t3, t4, 0
+        mov t3, 3
+        hstart t3, t4, 0
+
+        mov s0, 0x10003
+        mov s1, 2000
+
+        store:
+            str.b [s0], s1
+            dec s1
+            jne s1, zr, store
+
+        str.b [s0], 0xab
+        mov s2, 0x10006
+        str [s2], 0x11223344
+        mov s3, 0x1000c
+        mov t1, 3
+
+        wait:
+            ld t0, [s3]
+            jne t0, t1, wait
+
+        hlt
+
+        adder:
+            mov s0, 0x10000
+            mov s1, 0x1000c
+            mov s3, 50
+
+        loop:
+            amoadd zr, [s0], 1
+            dec s3
+            jne s3, zr, loop
+
+            amoadd zr, [s1], 1
+            hlt
+    }
+    .unwrap();
+
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x10000).unwrap(), 0xab00_0096);
+    // spans two words
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x10004).unwrap(), 0x3344_0000);
+    assert_eq!(emu.mmu.read_unchecked::<u16>(0x10008).unwrap(), 0x1122);
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x10006).unwrap(), 0x11223344);
+}
+
+#[test]
+#[serial]
+fn test_self_modifying_code() {
+    let emu = run! {
+        ; let us write to the code page
+        mov t0, 0
+        mov t1, 0x1000
+        mprot t0, t1, 0b0111
+
+        again:
+        patch:
+            add s0, s0, 1
+            jne s1, zr, done
+
+            ; patch the imm of the (already decoded) add and run it again
+            mov s1, 1
+            mov t2, patch
+            add t2, t2, 4
+            str [t2], 100
+            jmp again
+
+        done:
+    };
+
+    assert_eq!(emu.cpu.gp.s0, 101);
+}
+
+/// Run asm on every engine, they should end up in the same state as the interpreter
+fn assert_engines_agree(handle: fn(&mut Emulator), asm: &str) {
+    let state = |engine: Engine| {
+        let emu = emu::_try_run_with(
+            |emu| {
+                emu.engine = engine;
+                handle(emu);
+            },
+            asm,
+        )
+        .unwrap();
+
+        let gp = bytemuck::bytes_of(&emu.cpu.gp).to_vec();
+        (gp, emu.cpu.pc, emu.cpu.clk)
+    };
+
+    let expected = state(Engine::Interpreter);
+    let engines = [
+        Engine::Threaded,
+        #[cfg(feature = "jit")]
+        Engine::Jit,
+    ];
+
+    for engine in engines {
+        assert_eq!(expected, state(engine), "{engine:?}");
+    }
+}
+
+#[test]
+#[serial]
+fn test_threaded_engine() {
+    // loops, calls and the stack
+    assert_engines_agree(
+        handle_none,
+        r"
+        mov sp, 0x8000
+        mov s0, 0
+        mov s1, 0
+
+        loop:
+            push s1
+            call square
+            pop s1
+            add s0, s0, a0
+            inc s1
+            mov t0, 50
+            jl s1, t0, loop
+
+        ; through a register too
+        mov t1, square
+        call t1
+        rdclk s2, s3
+        rdpc s4
+        hlt
+
+        square:
+            mul a0, s1, s1
+            ret
+        ",
+    );
+
+    // memory, signed math and floats
+    assert_engines_agree(
+        handle_none,
+        r"
+        mov t0, 0x10000
+        mov t1, -7
+        mov t5, 0x10004
+        str [t0], t1
+        str.w [t5], 0xbeef
+        ld s0, [t0]
+        ld.w s1, [t5]
+        ld.b s2, [t0]
+        idiv s3, s0, 2
+        irem s4, s0, 2
+        asr s5, s0, 1
+        sl t2, s0, zr
+        itof t3, s0
+        fmul t3, t3, t3
+        ftoi t4, t3
+        ",
+    );
+
+    // faults in the middle of a block, handled by the guest
+    assert_engines_agree(
+        |emu| emu.mmu.set_prot(0x1000000, Prot::empty()),
+        r"
+        wrcr tvec, handler
+        mov s0, 1
+        str [zr], 1
+        mov s1, 2
+        ld s2, [0x1000000]
+        mov s3, 3
+        hlt
+
+        handler:
+            inc s4
+            rdcr t1, tepc
+            add t1, t1, 8
+            wrcr tepc, t1
+            trapret
+        ",
+    );
+}
+
+#[test]
+#[serial]
+fn test_hot_loops() {
+    // faults and the stack, once the loop is hot
+    assert_engines_agree(
+        handle_none,
+        r"
+        wrcr tvec, handler
+        mov sp, 0x8000
+        mov t0, 0x10000
+
+        loop:
+            push s0
+            str [t0], s0
+            ld s1, [t0]
+            add s2, s2, s1
+            pop s3
+            mov t1, 60
+            jne s0, t1, skip
+            ; stores fault from here on
+            mov t0, 0
+
+        skip:
+            inc s0
+            mov t2, 100
+            jl s0, t2, loop
+            hlt
+
+        handler:
+            inc s4
+            rdcr t3, tepc
+            add t3, t3, 4
+            wrcr tepc, t3
+            trapret
+        ",
+    );
+
+    // patching hot code
+    assert_engines_agree(
+        handle_none,
+        r"
+        mov t0, 0
+        mov t1, 0x1000
+        mprot t0, t1, 0b0111
+
+        loop:
+        patch:
+            add s0, s0, 1
+            inc s1
+            mov t2, 50
+            jne s1, t2, next
+            mov t3, patch
+            add t3, t3, 4
+            str [t3], 100
+
+        next:
+            mov t2, 100
+            jl s1, t2, loop
+        ",
+    );
+
+    // pc relative calls
+    assert_engines_agree(
+        handle_none,
+        r"
+        mov sp, 0x8000
+        mov t0, 100
+
+        loop:
+            call pc + func - $
+            jl s0, t0, loop
+            rdclk s1, s2
+            hlt
+
+        func:
+            inc s0
+            ret
+        ",
+    );
+}
+
+#[test]
+#[serial]
+fn test_jit_recompiles() {
+    // enough rewrites of a hot block for the jit to free its code and start over
+    assert_engines_agree(
+        handle_none,
+        r"
+        mov t0, 0
+        mov t1, 0x1000
+        mprot t0, t1, 0b0111
+
+        mov t3, patch
+        add t3, t3, 4
+        mov t4, 8500
+        mov t5, 40
+
+        outer:
+            mov s1, 0
+
+        loop:
+        patch:
+            add s0, s0, 1
+            inc s1
+            jl s1, t5, loop
+
+            inc s2
+            str [t3], s2
+            jl s2, t4, outer
+        ",
+    );
+}
+
+#[test]
+#[serial]
+fn test_threaded_smc_and_interrupts() {
+    let handle = |emu: &mut Emulator| emu.engine = Engine::Threaded;
+
+    let emu = try_run_with! {
+        handle,
+
+        ; let us write to the code page
+        mov t0, 0
+        mov t1, 0x1000
+        mprot t0, t1, 0b0111
+
+        again:
+        patch:
+            add s0, s0, 1
+            jne s1, zr, done
+
+            mov s1, 1
+            mov t2, patch
+            add t2, t2, 4
+            str [t2], 100
+            jmp again
+
+        done:
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, 101);
+    drop(emu);
+
+    let emu = try_run_with! {
+        handle,
+
+        wrcr ivec, handler
+        wrcr ie, 0b01
+        wrcr timer, 20
+        ei
+
+        wait:
+            inc s2
+            jez s0, wait
+            hlt
+
+        handler:
+            rdcr s1, icause
+            mov s0, 1
+            iret
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, 1);
+    assert_eq!(emu.cpu.gp.s1, Irq::Timer as u32);
+    assert!(emu.cpu.gp.s2 > 1);
+}
+
+#[test]
+#[serial]
+fn test_addressing() {
+    const ASM: &str = r"
+        mov s0, 0x10000
+        mov t0, 0x11223344
+        str [s0 + 8], t0
+        str.w [s0 + 12], t0
+        str.b [s0 - 1], t0
+        ld s1, [s0 + 8]
+        ld.w s2, [s0 + 12]
+        ld.b s3, [s0 - 1]
+        ld s4, [pc + data - $]
+
+        loop:
+            inc s5
+            mov t1, 100
+            jne s5, t1, pc + loop - $
+
+        mov sp, 0x8000
+        call pc + func - $
+        jmp pc + end - $
+        mov s7, 1
+
+        func:
+            mov s6, 42
+            ret
+
+        data:
+            #d8 0xef, 0xbe, 0xad, 0xde
+
+        end:
+    ";
+
+    let emu = emu::_try_run(ASM).unwrap();
+
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x10008).unwrap(), 0x11223344);
+    assert_eq!(emu.cpu.gp.s1, 0x11223344);
+    assert_eq!(emu.cpu.gp.s2, 0x3344);
+    assert_eq!(emu.cpu.gp.s3, 0x44);
+    assert_eq!(emu.cpu.gp.s4, 0xdeadbeef);
+    assert_eq!(emu.cpu.gp.s5, 100);
+    assert_eq!(emu.cpu.gp.s6, 42);
+    assert_eq!(emu.cpu.gp.s7, 0);
+    drop(emu);
+
+    assert_engines_agree(handle_none, ASM);
+}
+
+#[test]
+#[serial]
+fn test_bits() {
+    const ASM: &str = r"
+        mov t0, 0x10000
+        mov t1, 0x8001ff80
+        str [t0], t1
+        mov t2, 0x80000001
+        mov t3, 0xffffffff
+
+        loop:
+            ld.ws s0, [0x10000]
+            ld.bs s1, [t0 + 1]
+            ld.ws s2, [t0 + 2]
+            ld.b s3, [t0]
+            clz s4, t0
+            ctz s5, t0
+            popcnt s6, t1
+            clz s7, zr
+            rol s8, t2, 4
+            ror s9, t2, 1
+            bswap s10, t1
+            min s11, t3, 1
+            max a0, t3, 1
+            minu a1, t3, 1
+            maxu a2, t3, t2
+            mov a3, 0
+            csel a3, t2, 7
+            mov a4, 1
+            csel a4, t2, 7
+            inc t5
+            mov t6, 40
+            jne t5, t6, pc + loop - $
+    ";
+
+    let emu = emu::_try_run(ASM).unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, 0xffffff80);
+    assert_eq!(emu.cpu.gp.s1, 0xffffffff);
+    assert_eq!(emu.cpu.gp.s2, 0xffff8001);
+    assert_eq!(emu.cpu.gp.s3, 0x80);
+    assert_eq!(emu.cpu.gp.s4, 15);
+    assert_eq!(emu.cpu.gp.s5, 16);
+    assert_eq!(emu.cpu.gp.s6, 11);
+    assert_eq!(emu.cpu.gp.s7, 32);
+    assert_eq!(emu.cpu.gp.s8, 0x18);
+    assert_eq!(emu.cpu.gp.s9, 0xc0000000);
+    assert_eq!(emu.cpu.gp.s10, 0x80ff0180);
+    assert_eq!(emu.cpu.gp.s11, 0xffffffff);
+    assert_eq!(emu.cpu.gp.a0, 1);
+    assert_eq!(emu.cpu.gp.a1, 1);
+    assert_eq!(emu.cpu.gp.a2, 0xffffffff);
+    assert_eq!(emu.cpu.gp.a3, 7);
+    assert_eq!(emu.cpu.gp.a4, 0x80000001);
+    drop(emu);
+
+    assert_engines_agree(handle_none, ASM);
+}
+
+#[test]
+#[serial]
+fn test_wide_arith() {
+    const ASM: &str = r"
+        mov t0, 0xffffffff
+        mov t1, 1
+        mov t2, 1
+        mov t5, 0xfffffffe
+        mov t6, 0x80000000
+
+        loop:
+            mov t4, 0
+            addc s0, t0, t2, t4
+            addc s1, t1, zr, t4
+            mov s4, t4
+
+            subb s2, s0, t2, t4
+            subb s3, s1, zr, t4
+            mov s5, t4
+
+            mov a0, 1
+            subb s6, zr, zr, a0
+
+            mulh s7, t5, t6
+            mulhu s8, t5, t6
+            mulh s9, t5, 3
+
+            inc a3
+            mov a4, 40
+            jne a3, a4, pc + loop - $
+    ";
+
+    let emu = emu::_try_run(ASM).unwrap();
+
+    // 0x1_ffffffff + 1 = 0x2_00000000
+    assert_eq!(emu.cpu.gp.s0, 0);
+    assert_eq!(emu.cpu.gp.s1, 2);
+    assert_eq!(emu.cpu.gp.s4, 0);
+
+    // and back again
+    assert_eq!(emu.cpu.gp.s2, 0xffffffff);
+    assert_eq!(emu.cpu.gp.s3, 1);
+    assert_eq!(emu.cpu.gp.s5, 0);
+
+    assert_eq!(emu.cpu.gp.s6, 0xffffffff);
+    assert_eq!(emu.cpu.gp.a0, 1);
+
+    assert_eq!(emu.cpu.gp.s7, 1);
+    assert_eq!(emu.cpu.gp.s8, 0x7fffffff);
+    assert_eq!(emu.cpu.gp.s9, 0xffffffff);
+    drop(emu);
+
+    assert_engines_agree(handle_none, ASM);
+
+    // the carry comes from another block
+    assert_engines_agree(
+        handle_none,
+        r"
+        mov a4, 100
+
+        loop:
+            mov t4, 1
+            jmp body
+
+        body:
+            addc s0, zr, zr, t4
+            add s1, s1, s0
+            mov t5, 1
+            jmp borrow
+
+        borrow:
+            subb s2, zr, zr, t5
+            add s3, s3, s2
+            inc a3
+            jne a3, a4, loop
+        ",
+    );
+}
+
+/// Four registers, and a tick counter at 0x10
+#[derive(Debug, Default)]
+struct TestDevice {
+    regs: [BitSize; 4],
+    ticks: u64,
+    writes: Vec<(BitSize, usize, BitSize)>,
+}
+
+impl Device for TestDevice {
+    fn read(&mut self, offset: BitSize, _size: usize) -> BitSize {
+        match offset {
+            0x10 => self.ticks as BitSize,
+            _ => self.regs[(offset / 4) as usize % 4],
+        }
+    }
+
+    fn write(&mut self, offset: BitSize, size: usize, val: BitSize) {
+        self.regs[(offset / 4) as usize % 4] = val;
+        self.writes.push((offset, size, val));
+    }
+
+    fn ticks(&self) -> bool {
+        true
+    }
+
+    fn tick(&mut self, _clk: u64) {
+        self.ticks += 1;
+    }
+}
+
+#[test]
+#[serial]
+fn test_mmio() {
+    let mut dev = None;
+    let handle = |emu: &mut Emulator| {
+        dev = Some(
+            emu.add_device(0x90000000..0x90000020, TestDevice::default())
+                .unwrap(),
+        );
+
+        let res = emu.add_device(0x90000010..=0x90000fff, TestDevice::default());
+        assert_eq!(
+            res.map(|_| ()),
+            Err(EmuError::Mem(MemError::DeviceOverlap(0x90000010)))
+        );
+    };
+
+    let emu = emu::_try_run_with(
+        handle,
+        r"
+        mov t0, 0x90000000
+        mov t1, 0x1234abcd
+        str [t0 + 4], t1
+        str.b [t0 + 8], t1
+        ld s0, [t0 + 4]
+        ld s1, [t0 + 8]
+        ld s2, [t0 + 0x10]
+
+        ; the rest of the page is still RAM
+        str [t0 + 0x20], t1
+        ld s3, [t0 + 0x20]
+    ",
+    )
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, 0x1234abcd);
+    assert_eq!(emu.cpu.gp.s1, 0xcd);
+    assert_eq!(emu.cpu.gp.s2, 7);
+    assert_eq!(emu.cpu.gp.s3, 0x1234abcd);
+    assert_eq!(emu.mmu.read_unchecked::<u32>(0x90000004).unwrap(), 0);
+    assert_eq!(
+        emu.mmu.read_unchecked::<u32>(0x90000020).unwrap(),
+        0x1234abcd
+    );
+
+    let dev = dev.unwrap();
+    let dev = dev.lock().unwrap();
+    assert_eq!(dev.writes, [(4, 4, 0x1234abcd), (8, 1, 0xcd)]);
+    assert_eq!(emu.devices().len(), 1);
+}
+
+/// Doesn't opt into ticks, and panics on reads at 0xf0
+#[derive(Debug, Default)]
+struct QuietDevice {
+    ticks: u64,
+}
+
+impl Device for QuietDevice {
+    fn read(&mut self, offset: BitSize, _size: usize) -> BitSize {
+        assert_ne!(offset, 0xf0, "bad read");
+        1
+    }
+
+    fn write(&mut self, _offset: BitSize, _size: usize, _val: BitSize) {}
+
+    fn tick(&mut self, _clk: u64) {
+        self.ticks += 1;
+    }
+}
+
+#[test]
+#[serial]
+fn test_device_ticks_and_panics() {
+    let mut dev = None;
+    let handle = |emu: &mut Emulator| {
+        dev = Some(
+            emu.add_device(0x90000000..0x90000100, QuietDevice::default())
+                .unwrap(),
+        );
+    };
+
+    let emu = emu::_try_run_with(
+        handle,
+        r"
+        mov t0, 0x90000000
+        ld s0, [t0]
+        inc s1
+        inc s1
+    ",
+    )
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, 1);
+    assert_eq!(dev.unwrap().lock().unwrap().ticks, 0);
+
+    // a device that panicked once still works afterwards
+    let res = panic::catch_unwind(AssertUnwindSafe(|| emu.mmu.io_read(0x900000f0, 4)));
+    assert!(res.is_err());
+    assert_eq!(emu.mmu.io_read(0x90000000, 4), Some(1));
+}
+
+fn add_uart(emu: &mut Emulator, host: Host) {
+    emu.add_device(uart::BASE..uart::BASE + uart::SIZE, Uart::new(host))
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn test_uart() {
+    let pipe = Pipe::default();
+    pipe.send(b"ok");
+
+    let emu = emu::_try_run_with(
+        |emu| add_uart(emu, Host::Pipe(pipe.clone())),
+        r"
+        mov t0, 0xf0000000
+        mov t1, 0x68
+        str.b [t0], t1
+        mov t1, 0x69
+        str.b [t0], t1
+
+        ld s0, [t0 + 4]
+        ld s1, [t0]
+        ld s2, [t0]
+        ld s3, [t0 + 4]
+        ld s4, [t0]
+    ",
+    )
+    .unwrap();
+
+    assert_eq!(pipe.take(), b"hi");
+    assert_eq!(emu.cpu.gp.s0, uart::STATUS_RX | uart::STATUS_TX);
+    assert_eq!(emu.cpu.gp.s1, b'o' as u32);
+    assert_eq!(emu.cpu.gp.s2, b'k' as u32);
+    assert_eq!(emu.cpu.gp.s3, uart::STATUS_TX);
+    assert_eq!(emu.cpu.gp.s4, 0);
+    drop(emu);
+
+    // received bytes raise the device irq
+    let pipe = Pipe::default();
+    pipe.send(b"ab");
+
+    let emu = emu::_try_run_with(
+        |emu| add_uart(emu, Host::Pipe(pipe.clone())),
+        r"
+        mov t0, 0xf0000000
+        mov t1, 1
+        str [t0 + 8], t1
+
+        wrcr ivec, handler
+        wrcr ie, 0b100
+        ei
+
+        mov t2, 2
+        wait:
+            jne s0, t2, wait
+        hlt
+
+        handler:
+            rdcr s1, icause
+            ld t1, [t0]
+            add s2, s2, t1
+            inc s0
+            iret
+    ",
+    )
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s1, Irq::Device as u32);
+    assert_eq!(emu.cpu.gp.s2, (b'a' + b'b') as u32);
+    assert_eq!(emu.cpu.cr.ip & Irq::Device.bit(), 0);
+}
+
+#[test]
+#[serial]
+#[cfg(target_os = "linux")]
+fn test_uart_pty() {
+    use std::io::{Read as _, Write as _};
+
+    let host = Host::pty().unwrap();
+    let Host::Pty(pty) = &host else {
+        unreachable!()
+    };
+
+    let mut term = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(pty.path())
+        .unwrap();
+    term.write_all(b"z").unwrap();
+
+    let emu = emu::_try_run_with(
+        |emu| add_uart(emu, host),
+        r"
+        mov t0, 0xf0000000
+
+        wait:
+            ld t1, [t0 + 4]
+            and t1, t1, 1
+            jez t1, wait
+
+        ld s0, [t0]
+        add s0, s0, 1
+        str.b [t0], s0
+    ",
+    )
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, b'{' as u32);
+
+    let mut buf = [0];
+    term.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"{");
+}
+
+#[test]
+#[serial]
+fn test_ecall() {
+    // 1: sum of the bytes in [a0, a0 + a1), and the count
+    fn handle(emu: &mut Emulator) {
+        emu.ecalls.register(1, |regs, mmu| {
+            let mut buf = vec![0; regs.a1 as usize];
+            mmu.memcpy(regs.a0, &mut buf)?;
+            let sum = buf.iter().map(|&b| b as BitSize).sum();
+            Ok((sum, buf.len() as BitSize))
+        });
+
+        emu.ecalls.register(2, |regs, _| match regs.a0 {
+            0 => Ok((0, 0)),
+            n => Err(format!("bad arg {n}").into()),
+        });
+    }
+
+    const ASM: &str = r"
+        mov a7, 1
+        loop:
+            mov a0, data
+            mov a1, 3
+            ecall
+            add s0, s0, a0
+            mov s1, a1
+            inc t0
+            mov t1, 40
+            jne t0, t1, pc + loop - $
+
+        mov a7, 2
+        mov a0, 0
+        ecall
+        hlt
+
+        data:
+            #d8 1, 2, 3
+    ";
+
+    let emu = emu::_try_run_with(handle, ASM).unwrap();
+    assert_eq!(emu.cpu.gp.s0, 6 * 40);
+    assert_eq!(emu.cpu.gp.s1, 3);
+    drop(emu);
+
+    assert_engines_agree(handle, ASM);
+
+    let res = try_run_with! {
+        handle,
+
+        mov a7, 2
+        mov a0, 5
+        ecall
+    };
+
+    assert_eq!(res.map(|_| ()), Err(EmuError::Ecall(2, "bad arg 5".into())));
+
+    let res = try_run_with! {
+        handle,
+
+        mov a7, 3
+        ecall
+    };
+
+    assert_eq!(
+        res.map(|_| ()),
+        Err(EmuError::Ecall(3, "no handler registered".into()))
+    );
+    // an unused imm word is skipped
+    let emu = try_run_with! {
+        handle,
+
+        mov a7, 2
+        #d8 0x20, 0x16, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff
+        inc s0
+    }
+    .unwrap();
+
+    assert_eq!(emu.cpu.gp.s0, 1);
+}
+
+#[test]
+#[serial]
+fn test_exit_code() {
+    fn threaded(emu: &mut Emulator) {
+        emu.engine = Engine::Threaded;
+    }
+
+    for handle in [handle_none, threaded] {
+        let mut emu = emu::_try_run_with(
+            handle,
+            r"
+            mov s0, 3
+            hlt s0
+        ",
+        )
+        .unwrap();
+
+        assert_eq!(emu.cpu.exit_code, 3);
+
+        // starting over gives the same outcome
+        emu.cpu.zeroize();
+        let outcome = emu.run().unwrap();
+        assert_eq!(
+            outcome,
+            RunOutcome {
+                reason: HaltReason::Hlt,
+                exit_code: 3
+            }
+        );
+    }
+
+    // plain hlt exits with 0
+    let emu = run! {
+        mov s0, 3
+    };
+
+    assert_eq!(emu.cpu.exit_code, 0);
+}
+
+#[test]
+#[serial]
+fn test_breakpoints() {
+    fn threaded(emu: &mut Emulator) {
+        emu.engine = Engine::Threaded;
+    }
+
+    for handle in [handle_none, threaded] {
+        let mut emu = emu::_try_run_with(
+            handle,
+            r"
+            mov t0, 7
+            add t0, t0, 2
+            add t0, t0, 3
+            hlt t0
+        ",
+        )
+        .unwrap();
+
+        emu.cpu.zeroize();
+        let step = emu.step().unwrap();
+        assert_eq!(step.reason, HaltReason::Step);
+        assert_eq!(emu.cpu.gp.t0, 7);
+
+        let bp = emu.cpu.pc;
+        emu.cpu.zeroize();
+        emu.breakpoints.insert(bp);
+
+        // stops before running the breakpoint
+        let outcome = emu.run().unwrap();
+        assert_eq!(outcome.reason, HaltReason::Breakpoint);
+        assert_eq!(emu.cpu.pc, bp);
+        assert_eq!(emu.cpu.gp.t0, 7);
+
+        // resuming runs it
+        let outcome = emu.run().unwrap();
+        assert_eq!(
+            outcome,
+            RunOutcome {
+                reason: HaltReason::Hlt,
+                exit_code: 12
+            }
+        );
+
+        // interrupting before the run stops at the first instruction
+        emu.cpu.zeroize();
+        emu.interrupter().store(true, Ordering::Relaxed);
+        let outcome = emu.run().unwrap();
+        assert_eq!(outcome.reason, HaltReason::Interrupted);
+        assert_eq!(emu.cpu.pc, 0);
+
+        // and doesn't stick around
+        let outcome = emu.run().unwrap();
+        assert_eq!(outcome.reason, HaltReason::Breakpoint);
+    }
+}
+
+#[test]
+#[serial]
+fn test_gdb() {
+    use crate::gdb::{self, SessionEnd};
+    use std::{
+        io::{Read as _, Write as _},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    fn rsp(stream: &mut TcpStream, packet: &str) -> String {
+        let sum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(stream, "${packet}#{sum:02x}").unwrap();
+
+        let mut byte = || {
+            let mut b = [0];
+            stream.read_exact(&mut b).unwrap();
+            b[0]
+        };
+
+        assert_eq!(byte(), b'+');
+        assert_eq!(byte(), b'$');
+
+        let reply: Vec<u8> = std::iter::from_fn(|| Some(byte()))
+            .take_while(|&b| b != b'#')
+            .collect();
+
+        // checksum
+        byte();
+        byte();
+        stream.write_all(b"+").unwrap();
+
+        String::from_utf8(reply).unwrap()
+    }
+
+    let mut emu = run! {
+        mov t0, 7
+        add t0, t0, 2
+        add t0, t0, 3
+        hlt t0
+    };
+
+    emu.cpu.zeroize();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let mut s = TcpStream::connect(addr).unwrap();
+
+        assert!(rsp(&mut s, "qSupported:swbreak+").contains("swbreak+"));
+
+        let xml = rsp(&mut s, "qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with('l'));
+        assert!(xml.contains(r#"name="t0""#));
+        assert!(xml.contains(r#"name="a7""#));
+        assert!(xml.contains(r#"name="pc" bitsize="32" type="code_ptr" regnum="32""#));
+
+        assert_eq!(rsp(&mut s, "?"), "S05");
+        assert_eq!(rsp(&mut s, "s"), "S05");
+        assert_eq!(rsp(&mut s, "p5"), "07000000");
+
+        // back to the start, with a breakpoint on the second instruction
+        let pc = rsp(&mut s, "p20");
+        let bp = u32::from_le_bytes(u32::from_str_radix(&pc, 16).unwrap().to_be_bytes());
+        assert_eq!(rsp(&mut s, "P20=00000000"), "OK");
+        assert_eq!(rsp(&mut s, &format!("Z0,{bp:x},4")), "OK");
+        assert_eq!(rsp(&mut s, "c"), "T05swbreak:;");
+        assert_eq!(rsp(&mut s, "p20"), pc);
+
+        let regs = rsp(&mut s, "g");
+        assert_eq!(regs.len(), 33 * 8);
+        assert_eq!(&regs[5 * 8..6 * 8], "07000000");
+
+        assert_eq!(rsp(&mut s, "M2000,2:abcd"), "OK");
+        assert_eq!(rsp(&mut s, "m2000,2"), "abcd");
+        assert_eq!(rsp(&mut s, "M2000,2:0000"), "OK");
+
+        assert_eq!(rsp(&mut s, "P5=0a000000"), "OK");
+        assert_eq!(rsp(&mut s, &format!("z0,{bp:x},4")), "OK");
+        assert_eq!(rsp(&mut s, "c"), "W0f");
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let end = gdb::serve(&mut emu, stream).unwrap();
+    client.join().unwrap();
+
+    assert_eq!(
+        end,
+        SessionEnd::Exited(RunOutcome {
+            reason: HaltReason::Hlt,
+            exit_code: 15
+        })
+    );
+}
+
+#[test]
+#[serial]
+fn test_debugger() {
+    use crate::debugger::{Debugger, Symbols};
+
+    let asm = r"
+    start:
+        mov t0, 7
+        call double
+        add t0, t0, 1
+        hlt t0
+    double:
+        add t0, t0, t0
+        ret
+    ";
+
+    let (_, symbols) = graft::assemble_with_symbols("<input>.asm", asm).unwrap();
+    let symbols = Symbols::parse(&symbols);
+    assert_eq!(symbols.get("double"), Some(0x1c));
+    assert_eq!(symbols.lookup(0x20), Some(("double", 4)));
+
+    let mut emu = emu::_try_run(asm).unwrap();
+    emu.cpu.zeroize();
+
+    let mut dbg = Debugger::new(&mut emu, symbols);
+    let mut cmd = |line: &str| dbg.command(line).unwrap();
+
+    assert_eq!(cmd("b double"), "Breakpoint at 0x0000001c <double>\n");
+    assert!(cmd("c").starts_with("Breakpoint at 0x0000001c <double>\n=> 0x0000001c <double>: "));
+    assert_eq!(cmd("p t0 + 1"), "0x00000008 (8)\n");
+    assert_eq!(cmd("p [start] - 1"), "0x00000f64 (3940)\n");
+    assert_eq!(cmd("p [t0 +"), "Bad expression: [t0 +\n");
+    assert_eq!(cmd("p nope"), "Unknown symbol: nope\n");
+    assert_eq!(cmd("w t0"), "w0: t0 = 0x00000007\n");
+    assert_eq!(cmd("d double"), "");
+
+    // stepping into and out of the call
+    let out = cmd("s 2");
+    assert!(out.contains("<start+16>"));
+    assert!(out.ends_with("w0: t0 = 0x0000000e (was 0x00000007)\n"));
+
+    // stepping over a call runs it to completion
+    emu.cpu.zeroize();
+    let mut dbg = Debugger::new(&mut emu, Symbols::default());
+    dbg.command("s");
+    let out = dbg.command("n").unwrap();
+    assert!(out.starts_with("=> 0x00000010: "), "{out}");
+    assert_eq!(dbg.command("p t0").unwrap(), "0x0000000e (14)\n");
+
+    assert_eq!(dbg.command("c").unwrap(), "Exited with code 15\n");
+    assert_eq!(
+        dbg.command("x 0 4").unwrap(),
+        format!("0x00000000  65 0f 00 00{:36}  |e...|\n", "")
+    );
+    assert!(dbg.command("r").unwrap().contains("t0  0x0000000f"));
+    assert_eq!(dbg.command("q"), None);
+}
+
+#[test]
+#[serial]
+fn test_snapshot() {
+    use snapshot::{SnapshotError, VERSION};
+
+    let asm = r"
+        mov t0, 0x2000
+        mov t1, 0xabcd
+        str [t0], t1
+        add t1, t1, 1
+        hlt t1
+    ";
+
+    let mut emu = emu::_try_run(asm).unwrap();
+    emu.set_harts(2);
+    emu.harts[0].gp.s0 = 5;
+
+    let snap = emu.snapshot().unwrap();
+    assert_eq!(snap.harts.len(), 2);
+    assert_eq!(snap.harts[0].gp.t1, 0xabce);
+    assert_eq!(snap.harts[1].gp.s0, 5);
+
+    // only the program and the word it stored
+    let addrs: Vec<_> = snap.pages.iter().map(|(addr, _)| *addr).collect();
+    assert_eq!(addrs, [0, 0x2000]);
+    assert_eq!(snap.prot.len(), 2);
+
+    let mut file = Vec::new();
+    snap.save(&mut file).unwrap();
+    let loaded = Snapshot::load(file.as_slice()).unwrap();
+    assert_eq!(loaded, snap);
+
+    // everything since the snapshot is undone
+    emu.cpu.zeroize();
+    emu.set_harts(1);
+    emu.mmu.write(0x2000u32, 1u32).unwrap();
+    emu.mmu.write(0x5000u32, 1u32).unwrap();
+    emu.mmu.set_prot(0x5000, Prot::Read);
+
+    emu.restore(&loaded).unwrap();
+    assert_eq!(emu.cpu.gp.t1, 0xabce);
+    assert_eq!(emu.harts.len(), 1);
+    assert_eq!(emu.harts[0].gp.s0, 5);
+    assert_eq!(emu.mmu.read::<BitSize>(0x2000).unwrap(), 0xabcd);
+    assert_eq!(emu.mmu.read::<BitSize>(0x5000).unwrap(), 0);
+    assert_eq!(emu.mmu.prot(0x5000), Prot::Read | Prot::Write);
+    assert_eq!(emu.snapshot().unwrap(), snap);
+
+    // a snapshot from the middle of a run finishes the same way
+    emu.set_harts(1);
+    emu.cpu.zeroize();
+    emu.step().unwrap();
+    emu.step().unwrap();
+    let warm = emu.snapshot().unwrap();
+    assert_eq!(emu.run().unwrap().exit_code, 0xabce);
+
+    emu.restore(&warm).unwrap();
+    assert_eq!(emu.cpu.gp.t1, 0xabcd);
+    assert_eq!(emu.run().unwrap().exit_code, 0xabce);
+
+    // bad files
+    assert_eq!(
+        Snapshot::load(&b"ASPNSNOP"[..]),
+        Err(SnapshotError::BadMagic)
+    );
+
+    let mut newer = file.clone();
+    newer[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    assert_eq!(
+        Snapshot::load(newer.as_slice()),
+        Err(SnapshotError::Version(VERSION + 1))
+    );
+
+    assert_eq!(
+        Snapshot::load(&file[..file.len() - 1]),
+        Err(SnapshotError::Truncated)
+    );
+}
+
+#[test]
+#[serial]
+fn test_reverse_execution() {
+    use crate::debugger::Symbols;
+
+    let asm = r"
+        mov t0, 0x3000
+        mov t2, 4000
+
+    loop:
+        str [t0], t1
+        inc t1
+        jl t1, t2, loop
+
+        mov t3, 0xdead
+    corrupt:
+        str [t0], t3
+        hlt
+    ";
+
+    let (_, symbols) = graft::assemble_with_symbols("<input>.asm", asm).unwrap();
+    let symbols = Symbols::parse(&symbols);
+    let (lp, corrupt) = (
+        symbols.get("loop").unwrap(),
+        symbols.get("corrupt").unwrap(),
+    );
+
+    let mut emu = emu::_try_run(asm).unwrap();
+    assert_eq!(emu.step_back(), Err(EmuError::NotRecording));
+
+    emu.cpu.zeroize();
+    emu.mmu.write(0x3000u32, 0u32).unwrap();
+    emu.record().unwrap();
+
+    // long enough to need more than one checkpoint
+    assert_eq!(emu.run().unwrap().reason, HaltReason::Hlt);
+    assert_eq!(emu.mmu.read::<BitSize>(0x3000).unwrap(), 0xdead);
+
+    let write = emu.last_write(0x3002).unwrap();
+    assert_eq!((write.pc, write.addr, write.len), (corrupt, 0x3000, 4));
+    assert_eq!(emu.last_write(0x3004), None);
+
+    // back to before the bad store
+    emu.step_back().unwrap();
+    let outcome = emu.step_back().unwrap();
+    assert_eq!(outcome.reason, HaltReason::Step);
+    assert_eq!(emu.cpu.pc, corrupt);
+    assert_eq!(emu.mmu.read::<BitSize>(0x3000).unwrap(), 3999);
+    assert_eq!(emu.last_write(0x3000).unwrap().pc, lp);
+
+    // the last two times around the loop
+    emu.breakpoints.insert(lp);
+    for i in [3999, 3998] {
+        let outcome = emu.reverse_continue().unwrap();
+        assert_eq!(outcome.reason, HaltReason::Breakpoint);
+        assert_eq!(emu.cpu.pc, lp);
+        assert_eq!(emu.cpu.gp.t1, i);
+        assert_eq!(emu.mmu.read::<BitSize>(0x3000).unwrap(), i - 1);
+    }
+
+    emu.breakpoints.remove(&lp);
+    let outcome = emu.reverse_continue().unwrap();
+    assert_eq!(outcome.reason, HaltReason::HistoryStart);
+    assert_eq!(emu.cpu.pc, 0);
+    assert_eq!(emu.cpu.gp.t1, 0);
+    assert_eq!(emu.mmu.read::<BitSize>(0x3000).unwrap(), 0);
+    assert_eq!(emu.step_back().unwrap().reason, HaltReason::HistoryStart);
+
+    // and forward again
+    assert_eq!(emu.run().unwrap().reason, HaltReason::Hlt);
+    assert_eq!(emu.mmu.read::<BitSize>(0x3000).unwrap(), 0xdead);
+}
+
+#[test]
+#[serial]
+fn test_reverse_edits() {
+    let mut emu = run! {
+        mov t0, 1
+        mov t1, 2
+        mov t4, 0x7000
+        str [t4], t0
+    };
+
+    emu.cpu.zeroize();
+    emu.mmu.write(0x5000u32, 7u32).unwrap();
+    let snapshot = emu.snapshot().unwrap();
+    emu.record().unwrap();
+
+    // restoring isn't a store by the first instruction
+    emu.restore(&snapshot).unwrap();
+    emu.step().unwrap();
+    assert_eq!(emu.last_write(0x5000), None);
+
+    // edits between instructions survive going back to them
+    emu.cpu.gp.t3 = 99;
+    emu.mmu.write(0x6000u32, 5u32).unwrap();
+    emu.step().unwrap();
+    emu.step_back().unwrap();
+
+    assert_eq!(emu.cpu.pc, 8);
+    assert_eq!(emu.cpu.gp.t3, 99);
+    assert_eq!(emu.mmu.read::<BitSize>(0x6000).unwrap(), 5);
+    assert_eq!(emu.last_write(0x6000), None);
+
+    // and are undone going back past them
+    emu.step_back().unwrap();
+    assert_eq!(emu.cpu.pc, 0);
+    assert_eq!(emu.cpu.gp.t3, 0);
+    assert_eq!(emu.mmu.read::<BitSize>(0x6000).unwrap(), 0);
+    assert_eq!(emu.mmu.read::<BitSize>(0x5000).unwrap(), 7);
+}
+
+#[test]
+#[serial]
+fn test_history_limit() {
+    let mut emu = run! {
+        mov t0, 0x3000
+        mov t2, 9000
+
+    loop:
+        str [t0], t1
+        inc t1
+        jl t1, t2, loop
+    };
+
+    emu.cpu.zeroize();
+    emu.record_with(2).unwrap();
+    assert_eq!(emu.run().unwrap().reason, HaltReason::Hlt);
+
+    // checkpoints at 10000 and 20000 are left. 10000 is 3332 times around the loop,
+    // then a store and an inc
+    let outcome = emu.reverse_continue().unwrap();
+    assert_eq!(outcome.reason, HaltReason::HistoryStart);
+    assert_eq!(emu.cpu.gp.t1, 3333);
+    assert_eq!(emu.mmu.read::<BitSize>(0x3000).unwrap(), 3332);
+    assert_eq!(emu.step_back().unwrap().reason, HaltReason::HistoryStart);
+
+    assert_eq!(emu.run().unwrap().reason, HaltReason::Hlt);
+    assert_eq!(emu.cpu.gp.t1, 9000);
+}
+
+#[test]
+#[serial]
+fn test_stack() {
+    let emu = run! {
+        mov sp, 0x1000
+        mov ra, 0x1234
+        mov t0, 0x11111111
+        mov t1, 0x22222222
+
+        push t0
+        push t1
+        mov s0, sp
+        call func
+        mov s2, sp
+        pop s3
+        pop s4
+        mov s5, sp
+        hlt
+
+        func:
+            mov s1, sp
+            ret
+    };
+
+    // every slot is a full word, so pushes don't overlap
+    assert_eq!(emu.cpu.gp.s0, 0x1000 - 8);
+    assert_eq!(emu.mmu.read::<BitSize>(0xffc).unwrap(), 0x11111111);
+    assert_eq!(emu.mmu.read::<BitSize>(0xff8).unwrap(), 0x22222222);
+
+    // call saves the old ra below them
+    assert_eq!(emu.cpu.gp.s1, 0x1000 - 12);
+    assert_eq!(emu.mmu.read::<BitSize>(0xff4).unwrap(), 0x1234);
+    assert_eq!(emu.cpu.gp.ra, 0x1234);
+
+    assert_eq!(emu.cpu.gp.s2, 0x1000 - 8);
+    assert_eq!((emu.cpu.gp.s3, emu.cpu.gp.s4), (0x22222222, 0x11111111));
+    assert_eq!(emu.cpu.gp.s5, 0x1000);
+}
+
+#[test]
+#[serial]
+fn test_reverse_ecall() {
+    use std::sync::atomic::AtomicU32;
+
+    // 1: count the calls, store count * 10 to [a0] and return the count
+    let calls = Arc::new(AtomicU32::new(0));
+    let counter = calls.clone();
+    let handle = move |emu: &mut Emulator| {
+        emu.ecalls.register(1, move |regs, mmu| {
+            let n = counter.fetch_add(1, Ordering::Relaxed) + 1;
+            mmu.write(regs.a0, n * 10)?;
+            Ok((n, 0))
+        });
+    };
+
+    let mut emu = emu::_try_run_with(
+        handle,
+        r"
+        mov a7, 1
+        mov a0, 0x10000
+        ecall
+        mov s0, a0
+        mov a0, 0x10004
+        ecall
+        mov t0, 0x10008
+        str [t0], a0
+        hlt
+    ",
+    )
+    .unwrap();
+
+    emu.cpu.zeroize();
+    emu.mmu.memset(0x10000, 0, 12).unwrap();
+    calls.store(0, Ordering::Relaxed);
+    emu.record().unwrap();
+    assert_eq!(emu.run().unwrap().reason, HaltReason::Hlt);
+    assert_eq!(emu.mmu.read::<BitSize>(0x10008).unwrap(), 2);
+
+    // replays give the recorded answers without calling the host
+    emu.step_back().unwrap();
+    assert_eq!((emu.cpu.gp.s0, emu.cpu.gp.a0), (1, 2));
+    assert_eq!(emu.mmu.read::<BitSize>(0x10004).unwrap(), 20);
+    assert_eq!(emu.mmu.read::<BitSize>(0x10008).unwrap(), 2);
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+    // back to before the second one
+    for _ in 0..3 {
+        emu.step_back().unwrap();
+    }
+
+    assert_eq!((emu.cpu.gp.s0, emu.cpu.gp.a0), (1, 0x10004));
+    assert_eq!(emu.mmu.read::<BitSize>(0x10000).unwrap(), 10);
+    assert_eq!(emu.mmu.read::<BitSize>(0x10004).unwrap(), 0);
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+    // going forward calls it again
+    emu.step().unwrap();
+    assert_eq!(emu.cpu.gp.a0, 3);
+    assert_eq!(emu.mmu.read::<BitSize>(0x10004).unwrap(), 30);
+    assert_eq!(calls.load(Ordering::Relaxed), 3);
+    assert_eq!(emu.last_write(0x10004).unwrap().pc, emu.cpu.pc - 4);
+
+    // and replays that answer from then on
+    emu.step().unwrap();
+    emu.step_back().unwrap();
+    assert_eq!(emu.cpu.gp.a0, 3);
+    assert_eq!(emu.run().unwrap().reason, HaltReason::Hlt);
+    assert_eq!(emu.mmu.read::<BitSize>(0x10008).unwrap(), 3);
+    assert_eq!(calls.load(Ordering::Relaxed), 3);
+}
@@ -1,13 +1,13 @@
 use std::{
     ops::{Deref, DerefMut},
-    sync::{LazyLock, MutexGuard},
+    sync::{LazyLock, MutexGuard, atomic::Ordering},
 };
 
 use aho_corasick::AhoCorasick;
 use sayuri::sync::Mutex;
 
-use super::{EmuError, Emulator};
-use crate::mmu::Prot;
+use super::{EmuError, Emulator, Engine, ecall::Ecalls};
+use crate::{dev::Devices, mmu::Prot};
 
 #[derive(Debug)]
 pub struct EmuGuard<'a>(MutexGuard<'a, Emulator>, bool);
@@ -29,6 +29,16 @@ impl DerefMut for EmuGuard<'_> {
 impl Drop for EmuGuard<'_> {
     fn drop(&mut self) {
         self.cpu.zeroize();
+        self.harts.clear();
+        self.engine = Engine::default();
+        self.ecalls = Ecalls::default();
+        self.breakpoints.clear();
+        self.stop_recording();
+        self.interrupter().store(false, Ordering::Relaxed);
+        self.dev = Devices::default();
+        for map in self.devices() {
+            self.remove_device(map.start);
+        }
         // mem dirty flag
         let dirty = self.1;
         // skip mem resetting if there's nothing to reset, to save on processing
@@ -53,7 +63,7 @@ pub fn _try_run_with(f: impl FnOnce(&mut Emulator), asm: &str) -> Result<EmuGuar
     static LOCK: LazyLock<Mutex<Emulator>> =
         LazyLock::new(|| Mutex::new(Emulator::new(&[]).unwrap()));
 
-    let patterns = &["str", "str.w", "str.b"];
+    let patterns = &["str", "str.w", "str.b", "cas", "amo", "push", "call"];
     let ac = AhoCorasick::new(patterns).unwrap();
     let dirty = ac.find(asm).is_some();
 
@@ -0,0 +1,570 @@
+//! Threaded-code engine.
+//!
+//! Guest basic blocks are translated once into a chain of closures with their register
+//! operands and immediates already pulled out of the encoding, then run back to back.
+//! A block ends at anything that changes control flow (jumps, `call`, `ret`, `hlt`,
+//! `trapret`, `iret`) or state the translation depends on (`wrcr`, `mprot`, `tlbflush`,
+//! `ei`, `di`), and never crosses a page. Instructions without a handler of their own
+//! go through [`Cpu::process`].
+//!
+//! Differences from the interpreter:
+//! - interrupts are only taken between blocks
+//! - a store into the running block takes effect the next time the block is entered
+
+use std::sync::Arc;
+
+use crate::{
+    BitSize,
+    cpu::{Cpu, CpuError, hart::Harts},
+    dev::Devices,
+    emulator::EmuError,
+    instruction::{Instruction, InstructionType},
+    mmu::{Mmu, PAGE_SIZE, Prot, Protection},
+};
+
+const PAGE_MASK: BitSize = PAGE_SIZE as BitSize - 1;
+/// Longest block, in instructions
+const MAX_BLOCK: usize = 64;
+const CACHE_SIZE: usize = 4096;
+
+/// What the block should do after an op
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Flow {
+    /// on to the next op
+    Next,
+    /// the op set pc, leave the block
+    Jump,
+    /// hlt
+    Stop,
+}
+
+/// Everything an op can touch besides the cpu
+pub struct Env<'a> {
+    pub mmu: &'a Arc<Mmu>,
+    pub dev: &'a Devices,
+    pub harts: &'a Harts,
+}
+
+type Op = Box<dyn Fn(&mut Cpu, &Env) -> Result<Flow, CpuError> + Send>;
+
+fn op(f: impl Fn(&mut Cpu, &Env) -> Result<Flow, CpuError> + Send + 'static) -> Op {
+    Box::new(f)
+}
+
+struct Block {
+    /// physical address + 1, 0 is an empty slot
+    tag: BitSize,
+    generation: BitSize,
+    /// physical protection when the block was translated
+    prot: Protection,
+    ops: Vec<Op>,
+    /// guest pc and cycle cost of each op
+    meta: Vec<(BitSize, u64)>,
+    /// pc after the last op, when it falls through
+    end: BitSize,
+}
+
+/// Direct-mapped cache of translated blocks
+pub struct Blocks {
+    slots: Vec<Option<Block>>,
+}
+
+impl Default for Blocks {
+    fn default() -> Self {
+        Self {
+            slots: (0..CACHE_SIZE).map(|_| None).collect(),
+        }
+    }
+}
+
+impl Blocks {
+    /// Run the block at pc, translating it first if needed. Ok(false) if nothing
+    /// can be translated there, the interpreter should take a step instead.
+    /// On a fault, pc and clk are left at the faulting instruction
+    pub fn run(&mut self, cpu: &mut Cpu, env: &Env, stop: &mut bool) -> Result<bool, EmuError> {
+        let pc = cpu.pc;
+        if !pc.is_multiple_of(4) {
+            return Ok(false);
+        }
+
+        // the interpreter reports fetch faults
+        let paddr = match cpu.paging() {
+            true => match cpu.translate(env.mmu, pc, Prot::Execute) {
+                Ok(paddr) => paddr,
+                Err(_) => return Ok(false),
+            },
+            false => pc,
+        };
+
+        let slot = &mut self.slots[(paddr >> 2) as usize % CACHE_SIZE];
+
+        let fresh = slot.as_ref().is_some_and(|b| {
+            b.tag == paddr + 1 && b.meta[0].0 == pc && b.generation == env.mmu.code_gen(paddr)
+        });
+
+        if !fresh {
+            *slot = translate(env.mmu, paddr, pc);
+        }
+
+        let Some(block) = slot else {
+            return Ok(false);
+        };
+
+        if !cpu.paging() && !block.prot.contains(cpu.access(Prot::Execute)) {
+            return Ok(false);
+        }
+
+        for (op, &(pc, clk)) in block.ops.iter().zip(&block.meta) {
+            match op(cpu, env) {
+                Ok(Flow::Next) => cpu.clk += clk,
+
+                Ok(Flow::Jump) => {
+                    cpu.clk += clk;
+                    return Ok(true);
+                }
+
+                Ok(Flow::Stop) => {
+                    cpu.pc = pc;
+                    *stop = true;
+                    return Ok(true);
+                }
+
+                Err(e) => {
+                    cpu.pc = pc;
+                    return Err(e.into());
+                }
+            }
+        }
+
+        cpu.pc = block.end;
+
+        Ok(true)
+    }
+}
+
+/// Translate instructions from paddr on, pc being their guest address
+fn translate(mmu: &Mmu, paddr: BitSize, pc: BitSize) -> Option<Block> {
+    let generation = mmu.mark_code(paddr);
+    let prot = mmu.prot(paddr);
+
+    let tag = paddr + 1;
+    let mut ops = Vec::new();
+    let mut meta = Vec::new();
+    let (mut paddr, mut pc) = (paddr, pc);
+
+    while ops.len() < MAX_BLOCK {
+        // the imm word might be on the next page
+        if paddr & PAGE_MASK > PAGE_MASK - 7 {
+            break;
+        }
+
+        let mut buf = [0; 8];
+        mmu.memcpy(paddr, &mut buf).ok()?;
+
+        let Ok(inst) = Instruction::from_buf(buf) else {
+            break;
+        };
+
+        // run by the hart, see `Hart::step`
+        if inst.ty == InstructionType::Ecall {
+            break;
+        }
+
+        let len = if inst.has_imm { 8 } else { 4 };
+        let (op, clk, ends) = compile(inst, pc, pc.wrapping_add(len));
+
+        ops.push(op);
+        meta.push((pc, clk));
+        paddr += len;
+        pc = pc.wrapping_add(len);
+
+        if ends {
+            break;
+        }
+    }
+
+    if ops.is_empty() {
+        return None;
+    }
+
+    Some(Block {
+        tag,
+        generation,
+        prot,
+        ops,
+        meta,
+        end: pc,
+    })
+}
+
+/// Build the op for one instruction at pc, with its cycle cost and whether it ends the block
+fn compile(inst: Instruction, pc: BitSize, next: BitSize) -> (Op, u64, bool) {
+    use InstructionType::*;
+
+    let (dst, a, b, imm) = (inst.dst, inst.a, inst.b, inst.imm);
+
+    // dst = f(reg a, imm or reg b), same as `Cpu::process`
+    macro_rules! alu {
+        (|$a:ident, $b:ident| $e:expr) => {
+            match inst.has_imm {
+                true => op(move |cpu, _| {
+                    let ($a, $b) = (cpu.gp.get_reg(a), imm);
+                    cpu.gp.set_reg(dst, $e);
+                    Ok(Flow::Next)
+                }),
+
+                false => op(move |cpu, _| {
+                    let ($a, $b) = (cpu.gp.get_reg(a), cpu.gp.get_reg(b));
+                    cpu.gp.set_reg(dst, $e);
+                    Ok(Flow::Next)
+                }),
+            }
+        };
+    }
+
+    // imm or reg, read when the op runs
+    macro_rules! imm_or {
+        ($cpu:ident, $reg:expr) => {
+            match inst.has_imm {
+                true => imm,
+                false => $cpu.gp.get_reg($reg),
+            }
+        };
+    }
+
+    // ends the block either way
+    macro_rules! jcc {
+        (|$a:ident, $b:ident| $e:expr) => {{
+            let op = op(move |cpu, _| {
+                let ($a, $b) = (cpu.gp.get_reg(a), cpu.gp.get_reg(b));
+                match $e {
+                    true => {
+                        cpu.pc = imm_or!(cpu, dst);
+                        Ok(Flow::Jump)
+                    }
+
+                    false => Ok(Flow::Next),
+                }
+            });
+
+            return (op, 1, true);
+        }};
+    }
+
+    let f = f32::from_bits;
+
+    let op = match inst.ty {
+        Nop => op(|_, _| Ok(Flow::Next)),
+        Hlt => {
+            let op = op(move |cpu, _| {
+                cpu.exit_code = cpu.gp.get_reg(a);
+                Ok(Flow::Stop)
+            });
+
+            return (op, 1, true);
+        }
+
+        Nand => alu!(|a, b| !(a & b)),
+        Or => alu!(|a, b| a | b),
+        And => alu!(|a, b| a & b),
+        Nor => alu!(|a, b| !(a | b)),
+        Add => alu!(|a, b| a.wrapping_add(b)),
+        Sub => alu!(|a, b| a.wrapping_sub(b)),
+        Xor => alu!(|a, b| a ^ b),
+        Lsl => alu!(|a, b| a << b),
+        Lsr => alu!(|a, b| a >> b),
+        Mul => alu!(|a, b| a.wrapping_mul(b)),
+        Imul => alu!(|a, b| (a as i32).wrapping_mul(b as i32) as u32),
+        Mulh => alu!(|a, b| ((a as i32 as i64 * b as i32 as i64) >> 32) as u32),
+        Mulhu => alu!(|a, b| ((a as u64 * b as u64) >> 32) as u32),
+        Div => alu!(|a, b| if a != 0 { a.wrapping_div(b) } else { 0 }),
+        Idiv => alu!(|a, b| if a != 0 {
+            (a as i32).wrapping_div(b as i32) as u32
+        } else {
+            0
+        }),
+        Rem => alu!(|a, b| a % b),
+        Irem => alu!(|a, b| (a as i32 % b as i32) as u32),
+        Se => alu!(|a, b| (a == b) as _),
+        Sne => alu!(|a, b| (a != b) as _),
+        Sl => alu!(|a, b| ((a as i32) < b as i32) as _),
+        Sle => alu!(|a, b| (a as i32 <= b as i32) as _),
+        Sg => alu!(|a, b| (a as i32 > b as i32) as _),
+        Sge => alu!(|a, b| (a as i32 >= b as i32) as _),
+        Asr => alu!(|a, b| (a as i32 >> b as i32) as u32),
+        Fadd => alu!(|a, b| (f(a) + f(b)).to_bits()),
+        Fsub => alu!(|a, b| (f(a) - f(b)).to_bits()),
+        Fmul => alu!(|a, b| (f(a) * f(b)).to_bits()),
+        Fdiv => alu!(|a, b| (f(a) / f(b)).to_bits()),
+        Rol => alu!(|a, b| a.rotate_left(b)),
+        Ror => alu!(|a, b| a.rotate_right(b)),
+        Min => alu!(|a, b| (a as i32).min(b as i32) as u32),
+        Max => alu!(|a, b| (a as i32).max(b as i32) as u32),
+        Minu => alu!(|a, b| a.min(b)),
+        Maxu => alu!(|a, b| a.max(b)),
+
+        Clz => op(move |cpu, _| {
+            cpu.gp.set_reg(dst, cpu.gp.get_reg(a).leading_zeros());
+            Ok(Flow::Next)
+        }),
+
+        Ctz => op(move |cpu, _| {
+            cpu.gp.set_reg(dst, cpu.gp.get_reg(a).trailing_zeros());
+            Ok(Flow::Next)
+        }),
+
+        Popcnt => op(move |cpu, _| {
+            cpu.gp.set_reg(dst, cpu.gp.get_reg(a).count_ones());
+            Ok(Flow::Next)
+        }),
+
+        Bswap => op(move |cpu, _| {
+            cpu.gp.set_reg(dst, cpu.gp.get_reg(a).swap_bytes());
+            Ok(Flow::Next)
+        }),
+
+        Csel => op(move |cpu, _| {
+            let val = match cpu.gp.get_reg(dst) {
+                0 => imm_or!(cpu, b),
+                _ => cpu.gp.get_reg(a),
+            };
+
+            cpu.gp.set_reg(dst, val);
+            Ok(Flow::Next)
+        }),
+
+        Mov => op(move |cpu, _| {
+            cpu.gp.set_reg(dst, imm_or!(cpu, a));
+            Ok(Flow::Next)
+        }),
+
+        Inc => op(move |cpu, _| {
+            cpu.gp.set_reg(dst, cpu.gp.get_reg(dst).wrapping_add(1));
+            Ok(Flow::Next)
+        }),
+
+        Dec => op(move |cpu, _| {
+            cpu.gp.set_reg(dst, cpu.gp.get_reg(dst).wrapping_sub(1));
+            Ok(Flow::Next)
+        }),
+
+        Ld | Ldw | Ldb => {
+            let size = inst.ty.access_size();
+            op(move |cpu, env| {
+                let val = cpu.load(env.mmu, imm_or!(cpu, a), size)?;
+                cpu.gp.set_reg(dst, val);
+                Ok(Flow::Next)
+            })
+        }
+
+        Str | Strw | Strb => {
+            let size = inst.ty.access_size();
+            op(move |cpu, env| {
+                let addr = cpu.gp.get_reg(dst);
+                cpu.store(env.mmu, addr, imm_or!(cpu, a), size)?;
+                Ok(Flow::Next)
+            })
+        }
+
+        Ldo | Ldwo | Ldbo => {
+            let size = inst.ty.access_size();
+            op(move |cpu, env| {
+                let addr = cpu.gp.get_reg(a).wrapping_add(imm);
+                let val = cpu.load(env.mmu, addr, size)?;
+                cpu.gp.set_reg(dst, val);
+                Ok(Flow::Next)
+            })
+        }
+
+        Ldws | Ldbs => {
+            let (ty, size) = (inst.ty, inst.ty.access_size());
+            op(move |cpu, env| {
+                let val = cpu.load(env.mmu, imm_or!(cpu, a), size)?;
+                cpu.gp.set_reg(dst, ty.extend(val));
+                Ok(Flow::Next)
+            })
+        }
+
+        Ldwso | Ldbso => {
+            let (ty, size) = (inst.ty, inst.ty.access_size());
+            op(move |cpu, env| {
+                let addr = cpu.gp.get_reg(a).wrapping_add(imm);
+                let val = cpu.load(env.mmu, addr, size)?;
+                cpu.gp.set_reg(dst, ty.extend(val));
+                Ok(Flow::Next)
+            })
+        }
+
+        Stro | Strwo | Strbo => {
+            let size = inst.ty.access_size();
+            op(move |cpu, env| {
+                let addr = cpu.gp.get_reg(dst).wrapping_add(imm);
+                cpu.store(env.mmu, addr, cpu.gp.get_reg(a), size)?;
+                Ok(Flow::Next)
+            })
+        }
+
+        Ldr | Ldwr | Ldbr => {
+            let (addr, size) = (pc.wrapping_add(imm), inst.ty.access_size());
+            op(move |cpu, env| {
+                let val = cpu.load(env.mmu, addr, size)?;
+                cpu.gp.set_reg(dst, val);
+                Ok(Flow::Next)
+            })
+        }
+
+        Push => {
+            let op = op(move |cpu, env| {
+                let val = cpu.gp.get_reg(a);
+                let sp = cpu
+                    .gp
+                    .sp
+                    .checked_sub(4)
+                    .ok_or(CpuError::StackOverflow(pc))?;
+                cpu.stack_write(env.mmu, sp, val)?;
+                cpu.gp.sp = sp;
+                Ok(Flow::Next)
+            });
+
+            return (op, 2, false);
+        }
+
+        Pop => {
+            let op = op(move |cpu, env| {
+                let data = cpu.stack_read(env.mmu, cpu.gp.sp)?;
+                cpu.gp.sp = cpu
+                    .gp
+                    .sp
+                    .checked_add(4)
+                    .ok_or(CpuError::StackUnderflow(cpu.gp.sp))?;
+                cpu.gp.set_reg(dst, data);
+                Ok(Flow::Next)
+            });
+
+            return (op, 2, false);
+        }
+
+        Call => {
+            let op = op(move |cpu, env| {
+                let sp = cpu
+                    .gp
+                    .sp
+                    .checked_sub(4)
+                    .ok_or(CpuError::StackOverflow(pc))?;
+                cpu.stack_write(env.mmu, sp, cpu.gp.ra)?;
+                cpu.gp.sp = sp;
+
+                cpu.pc = imm_or!(cpu, a);
+                cpu.gp.ra = next;
+                Ok(Flow::Jump)
+            });
+
+            return (op, 3, true);
+        }
+
+        Callr => {
+            let target = pc.wrapping_add(imm);
+            let op = op(move |cpu, env| {
+                let sp = cpu
+                    .gp
+                    .sp
+                    .checked_sub(4)
+                    .ok_or(CpuError::StackOverflow(pc))?;
+                cpu.stack_write(env.mmu, sp, cpu.gp.ra)?;
+                cpu.gp.sp = sp;
+
+                cpu.pc = target;
+                cpu.gp.ra = next;
+                Ok(Flow::Jump)
+            });
+
+            return (op, 3, true);
+        }
+
+        Ret => {
+            let op = op(move |cpu, env| {
+                let ra = cpu.stack_read(env.mmu, cpu.gp.sp)?;
+                cpu.pc = cpu.gp.ra;
+                cpu.gp.ra = ra;
+                cpu.gp.sp = cpu
+                    .gp
+                    .sp
+                    .checked_add(4)
+                    .ok_or(CpuError::StackUnderflow(cpu.gp.sp))?;
+                Ok(Flow::Jump)
+            });
+
+            return (op, 2, true);
+        }
+
+        Jmp => {
+            let op = op(move |cpu, _| {
+                cpu.pc = imm_or!(cpu, dst);
+                Ok(Flow::Jump)
+            });
+
+            return (op, 1, true);
+        }
+
+        Je => jcc!(|a, b| a == b),
+        Jne => jcc!(|a, b| a != b),
+        Jl => jcc!(|a, b| (a as i32) < b as i32),
+        Jge => jcc!(|a, b| a as i32 >= b as i32),
+        Jle => jcc!(|a, b| a as i32 <= b as i32),
+        Jg => jcc!(|a, b| a as i32 > b as i32),
+        Jb => jcc!(|a, b| a < b),
+        Jae => jcc!(|a, b| a >= b),
+        Jbe => jcc!(|a, b| a <= b),
+        Ja => jcc!(|a, b| a > b),
+
+        Jmpr => {
+            let target = pc.wrapping_add(imm);
+            let op = op(move |cpu, _| {
+                cpu.pc = target;
+                Ok(Flow::Jump)
+            });
+
+            return (op, 1, true);
+        }
+
+        Jer | Jner | Jlr | Jger | Jler | Jgr | Jbr | Jaer | Jber | Jar => {
+            let (ty, target) = (inst.ty, pc.wrapping_add(imm));
+            let op =
+                op(
+                    move |cpu, _| match ty.branch_taken(cpu.gp.get_reg(a), cpu.gp.get_reg(b)) {
+                        true => {
+                            cpu.pc = target;
+                            Ok(Flow::Jump)
+                        }
+
+                        false => Ok(Flow::Next),
+                    },
+                );
+
+            return (op, 1, true);
+        }
+
+        _ => return fallback(inst, pc),
+    };
+
+    (op, 1, false)
+}
+
+/// Run anything else through the interpreter
+fn fallback(inst: Instruction, pc: BitSize) -> (Op, u64, bool) {
+    use InstructionType::*;
+
+    let ends = matches!(inst.ty, Trapret | Iret | Wrcr | Mprot | Tlbflush | Ei | Di);
+
+    let op = op(move |cpu, env| {
+        let (mut stop, mut clk) = (false, 1);
+        cpu.pc = pc;
+        cpu.process(inst, env.mmu, env.dev, env.harts, &mut stop, &mut clk)?;
+        // the block adds the first cycle
+        cpu.clk += clk as u64 - 1;
+
+        Ok(if ends { Flow::Jump } else { Flow::Next })
+    });
+
+    (op, 1, ends)
+}
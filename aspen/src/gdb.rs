@@ -0,0 +1,487 @@
+//! GDB remote serial protocol stub, for `target remote` over TCP.
+//!
+//! Registers are the 32 [`Reg`]s followed by pc, memory is accessed
+//! by physical address. Breakpoints and stepping only apply to the boot hart
+
+use std::{
+    fmt::Write as _,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::{
+    BitSize,
+    cpu::{CpuError, Reg},
+    emulator::{EmuError, Emulator, HaltReason, RunOutcome},
+};
+
+/// Register count gdb sees, pc is the last one
+const REG_COUNT: usize = 33;
+const PC: usize = 32;
+
+/// Largest packet gdb may send us
+const PACKET_SIZE: usize = 0x4000;
+
+/// How often the interrupt watcher checks whether the run ended
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGSEGV: u8 = 11;
+
+/// How a debugging session ended
+#[derive(Debug, PartialEq)]
+pub enum SessionEnd {
+    /// the guest ran `hlt`
+    Exited(RunOutcome),
+    /// gdb detached, the guest should keep running without it
+    Detached,
+    /// gdb killed the guest or hung up
+    Killed,
+}
+
+/// Serve one gdb connection until it ends. Faults are reported to gdb as signals
+pub fn serve(emu: &mut Emulator, stream: TcpStream) -> io::Result<SessionEnd> {
+    let mut conn = Conn { stream, ack: true };
+
+    let mut last_stop = format!("S{SIGTRAP:02x}");
+
+    loop {
+        let Some(packet) = conn.recv()? else {
+            return Ok(SessionEnd::Killed);
+        };
+
+        debug!(target: "aspen::gdb", "<- {packet}");
+
+        let reply = match packet.as_str() {
+            "?" => last_stop.clone(),
+            "g" => read_regs(emu),
+            "k" => return Ok(SessionEnd::Killed),
+            "vKill;1" => {
+                conn.send("OK")?;
+                return Ok(SessionEnd::Killed);
+            }
+            "QStartNoAckMode" => {
+                conn.send("OK")?;
+                conn.ack = false;
+                continue;
+            }
+            "qAttached" => "1".into(),
+            "qC" => "QC1".into(),
+            "qfThreadInfo" => "m1".into(),
+            "qsThreadInfo" => "l".into(),
+
+            p if p.starts_with('D') => {
+                conn.send("OK")?;
+                return Ok(SessionEnd::Detached);
+            }
+
+            p if p.starts_with("qSupported") => {
+                format!(
+                    "PacketSize={PACKET_SIZE:x};qXfer:features:read+;swbreak+;QStartNoAckMode+;\
+                     ReverseStep+;ReverseContinue+"
+                )
+            }
+
+            p if p.starts_with("qXfer:features:read:") => {
+                read_features(&p["qXfer:features:read:".len()..]).unwrap_or_else(|| "E00".into())
+            }
+
+            p if p.starts_with('H') || p.starts_with('T') => "OK".into(),
+
+            p if p.starts_with('G') => write_regs(emu, &p[1..]).unwrap_or_else(|| "E00".into()),
+            p if p.starts_with('p') => read_reg(emu, &p[1..]).unwrap_or_else(|| "E00".into()),
+            p if p.starts_with('P') => write_reg(emu, &p[1..]).unwrap_or_else(|| "E00".into()),
+            p if p.starts_with('m') => read_mem(emu, &p[1..]).unwrap_or_else(|| "E01".into()),
+            p if p.starts_with('M') => write_mem(emu, &p[1..]).unwrap_or_else(|| "E01".into()),
+
+            p if p.starts_with("Z0,") || p.starts_with("z0,") => match breakpoint_addr(&p[3..]) {
+                Some(addr) if p.starts_with('Z') => {
+                    emu.breakpoints.insert(addr);
+                    "OK".into()
+                }
+                Some(addr) => {
+                    emu.breakpoints.remove(&addr);
+                    "OK".into()
+                }
+                None => "E00".into(),
+            },
+
+            "bs" | "bc" if !emu.is_recording() => "E01".into(),
+            "bs" | "bc" => {
+                let res = match packet.as_str() {
+                    "bs" => emu.step_back(),
+                    _ => emu.reverse_continue(),
+                };
+
+                last_stop = stop_reply(&res);
+                last_stop.clone()
+            }
+
+            p if p.starts_with('s') || p.starts_with('c') => {
+                if p.len() > 1 {
+                    let Some(addr) = parse_hex(&p[1..]) else {
+                        conn.send("E00")?;
+                        continue;
+                    };
+
+                    emu.cpu.pc = addr;
+                }
+
+                let res = match p.starts_with('s') {
+                    true => emu.step(),
+                    false => resume(emu, &conn.stream)?,
+                };
+
+                if let Ok(
+                    outcome @ RunOutcome {
+                        reason: HaltReason::Hlt,
+                        ..
+                    },
+                ) = res
+                {
+                    conn.send(&stop_reply(&res))?;
+                    return Ok(SessionEnd::Exited(outcome));
+                }
+
+                last_stop = stop_reply(&res);
+                last_stop.clone()
+            }
+
+            // unsupported
+            _ => String::new(),
+        };
+
+        debug!(target: "aspen::gdb", "-> {reply}");
+        conn.send(&reply)?;
+    }
+}
+
+/// Run until the guest stops, or gdb sends an interrupt
+fn resume(emu: &mut Emulator, stream: &TcpStream) -> io::Result<Result<RunOutcome, EmuError>> {
+    let mut watcher = stream.try_clone()?;
+    watcher.set_read_timeout(Some(WATCH_INTERVAL))?;
+
+    let interrupt = emu.interrupter();
+    let done = AtomicBool::new(false);
+
+    let res = thread::scope(|s| {
+        s.spawn(|| {
+            let mut byte = [0];
+
+            while !done.load(Ordering::Relaxed) {
+                match watcher.read(&mut byte) {
+                    // hung up, the next recv sees it too
+                    Ok(0) => {
+                        interrupt.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    Ok(_) if byte[0] == 0x03 => {
+                        interrupt.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    Ok(_) => (),
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let res = emu.run();
+        done.store(true, Ordering::Relaxed);
+        res
+    });
+
+    // an interrupt that came in after the run ended
+    interrupt.store(false, Ordering::Relaxed);
+    stream.set_read_timeout(None)?;
+
+    Ok(res)
+}
+
+fn stop_reply(res: &Result<RunOutcome, EmuError>) -> String {
+    match res {
+        Ok(outcome) => match outcome.reason {
+            HaltReason::Hlt => format!("W{:02x}", outcome.exit_code as u8),
+            HaltReason::Breakpoint => format!("T{SIGTRAP:02x}swbreak:;"),
+            HaltReason::Step => format!("S{SIGTRAP:02x}"),
+            HaltReason::Interrupted => format!("S{SIGINT:02x}"),
+            HaltReason::HistoryStart => format!("T{SIGTRAP:02x}replaylog:begin;"),
+        },
+
+        Err(e) => {
+            warn!(target: "aspen::gdb", "{e}");
+            format!("S{:02x}", signal(e))
+        }
+    }
+}
+
+fn signal(e: &EmuError) -> u8 {
+    match e {
+        EmuError::Mem(_) | EmuError::PageFault(..) | EmuError::Cpu(CpuError::Mem(_)) => SIGSEGV,
+        EmuError::Inst(_)
+        | EmuError::Cpu(CpuError::UnsupportedInst(_) | CpuError::Privileged(_)) => SIGILL,
+        EmuError::Hart(_, e) => signal(e),
+        _ => SIGABRT,
+    }
+}
+
+/// Register names come from [`Reg`], so they match the assembler
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <feature name=\"org.toyemu.aspen.core\">\n",
+    );
+
+    for n in 0..PC as u8 {
+        let reg = Reg::from(n);
+        let ty = match reg {
+            Reg::Sp => "data_ptr",
+            _ => "uint32",
+        };
+
+        writeln!(
+            xml,
+            "<reg name=\"{reg}\" bitsize=\"32\" type=\"{ty}\" regnum=\"{n}\"/>"
+        )
+        .unwrap();
+    }
+
+    writeln!(
+        xml,
+        "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{PC}\"/>"
+    )
+    .unwrap();
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+/// `target.xml:off,len`
+fn read_features(args: &str) -> Option<String> {
+    let (annex, range) = args.split_once(':')?;
+    if annex != "target.xml" {
+        return None;
+    }
+
+    let (off, len) = range.split_once(',')?;
+    let (off, len) = (parse_hex(off)? as usize, parse_hex(len)? as usize);
+
+    let xml = target_xml();
+    let chunk = xml.get(off.min(xml.len())..(off + len).min(xml.len()))?;
+
+    let more = match off + len < xml.len() {
+        true => 'm',
+        false => 'l',
+    };
+
+    Some(format!("{more}{chunk}"))
+}
+
+fn get_reg(emu: &Emulator, n: usize) -> BitSize {
+    match n {
+        PC => emu.cpu.pc,
+        n => emu.cpu.gp.get_reg(Reg::from(n as u8)),
+    }
+}
+
+fn set_reg(emu: &mut Emulator, n: usize, val: BitSize) {
+    match n {
+        PC => emu.cpu.pc = val,
+        n => emu.cpu.gp.set_reg(Reg::from(n as u8), val),
+    }
+}
+
+fn read_regs(emu: &Emulator) -> String {
+    let mut out = String::with_capacity(REG_COUNT * 8);
+    for n in 0..REG_COUNT {
+        push_hex(&mut out, &get_reg(emu, n).to_le_bytes());
+    }
+
+    out
+}
+
+fn write_regs(emu: &mut Emulator, hex: &str) -> Option<String> {
+    let bytes = parse_bytes(hex)?;
+    if bytes.len() != REG_COUNT * 4 {
+        return None;
+    }
+
+    for (n, val) in bytes.chunks_exact(4).enumerate() {
+        set_reg(emu, n, BitSize::from_le_bytes(val.try_into().unwrap()));
+    }
+
+    Some("OK".into())
+}
+
+/// `n`
+fn read_reg(emu: &Emulator, args: &str) -> Option<String> {
+    let n = parse_hex(args)? as usize;
+    if n >= REG_COUNT {
+        return None;
+    }
+
+    let mut out = String::new();
+    push_hex(&mut out, &get_reg(emu, n).to_le_bytes());
+    Some(out)
+}
+
+/// `n=val`
+fn write_reg(emu: &mut Emulator, args: &str) -> Option<String> {
+    let (n, val) = args.split_once('=')?;
+    let n = parse_hex(n)? as usize;
+    let val = parse_bytes(val)?;
+
+    if n >= REG_COUNT {
+        return None;
+    }
+
+    set_reg(emu, n, BitSize::from_le_bytes(val.try_into().ok()?));
+    Some("OK".into())
+}
+
+/// `addr,len`
+fn read_mem(emu: &Emulator, args: &str) -> Option<String> {
+    let (addr, len) = args.split_once(',')?;
+    let mut buf = vec![0; (parse_hex(len)? as usize).min(PACKET_SIZE / 2)];
+    emu.mmu.memcpy(parse_hex(addr)?, &mut buf).ok()?;
+
+    let mut out = String::with_capacity(buf.len() * 2);
+    push_hex(&mut out, &buf);
+    Some(out)
+}
+
+/// `addr,len:data`
+fn write_mem(emu: &Emulator, args: &str) -> Option<String> {
+    let (range, data) = args.split_once(':')?;
+    let (addr, len) = range.split_once(',')?;
+
+    let data = parse_bytes(data)?;
+    if data.len() != parse_hex(len)? as usize {
+        return None;
+    }
+
+    emu.mmu.memwrite(parse_hex(addr)?, &data).ok()?;
+    Some("OK".into())
+}
+
+/// `addr,kind`
+fn breakpoint_addr(args: &str) -> Option<BitSize> {
+    let (addr, _kind) = args.split_once(',')?;
+    parse_hex(addr)
+}
+
+fn parse_hex(s: &str) -> Option<BitSize> {
+    BitSize::from_str_radix(s, 16).ok()
+}
+
+fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn push_hex(out: &mut String, bytes: &[u8]) {
+    for b in bytes {
+        write!(out, "{b:02x}").unwrap();
+    }
+}
+
+/// Packet framing, `$data#checksum`
+struct Conn {
+    stream: TcpStream,
+    /// gdb expects `+` after every packet, until no-ack mode
+    ack: bool,
+}
+
+impl Conn {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Next packet, None once gdb hangs up
+    fn recv(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // acks, and interrupts while already stopped
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => (),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut sum = [0; 2];
+            for b in &mut sum {
+                let Some(byte) = self.read_byte()? else {
+                    return Ok(None);
+                };
+
+                *b = byte;
+            }
+
+            if !self.ack {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+
+            if expected == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${data}#{:02x}", checksum(data.as_bytes()));
+
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+
+            if !self.ack {
+                return Ok(());
+            }
+
+            match self.read_byte()? {
+                Some(b'-') => (),
+                Some(_) => return Ok(()),
+                None => return Err(ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
@@ -16,11 +16,14 @@ use std::fmt::Display;
 use strum::Display;
 use yansi::Paint as _;
 
-use crate::{BitSize, cpu::Reg};
+use crate::{
+    BitSize,
+    cpu::{Cr, Reg},
+};
 
 #[derive(Debug, Copy, Clone, thiserror::Error, PartialEq)]
 pub enum InstError {
-    #[error("Unknown opcode: {0} {0}")]
+    #[error("Unknown opcode: {0} {1}")]
     UnknownInstruction(u8, u8),
 }
 
@@ -99,7 +102,7 @@ impl Display for Instruction {
         for args in args.iter() {
             #[rustfmt::skip]
             let args_has_imm = args.iter().any(|i| {
-                matches!(i, RegOpts::C | RegOpts::D | RegOpts::E | RegOpts::F | RegOpts::Imm)
+                matches!(i, RegOpts::C | RegOpts::D | RegOpts::E | RegOpts::F | RegOpts::Imm | RegOpts::FImm | RegOpts::Offset | RegOpts::PcRel)
             });
 
             if (self.has_imm && !args_has_imm) || (!self.has_imm && args_has_imm) {
@@ -108,47 +111,36 @@ impl Display for Instruction {
 
             let mut offset = 0;
             let mut use_brackets = false;
+            let mut use_cr = false;
+            let mut use_offset = false;
             for (i, arg) in args.iter().enumerate() {
                 let reg = match arg {
                     RegOpts::Dst => self.dst,
                     RegOpts::A => self.a,
                     RegOpts::B => self.b,
 
-                    RegOpts::C => Reg::from(self.imm >> 24),
+                    RegOpts::C => self.c,
+                    RegOpts::D => self.d,
+                    RegOpts::E => self.e,
+                    RegOpts::F => self.f,
 
-                    RegOpts::D => Reg::from(self.imm >> 16),
+                    RegOpts::Imm | RegOpts::FImm | RegOpts::PcRel => {
+                        let imm = match arg {
+                            RegOpts::FImm => format!("{:?}", f32::from_bits(self.imm)),
+                            RegOpts::PcRel => format!("pc {}", SignedImm(self.imm)),
+                            _ => format!("0x{:0>8x}", self.imm),
+                        };
 
-                    RegOpts::E => Reg::from(self.imm >> 8),
-
-                    RegOpts::F => Reg::from(self.imm),
-
-                    RegOpts::Imm => {
                         if use_brackets {
                             if i.saturating_sub(offset) > 0 {
-                                write!(
-                                    f,
-                                    ", [{}]",
-                                    format_args!("0x{:0>8x}", self.imm).bright_yellow()
-                                )?;
+                                write!(f, ", [{}]", imm.bright_yellow())?;
                             } else {
-                                write!(
-                                    f,
-                                    " [{}]",
-                                    format_args!("0x{:0>8x}", self.imm).bright_yellow()
-                                )?;
+                                write!(f, " [{}]", imm.bright_yellow())?;
                             }
                         } else if i.saturating_sub(offset) > 0 {
-                            write!(
-                                f,
-                                ", {}",
-                                format_args!("0x{:0>8x}", self.imm).bright_yellow()
-                            )?;
+                            write!(f, ", {}", imm.bright_yellow())?;
                         } else {
-                            write!(
-                                f,
-                                " {}",
-                                format_args!("0x{:0>8x}", self.imm).bright_yellow()
-                            )?;
+                            write!(f, " {}", imm.bright_yellow())?;
                         }
 
                         use_brackets = false;
@@ -160,8 +152,45 @@ impl Display for Instruction {
                         use_brackets = true;
                         continue;
                     }
+
+                    RegOpts::Cr => {
+                        offset += 1;
+                        use_cr = true;
+                        continue;
+                    }
+
+                    RegOpts::Offset => {
+                        offset += 1;
+                        use_offset = true;
+                        continue;
+                    }
                 };
 
+                let cr;
+                let reg: &dyn Display = if use_cr {
+                    use_cr = false;
+                    cr = CrName(reg as u8);
+                    &cr
+                } else {
+                    &reg
+                };
+
+                if use_offset {
+                    use_offset = false;
+                    let sep = if i.saturating_sub(offset) > 0 {
+                        ","
+                    } else {
+                        ""
+                    };
+                    write!(
+                        f,
+                        "{sep} [{} {}]",
+                        reg.bright_blue(),
+                        SignedImm(self.imm).bright_yellow()
+                    )?;
+                    continue;
+                }
+
                 if use_brackets {
                     if i.saturating_sub(offset) > 0 {
                         write!(f, ", [{}]", reg.bright_blue())?;
@@ -182,6 +211,30 @@ impl Display for Instruction {
     }
 }
 
+/// Displays an offset as `+ 0x10` or `- 0x10`
+struct SignedImm(BitSize);
+
+impl Display for SignedImm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 as i32 {
+            n if n < 0 => write!(f, "- 0x{:x}", n.unsigned_abs()),
+            n => write!(f, "+ 0x{n:x}"),
+        }
+    }
+}
+
+/// Displays a control register index, even an unknown one
+struct CrName(u8);
+
+impl Display for CrName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match Cr::try_from(self.0) {
+            Ok(cr) => write!(f, "{cr}"),
+            Err(_) => write!(f, "cr{}", self.0),
+        }
+    }
+}
+
 #[expect(dead_code)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 enum RegOpts {
@@ -193,8 +246,71 @@ enum RegOpts {
     E,
     F,
     Imm,
+    // Imm holding f32 bits
+    FImm,
     // Special opt which places brackets around next arg
     Brackets,
+    // Special opt which shows next arg as a control register
+    Cr,
+    // Special opt which shows next arg plus the imm, in brackets
+    Offset,
+    // Imm added to the pc of the instruction
+    PcRel,
+}
+
+impl InstructionType {
+    /// Faults when executed in user mode, except `rdcr` of `hartid`
+    pub fn is_privileged(&self) -> bool {
+        use InstructionType::*;
+
+        matches!(
+            self,
+            Rdcr | Wrcr | Trapret | Ei | Di | Iret | Gfx | Mprot | Tlbflush | Hstart
+        )
+    }
+
+    /// Bytes accessed by a load or store
+    pub fn access_size(&self) -> usize {
+        use InstructionType::*;
+
+        match self {
+            Ldw | Ldwo | Ldwr | Ldws | Ldwso | Strw | Strwo | Pldw | Pstrw => 2,
+            Ldb | Ldbo | Ldbr | Ldbs | Ldbso | Strb | Strbo | Pldb | Pstrb => 1,
+            _ => 4,
+        }
+    }
+
+    /// Sign-extend a value loaded by this instruction
+    pub fn extend(&self, val: BitSize) -> BitSize {
+        use InstructionType::*;
+
+        match self {
+            Ldws | Ldwso => val as u16 as i16 as BitSize,
+            Ldbs | Ldbso => val as u8 as i8 as BitSize,
+            _ => val,
+        }
+    }
+
+    /// Whether a pc-relative conditional jump is taken
+    pub fn branch_taken(&self, a: BitSize, b: BitSize) -> bool {
+        use InstructionType::*;
+
+        let (ia, ib) = (a as i32, b as i32);
+
+        match self {
+            Jer => a == b,
+            Jner => a != b,
+            Jlr => ia < ib,
+            Jger => ia >= ib,
+            Jler => ia <= ib,
+            Jgr => ia > ib,
+            Jbr => a < b,
+            Jaer => a >= b,
+            Jber => a <= b,
+            Jar => a > b,
+            _ => false,
+        }
+    }
 }
 
 macro_rules! impl_inst {
@@ -241,7 +357,7 @@ impl_inst! {
     // (mode, opcode)
 
     (0, 0x00) => Nop
-    (0, 0x01) => Hlt
+    (0, 0x01) => Hlt [A]
     (0, 0x02) => Pr [A, B]
     (0, 0x03) => Epr [A, B]
     (0, 0x04) => Tme [A, B, C, D]
@@ -254,6 +370,26 @@ impl_inst! {
     (0, 0x0b) => Dbg [A]
     (0, 0x0c) => Smem [Brackets, Dst, A, B] [Brackets, Dst, A, Imm]
 
+    // Control registers / traps
+    (0, 0x0d) => Rdcr [Dst, Cr, A]
+    (0, 0x0e) => Wrcr [Cr, Dst, A] [Cr, Dst, Imm]
+    (0, 0x0f) => Trapret
+
+    // Interrupts
+    (0, 0x10) => Ei
+    (0, 0x11) => Di
+    (0, 0x12) => Iret
+
+    // Memory protection
+    (0, 0x13) => Mprot [A, B, Dst] [A, B, Imm]
+    (0, 0x14) => Tlbflush
+
+    // Harts
+    (0, 0x15) => Hstart [A, B, Dst] [A, B, Imm]
+
+    // Host calls
+    (0, 0x16) => Ecall
+
     // Memory
     (0, 0x20) => Ld [Dst, Brackets, A] [Dst, Brackets, Imm]
     #[strum(to_string = "ld.w")]
@@ -279,6 +415,42 @@ impl_inst! {
     #[strum(to_string = "pstr.b")]
     (0, 0x2b) => Pstrb [Brackets, Dst, A] [Brackets, Dst, Imm]
 
+    // Atomics
+    (0, 0x2c) => Cas [Dst, Brackets, A, B]
+    (0, 0x2d) => Amoadd [Dst, Brackets, A, B] [Dst, Brackets, A, Imm]
+    (0, 0x2e) => Amoswap [Dst, Brackets, A, B] [Dst, Brackets, A, Imm]
+    (0, 0x2f) => Fence
+
+    // Memory, base register + offset, or pc + offset
+    #[strum(to_string = "ld")]
+    (0, 0x30) => Ldo [Dst, Offset, A]
+    #[strum(to_string = "ld.w")]
+    (0, 0x31) => Ldwo [Dst, Offset, A]
+    #[strum(to_string = "ld.b")]
+    (0, 0x32) => Ldbo [Dst, Offset, A]
+    #[strum(to_string = "str")]
+    (0, 0x33) => Stro [Offset, Dst, A]
+    #[strum(to_string = "str.w")]
+    (0, 0x34) => Strwo [Offset, Dst, A]
+    #[strum(to_string = "str.b")]
+    (0, 0x35) => Strbo [Offset, Dst, A]
+    #[strum(to_string = "ld")]
+    (0, 0x36) => Ldr [Dst, Brackets, PcRel]
+    #[strum(to_string = "ld.w")]
+    (0, 0x37) => Ldwr [Dst, Brackets, PcRel]
+    #[strum(to_string = "ld.b")]
+    (0, 0x38) => Ldbr [Dst, Brackets, PcRel]
+
+    // Memory, sign-extending
+    #[strum(to_string = "ld.ws")]
+    (0, 0x39) => Ldws [Dst, Brackets, A] [Dst, Brackets, Imm]
+    #[strum(to_string = "ld.bs")]
+    (0, 0x3a) => Ldbs [Dst, Brackets, A] [Dst, Brackets, Imm]
+    #[strum(to_string = "ld.ws")]
+    (0, 0x3b) => Ldwso [Dst, Offset, A]
+    #[strum(to_string = "ld.bs")]
+    (0, 0x3c) => Ldbso [Dst, Offset, A]
+
     // Math
     (1, 0x00) => Nand [Dst, A, B] [Dst, A, Imm]
     (1, 0x01) => Or [Dst, A, B] [Dst, A, Imm]
@@ -306,6 +478,39 @@ impl_inst! {
     (1, 0x17) => Sge [Dst, A, B] [Dst, A, Imm]
     (1, 0x18) => Asr [Dst, A, B] [Dst, A, Imm]
 
+    // Float (f32 bit patterns in the integer registers)
+    (1, 0x19) => Fadd [Dst, A, B] [Dst, A, FImm]
+    (1, 0x1a) => Fsub [Dst, A, B] [Dst, A, FImm]
+    (1, 0x1b) => Fmul [Dst, A, B] [Dst, A, FImm]
+    (1, 0x1c) => Fdiv [Dst, A, B] [Dst, A, FImm]
+    (1, 0x1d) => Fsqrt [Dst, A]
+    (1, 0x1e) => Fse [Dst, A, B] [Dst, A, FImm]
+    (1, 0x1f) => Fsl [Dst, A, B] [Dst, A, FImm]
+    (1, 0x20) => Fsle [Dst, A, B] [Dst, A, FImm]
+    (1, 0x21) => Itof [Dst, A] [Dst, Imm]
+    (1, 0x22) => Utof [Dst, A] [Dst, Imm]
+    (1, 0x23) => Ftoi [Dst, A]
+    (1, 0x24) => Ftou [Dst, A]
+
+    // Bit manipulation
+    (1, 0x25) => Clz [Dst, A]
+    (1, 0x26) => Ctz [Dst, A]
+    (1, 0x27) => Popcnt [Dst, A]
+    (1, 0x28) => Rol [Dst, A, B] [Dst, A, Imm]
+    (1, 0x29) => Ror [Dst, A, B] [Dst, A, Imm]
+    (1, 0x2a) => Bswap [Dst, A]
+    (1, 0x2b) => Min [Dst, A, B] [Dst, A, Imm]
+    (1, 0x2c) => Max [Dst, A, B] [Dst, A, Imm]
+    (1, 0x2d) => Minu [Dst, A, B] [Dst, A, Imm]
+    (1, 0x2e) => Maxu [Dst, A, B] [Dst, A, Imm]
+    (1, 0x2f) => Csel [Dst, A, B] [Dst, A, Imm]
+
+    // Wide arithmetic
+    (1, 0x30) => Mulh [Dst, A, B] [Dst, A, Imm]
+    (1, 0x31) => Mulhu [Dst, A, B] [Dst, A, Imm]
+    (1, 0x32) => Addc [Dst, A, B, C]
+    (1, 0x33) => Subb [Dst, A, B, C]
+
     // Cond
     (2, 0x00) => Jmp [Dst] [Imm]
     (2, 0x01) => Je [A, B, Dst] [A, B, Imm]
@@ -319,9 +524,35 @@ impl_inst! {
     (2, 0x09) => Jbe [A, B, Dst] [A, B, Imm]
     (2, 0x0a) => Ja [A, B, Dst] [A, B, Imm]
 
+    // Cond, pc + offset
+    #[strum(to_string = "jmp")]
+    (2, 0x0b) => Jmpr [PcRel]
+    #[strum(to_string = "je")]
+    (2, 0x0c) => Jer [A, B, PcRel]
+    #[strum(to_string = "jne")]
+    (2, 0x0d) => Jner [A, B, PcRel]
+    #[strum(to_string = "jl")]
+    (2, 0x0e) => Jlr [A, B, PcRel]
+    #[strum(to_string = "jge")]
+    (2, 0x0f) => Jger [A, B, PcRel]
+    #[strum(to_string = "jle")]
+    (2, 0x10) => Jler [A, B, PcRel]
+    #[strum(to_string = "jg")]
+    (2, 0x11) => Jgr [A, B, PcRel]
+    #[strum(to_string = "jb")]
+    (2, 0x12) => Jbr [A, B, PcRel]
+    #[strum(to_string = "jae")]
+    (2, 0x13) => Jaer [A, B, PcRel]
+    #[strum(to_string = "jbe")]
+    (2, 0x14) => Jber [A, B, PcRel]
+    #[strum(to_string = "ja")]
+    (2, 0x15) => Jar [A, B, PcRel]
+
     // Stack
     (3, 0x00) => Push [A]
     (3, 0x01) => Pop [Dst]
     (3, 0x02) => Call [A] [Imm]
     (3, 0x03) => Ret
+    #[strum(to_string = "call")]
+    (3, 0x04) => Callr [PcRel]
 }
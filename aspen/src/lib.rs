@@ -1,5 +1,11 @@
+#[cfg(all(feature = "jit", not(all(target_os = "linux", target_arch = "x86_64"))))]
+compile_error!("the jit feature is only supported on linux x86-64");
+
 pub mod cpu;
+pub mod debugger;
+pub mod dev;
 pub mod emulator;
+pub mod gdb;
 pub mod instruction;
 pub mod mmu;
 
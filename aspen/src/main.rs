@@ -1,28 +1,213 @@
-use std::{env, error::Error, fs};
+use std::{
+    env,
+    error::Error,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    net::TcpListener,
+    process::ExitCode,
+};
 
 use env_logger::Env;
 
-use aspen::emulator::Emulator;
+use aspen::{
+    debugger::{Debugger, Symbols},
+    dev::{
+        Devices,
+        storage::Storage,
+        uart::{self, Host, Uart},
+    },
+    emulator::{Emulator, Engine, snapshot::Snapshot},
+    gdb::{self, SessionEnd},
+};
 
 pub type BitSize = u32;
 
-fn main() -> Result<(), Box<dyn Error>> {
+const USAGE: &str = "aspen <file> [--disk <image>] [--disk-ro <image>] [--harts <count>] \
+                     [--engine <interpreter|threaded|jit>] [--uart <stdio|pty|file>] \
+                     [--gdb <port>] [--debug] [--symbols <file>] [--snapshot <file>] \
+                     [--restore <file>] [--record]
+
+--restore starts from a snapshot instead of <file>, --snapshot saves one when the run ends.
+--record keeps recent history for going back in --debug or --gdb, running only the boot hart
+
+exits with the low 8 bits of the guest's `hlt` operand, 70 if the emulator faulted \
+                     and 2 for bad arguments";
+
+/// Exit status when the guest faults instead of halting (sysexits EX_SOFTWARE)
+const EXIT_FAULT: u8 = 70;
+
+fn usage() -> ExitCode {
+    eprintln!("{USAGE}");
+    ExitCode::from(2)
+}
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
     let env = Env::default().filter_or("EMU_LOG", "warn");
     env_logger::Builder::from_env(env)
         .format_timestamp(None)
         .init();
 
-    let Some(file) = env::args().nth(1) else {
-        eprintln!("aspen <file>");
-        return Ok(());
+    let mut file = None;
+    let mut dev = Devices::default();
+    let mut harts = 1;
+    let mut engine = Engine::default();
+    let mut uart = None;
+    let mut gdb_port = None;
+    let mut debug = false;
+    let mut symbols = None;
+    let mut snapshot = None;
+    let mut restore = None;
+    let mut record = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--disk" | "--disk-ro" => {
+                let Some(path) = args.next() else {
+                    return Ok(usage());
+                };
+
+                dev.storage = Some(Storage::open(path, arg == "--disk-ro")?);
+            }
+
+            "--harts" => {
+                let Some(count) = args.next().and_then(|n| n.parse().ok()) else {
+                    return Ok(usage());
+                };
+
+                harts = count;
+            }
+
+            "--engine" => {
+                engine = match args.next().as_deref() {
+                    Some("interpreter") => Engine::Interpreter,
+                    Some("threaded") => Engine::Threaded,
+                    #[cfg(feature = "jit")]
+                    Some("jit") => Engine::Jit,
+                    _ => {
+                        return Ok(usage());
+                    }
+                };
+            }
+
+            "--uart" => {
+                uart = match args.next().as_deref() {
+                    Some("stdio") => Some(Host::Stdio),
+                    #[cfg(target_os = "linux")]
+                    Some("pty") => Some(Host::pty()?),
+                    Some(path) => Some(Host::File(File::create(path)?)),
+                    None => {
+                        return Ok(usage());
+                    }
+                };
+            }
+
+            "--gdb" => {
+                let Some(port) = args.next().and_then(|p| p.parse::<u16>().ok()) else {
+                    return Ok(usage());
+                };
+
+                gdb_port = Some(port);
+            }
+
+            "--debug" => debug = true,
+
+            "--record" => record = true,
+
+            "--symbols" => {
+                let Some(path) = args.next() else {
+                    return Ok(usage());
+                };
+
+                symbols = Some(Symbols::parse(&fs::read_to_string(path)?));
+            }
+
+            "--snapshot" | "--restore" => {
+                let Some(path) = args.next() else {
+                    return Ok(usage());
+                };
+
+                match arg.as_str() {
+                    "--snapshot" => snapshot = Some(path),
+                    _ => restore = Some(Snapshot::load(BufReader::new(File::open(path)?))?),
+                }
+            }
+
+            _ => file = Some(arg),
+        }
+    }
+
+    if file.is_none() && restore.is_none() {
+        return Ok(usage());
+    }
+
+    if debug && gdb_port.is_some() {
+        return Ok(usage());
+    }
+
+    let program = file.map(fs::read).transpose()?.unwrap_or_default();
+    let mut emu = Emulator::with_engine(&program, dev, engine)?;
+    emu.set_harts(harts);
+
+    if let Some(restore) = &restore {
+        emu.restore(restore)?;
+    }
+
+    if record {
+        emu.record()?;
+    }
+    // headless programs read keys from the terminal, unless the uart or debugger has it
+    emu.dev
+        .kbd
+        .set_stdin(!debug && !matches!(uart, Some(Host::Stdio)));
+
+    if let Some(host) = uart {
+        #[cfg(target_os = "linux")]
+        if let Host::Pty(pty) = &host {
+            eprintln!("uart: {}", pty.path().display());
+        }
+
+        emu.add_device(uart::BASE..uart::BASE + uart::SIZE, Uart::new(host))?;
+    }
+
+    let res = match gdb_port {
+        Some(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("gdb: waiting for a connection on 127.0.0.1:{port}");
+            let (stream, _) = listener.accept()?;
+
+            match gdb::serve(&mut emu, stream)? {
+                SessionEnd::Exited(outcome) => Some(Ok(outcome)),
+                SessionEnd::Detached => Some(emu.run()),
+                SessionEnd::Killed => None,
+            }
+        }
+
+        None if debug => {
+            let debugger = Debugger::new(&mut emu, symbols.unwrap_or_default());
+            debugger.repl(io::stdin().lock(), io::stdout())?
+        }
+
+        None => Some(emu.run()),
     };
 
-    let program = fs::read(file)?;
-    let mut emu = Emulator::new(&program)?;
+    let code = match res {
+        Some(Ok(outcome)) => outcome.exit_code as u8,
+        // killed or quit from the debugger
+        None => 0,
+        Some(Err(e)) => {
+            eprintln!("{e}");
+            EXIT_FAULT
+        }
+    };
+
+    if let Some(path) = snapshot {
+        emu.snapshot()?.save(BufWriter::new(File::create(path)?))?;
+    }
 
-    if let Err(e) = emu.run() {
-        eprintln!("{e}");
+    if let Some(storage) = &emu.dev.storage {
+        storage.flush()?;
     }
 
-    Ok(())
+    Ok(ExitCode::from(code))
 }
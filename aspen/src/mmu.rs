@@ -1,17 +1,20 @@
 mod address_range;
+mod bus;
 mod memory;
 
-use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
 
 use enumflags2::{BitFlag, BitFlags, bitflags};
+use sayuri::sync::Mutex;
 
 use crate::{
     BitSize,
-    mmu::{
-        address_range::AddressRange,
-        memory::{FromBytes, ToBytes},
-    },
+    mmu::memory::{FromBytes, ToBytes},
 };
+#[doc(hidden)]
+pub use address_range::AddressRange;
+use bus::Bus;
+pub use bus::{DeviceRef, Mapping};
 use memory::Memory;
 
 pub type Protection = BitFlags<Prot>;
@@ -27,12 +30,18 @@ macro_rules! page_idx {
     ($addr:ident) => {{ ($addr / PAGE_SIZE as u32) as usize }};
 }
 
-#[derive(Debug, Clone, thiserror::Error, PartialEq)]
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum MemError {
-    #[error("Page fault: {0} access denied")]
-    PageFault(Protection),
+    #[error("Page fault: {0} access denied @ 0x{1:08x}")]
+    PageFault(Protection, BitSize),
+    #[error("Misaligned atomic access @ 0x{0:08x}")]
+    Misaligned(BitSize),
+    #[error("Page not mapped @ 0x{0:08x}")]
+    NotMapped(BitSize),
     #[error("Overflow occurred")]
     Overflow,
+    #[error("Device already mapped @ 0x{0:08x}")]
+    DeviceOverlap(BitSize),
     #[cfg(windows)]
     #[error("Winapi Error: {0}")]
     WinApi(#[from] windows::core::Error),
@@ -44,20 +53,52 @@ pub enum MemError {
     Io(std::sync::Arc<std::io::Error>),
 }
 
+// io::Error isn't PartialEq, so this can't be derived
+impl PartialEq for MemError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::PageFault(a, x), Self::PageFault(b, y)) => a == b && x == y,
+            (Self::NotMapped(a), Self::NotMapped(b)) => a == b,
+            (Self::Misaligned(a), Self::Misaligned(b)) => a == b,
+            (Self::Overflow, Self::Overflow) => true,
+            (Self::DeviceOverlap(a), Self::DeviceOverlap(b)) => a == b,
+            #[cfg(windows)]
+            (Self::WinApi(a), Self::WinApi(b)) => a == b,
+            #[cfg(windows)]
+            (Self::Alloc(a), Self::Alloc(b)) => a == b,
+            #[cfg(unix)]
+            (Self::Io(a), Self::Io(b)) => {
+                a.kind() == b.kind() && a.raw_os_error() == b.raw_os_error()
+            }
+            _ => false,
+        }
+    }
+}
+
 /// Protection state of page
 #[rustfmt::skip]
 #[bitflags]
 #[repr(u8)]
 #[derive(Copy, Clone, Debug)]
 pub enum Prot {
-    Read    = 0b001,
-    Write   = 0b010,
-    Execute = 0b100,
+    Read    = 0b0001,
+    Write   = 0b0010,
+    Execute = 0b0100,
+    /// Accessible from user mode
+    User    = 0b1000,
 }
 
 #[derive(Default, Debug)]
 struct Page {
     prot: AtomicU8,
+    /// instructions were decoded from this page, writes must bump generation
+    code: AtomicBool,
+    /// bumped whenever decoded instructions from this page go stale
+    generation: AtomicU32,
+    /// a device is mapped somewhere in this page
+    io: AtomicBool,
+    /// written since memory was last zeroed
+    dirty: AtomicBool,
 }
 
 impl Page {
@@ -69,6 +110,20 @@ impl Page {
 
     fn set_prot(&self, prot: Protection) {
         self.prot.store(prot.bits(), Ordering::Relaxed);
+        // cached decodes remember the protection too
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn written(&self) {
+        // loads are cheaper than contended stores
+        if !self.dirty.load(Ordering::Relaxed) {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+
+        if self.code.load(Ordering::SeqCst) {
+            self.code.store(false, Ordering::SeqCst);
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
     }
 }
 
@@ -76,6 +131,10 @@ impl Page {
 pub struct Mmu {
     pages: Vec<Page>,
     mem: Memory,
+    bus: Bus,
+    /// see [`Mmu::log_writes`]
+    logging: AtomicBool,
+    writes: Mutex<Vec<(BitSize, BitSize)>>,
 }
 
 impl Mmu {
@@ -88,6 +147,9 @@ impl Mmu {
         let this = Self {
             pages,
             mem: Memory::new()?,
+            bus: Bus::default(),
+            logging: AtomicBool::new(false),
+            writes: Mutex::new(Vec::new()),
         };
 
         Ok(this)
@@ -103,10 +165,9 @@ impl Mmu {
     /// Note: All page(s) covering the range are changed
     pub fn set_prot(&self, addr: impl Into<AddressRange>, prot: impl Into<Protection>) {
         let prot = prot.into();
-        let addr = addr.into().into_iter();
+        let AddressRange { start, end } = addr.into();
 
-        for addr in addr.step_by(PAGE_SIZE) {
-            let idx = page_idx!(addr);
+        for idx in page_idx!(start)..=page_idx!(end) {
             self.pages[idx].set_prot(prot);
         }
     }
@@ -119,20 +180,144 @@ impl Mmu {
         req: impl Into<Protection>,
     ) -> Result<(), MemError> {
         let req = req.into();
-        let addr = addr.into().into_iter();
+        let AddressRange { start, end } = addr.into();
 
-        for addr in addr.step_by(PAGE_SIZE) {
-            let idx = page_idx!(addr);
+        for idx in page_idx!(start)..=page_idx!(end) {
             let record = self.pages[idx].prot();
             if !record.contains(req) {
-                let i = !record & req;
-                return Err(MemError::PageFault(i));
+                // a user access to a supervisor page reports the whole access,
+                // so the kind of access (read/write/execute) isn't lost
+                let i = match !record & req {
+                    i if i == Prot::User => req,
+                    i => i,
+                };
+                // first faulting byte of the range
+                let addr = start.max((idx * PAGE_SIZE) as BitSize);
+                return Err(MemError::PageFault(i, addr));
             }
         }
 
         Ok(())
     }
 
+    /// Generation of the page holding addr, it changes whenever code
+    /// decoded from the page since [`Mmu::mark_code`] may be stale
+    #[inline]
+    pub fn code_gen(&self, addr: BitSize) -> BitSize {
+        self.pages[page_idx!(addr)]
+            .generation
+            .load(Ordering::SeqCst)
+    }
+
+    /// Note that instructions are about to be decoded from the page holding addr,
+    /// so writes to it bump its generation. Returns the current generation
+    pub fn mark_code(&self, addr: BitSize) -> BitSize {
+        let page = &self.pages[page_idx!(addr)];
+        page.code.store(true, Ordering::SeqCst);
+        page.generation.load(Ordering::SeqCst)
+    }
+
+    /// Start addresses of pages written since memory was last zeroed.
+    /// Writes through [`Mmu::mem_mut`] aren't tracked
+    pub fn dirty_pages(&self) -> impl Iterator<Item = BitSize> + '_ {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter(|(_, page)| page.dirty.load(Ordering::Relaxed))
+            .map(|(idx, _)| (idx * PAGE_SIZE) as BitSize)
+    }
+
+    /// Keep the address and length of every write to memory, until turned off
+    pub fn log_writes(&self, on: bool) {
+        self.logging.store(on, Ordering::SeqCst);
+    }
+
+    /// Writes logged so far, oldest first
+    pub fn take_writes(&self) -> Vec<(BitSize, BitSize)> {
+        std::mem::take(&mut self.writes.lock())
+    }
+
+    /// Bump the generation of all code pages in range
+    fn written(&self, addr: BitSize, len: usize) {
+        if self.logging.load(Ordering::Relaxed) {
+            self.writes.lock().push((addr, len as BitSize));
+        }
+
+        let end = addr.saturating_add((len as BitSize).saturating_sub(1));
+        for idx in page_idx!(addr)..=page_idx!(end) {
+            self.pages[idx].written();
+        }
+    }
+
+    /// Route loads and stores in range to dev instead of RAM.
+    /// Protection still applies, and page table walks and fetches always use RAM
+    pub fn map_device(
+        &self,
+        range: impl Into<AddressRange>,
+        dev: DeviceRef,
+    ) -> Result<(), MemError> {
+        let AddressRange { start, end } = range.into();
+        self.bus
+            .map(start, end, dev)
+            .map_err(MemError::DeviceOverlap)?;
+
+        for idx in page_idx!(start)..=page_idx!(end) {
+            self.pages[idx].io.store(true, Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+
+    /// Unmap the device covering addr
+    pub fn unmap_device(&self, addr: BitSize) -> Option<DeviceRef> {
+        let map = self.bus.unmap(addr)?;
+        let maps = self.bus.mappings();
+
+        // other devices may share the pages
+        let (start, end) = (map.start, map.end);
+        for idx in page_idx!(start)..=page_idx!(end) {
+            let first = (idx * PAGE_SIZE) as BitSize;
+            let last = first + (PAGE_SIZE - 1) as BitSize;
+            let shared = maps.iter().any(|m| m.start <= last && first <= m.end);
+            self.pages[idx].io.store(shared, Ordering::SeqCst);
+        }
+
+        Some(map.dev)
+    }
+
+    /// All mapped devices
+    pub fn devices(&self) -> Vec<Mapping> {
+        self.bus.mappings()
+    }
+
+    /// Whether addr may belong to a device, so loads and stores need to go through [`Mmu::io_read`]
+    #[inline]
+    pub fn is_io(&self, addr: BitSize) -> bool {
+        self.pages[page_idx!(addr)].io.load(Ordering::Relaxed)
+    }
+
+    /// Read from the device at addr, if there is one
+    pub fn io_read(&self, addr: BitSize, size: usize) -> Option<BitSize> {
+        self.bus.read(addr, size)
+    }
+
+    /// Write to the device at addr. False if there is none
+    pub fn io_write(&self, addr: BitSize, size: usize, val: BitSize) -> bool {
+        self.bus.write(addr, size, val)
+    }
+
+    /// Let devices advance their own state
+    #[inline]
+    pub fn tick_devices(&self, clk: u64) {
+        self.bus.tick(clk);
+    }
+
+    /// Whether any device raised its irq at the last tick
+    #[inline]
+    pub fn device_irq(&self) -> bool {
+        self.bus.irq()
+    }
+
     /// Access raw mem
     ///
     /// # Safety
@@ -142,7 +327,8 @@ impl Mmu {
         unsafe { self.mem.mem() }
     }
 
-    /// Access raw mutable mem
+    /// Access raw mutable mem. Writes through it don't invalidate decoded instructions
+    /// or mark pages dirty
     ///
     /// # Safety
     /// No read or writes of any kind are allowed while this slice is alive
@@ -158,11 +344,15 @@ impl Mmu {
 
     /// Write buffer to memory starting at addr
     pub fn memwrite(&self, addr: BitSize, buf: &[u8]) -> Result<(), MemError> {
-        self.mem.memwrite(addr, buf)
+        self.mem.memwrite(addr, buf)?;
+        self.written(addr, buf.len());
+        Ok(())
     }
 
     pub fn memset(&self, addr: BitSize, val: BitSize, count: BitSize) -> Result<(), MemError> {
-        self.mem.memset(addr, val, count)
+        self.mem.memset(addr, val, count)?;
+        self.written(addr, count as usize);
+        Ok(())
     }
 
     /// Read, but don't check protection
@@ -174,28 +364,85 @@ impl Mmu {
     // Write, but don't check protection
     pub fn write_unchecked<N: Copy + ToBytes>(&self, addr: BitSize, n: N) -> Result<(), MemError> {
         self.mem.write(addr, n)?;
+        self.written(addr, size_of::<N>());
         Ok(())
     }
 
     // Read with protection check
     pub fn read<N: FromBytes>(&self, addr: BitSize) -> Result<N, MemError> {
-        self.check_prot(addr, Prot::Read)?;
-        let n = self.mem.read(addr)?;
-        Ok(n)
+        self.read_with(addr, Prot::Read)
     }
 
     /// Write with protection check
     pub fn write<N: Copy + ToBytes>(&self, addr: BitSize, n: N) -> Result<(), MemError> {
-        self.check_prot(addr, Prot::Write)?;
+        self.write_with(addr, n, Prot::Write)
+    }
+
+    /// Read, requiring `req` protection on every page touched
+    pub fn read_with<N: FromBytes>(
+        &self,
+        addr: BitSize,
+        req: impl Into<Protection>,
+    ) -> Result<N, MemError> {
+        let end = addr.saturating_add((size_of::<N>() as BitSize).saturating_sub(1));
+        self.check_prot(addr..=end, req)?;
+        let n = self.mem.read(addr)?;
+        Ok(n)
+    }
+
+    /// Write, requiring `req` protection on every page touched
+    pub fn write_with<N: Copy + ToBytes>(
+        &self,
+        addr: BitSize,
+        n: N,
+        req: impl Into<Protection>,
+    ) -> Result<(), MemError> {
+        let end = addr.saturating_add((size_of::<N>() as BitSize).saturating_sub(1));
+        self.check_prot(addr..=end, req)?;
         self.mem.write(addr, n)?;
+        self.written(addr, size_of::<N>());
         Ok(())
     }
 
+    /// Atomic compare and swap of an aligned word, but don't check protection.
+    /// Returns the old value, the swap happened if it equals current
+    pub fn cas_unchecked(
+        &self,
+        addr: BitSize,
+        current: BitSize,
+        new: BitSize,
+    ) -> Result<BitSize, MemError> {
+        let old = self.mem.cas(addr, current, new)?;
+        self.written(addr, 4);
+        Ok(old)
+    }
+
+    /// Atomic add to an aligned word, but don't check protection. Returns the old value
+    pub fn fetch_add_unchecked(&self, addr: BitSize, val: BitSize) -> Result<BitSize, MemError> {
+        let old = self.mem.fetch_add(addr, val)?;
+        self.written(addr, 4);
+        Ok(old)
+    }
+
+    /// Atomic swap of an aligned word, but don't check protection. Returns the old value
+    pub fn swap_unchecked(&self, addr: BitSize, val: BitSize) -> Result<BitSize, MemError> {
+        let old = self.mem.swap(addr, val)?;
+        self.written(addr, 4);
+        Ok(old)
+    }
+
     /// Zeroes memory
     ///
     /// # Safety
     /// This function cannot be called while any views exist or read/write happen
     pub unsafe fn zeroize(&self) -> Result<(), MemError> {
-        unsafe { self.mem.zeroize() }
+        unsafe { self.mem.zeroize()? };
+
+        for page in &self.pages {
+            page.written();
+            page.dirty.store(false, Ordering::Relaxed);
+        }
+
+        Ok(())
     }
 }
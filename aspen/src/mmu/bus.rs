@@ -0,0 +1,132 @@
+use std::sync::{
+    Arc, LockResult, Mutex, RwLock,
+    atomic::{AtomicBool, Ordering},
+};
+
+use crate::{BitSize, dev::Device};
+
+/// Shared handle to a mapped device.
+///
+/// `sayuri::sync::Mutex` can't lock a `dyn Device`, so this is a std mutex. The bus
+/// ignores poisoning, so a device that panicked once doesn't break every later access
+pub type DeviceRef = Arc<Mutex<dyn Device>>;
+
+/// Take a lock, ignoring poisoning like `sayuri::sync::Mutex` does
+fn lock<G>(res: LockResult<G>) -> G {
+    res.unwrap_or_else(|e| e.into_inner())
+}
+
+/// Device and the inclusive range it answers to
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    pub start: BitSize,
+    pub end: BitSize,
+    pub dev: DeviceRef,
+    /// [`Device::ticks`] when it was mapped
+    ticks: bool,
+}
+
+/// Devices reachable through loads and stores
+#[derive(Debug, Default)]
+pub struct Bus {
+    maps: RwLock<Vec<Mapping>>,
+    /// skips taking the lock in [`Bus::tick`] when no device wants ticks
+    ticking: AtomicBool,
+    /// some device raised its irq at the last tick
+    irq: AtomicBool,
+}
+
+impl Bus {
+    /// Map dev, failing with the first overlapping address
+    pub fn map(&self, start: BitSize, end: BitSize, dev: DeviceRef) -> Result<(), BitSize> {
+        let mut maps = lock(self.maps.write());
+
+        if let Some(m) = maps.iter().find(|m| m.start <= end && start <= m.end) {
+            return Err(start.max(m.start));
+        }
+
+        let ticks = lock(dev.lock()).ticks();
+        maps.push(Mapping {
+            start,
+            end,
+            dev,
+            ticks,
+        });
+        self.ticking
+            .store(maps.iter().any(|m| m.ticks), Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Unmap the device covering addr
+    pub fn unmap(&self, addr: BitSize) -> Option<Mapping> {
+        let mut maps = lock(self.maps.write());
+
+        let idx = maps.iter().position(|m| m.contains(addr))?;
+        let map = maps.swap_remove(idx);
+        self.ticking
+            .store(maps.iter().any(|m| m.ticks), Ordering::Relaxed);
+        self.irq.store(false, Ordering::Relaxed);
+
+        Some(map)
+    }
+
+    pub fn mappings(&self) -> Vec<Mapping> {
+        lock(self.maps.read()).clone()
+    }
+
+    /// Device covering addr and the offset of addr into its range
+    fn find(&self, addr: BitSize) -> Option<(DeviceRef, BitSize)> {
+        let maps = lock(self.maps.read());
+        let m = maps.iter().find(|m| m.contains(addr))?;
+        Some((m.dev.clone(), addr - m.start))
+    }
+
+    pub fn read(&self, addr: BitSize, size: usize) -> Option<BitSize> {
+        let (dev, offset) = self.find(addr)?;
+        let val = lock(dev.lock()).read(offset, size);
+        Some(val)
+    }
+
+    pub fn write(&self, addr: BitSize, size: usize, val: BitSize) -> bool {
+        let Some((dev, offset)) = self.find(addr) else {
+            return false;
+        };
+
+        let val = match size {
+            1 => val as u8 as BitSize,
+            2 => val as u16 as BitSize,
+            _ => val,
+        };
+
+        lock(dev.lock()).write(offset, size, val);
+        true
+    }
+
+    #[inline]
+    pub fn tick(&self, clk: u64) {
+        if !self.ticking.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut irq = false;
+        for m in lock(self.maps.read()).iter().filter(|m| m.ticks) {
+            let mut dev = lock(m.dev.lock());
+            dev.tick(clk);
+            irq |= dev.irq();
+        }
+
+        self.irq.store(irq, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn irq(&self) -> bool {
+        self.irq.load(Ordering::Relaxed)
+    }
+}
+
+impl Mapping {
+    fn contains(&self, addr: BitSize) -> bool {
+        (self.start..=self.end).contains(&addr)
+    }
+}
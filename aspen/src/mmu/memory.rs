@@ -1,27 +1,37 @@
 use std::{
     ffi::c_void,
     marker::PhantomData,
+    ops::Range,
     slice,
-    sync::atomic::{AtomicU8, Ordering},
+    sync::atomic::{AtomicU32, Ordering},
 };
 
-#[cfg(windows)]
-use crate::mmu::MemError;
 use crate::{
     BitSize,
-    mmu::{MEM_SIZE, address_range::AddressRange},
+    mmu::{MEM_SIZE, MemError},
 };
 
+const WORDS: usize = MEM_SIZE / 4;
+
+// the guest is little endian, and the raw byte views have to agree with the words
+const _: () = assert!(cfg!(target_endian = "little"));
+
+/// Guest memory
+///
+/// Every access goes through the `AtomicU32` containing the bytes, never a smaller or
+/// larger atomic, so harts racing on the same word never mix access sizes. Accesses
+/// narrower than a word update it with a compare exchange, accesses spanning words
+/// aren't atomic as a whole
 #[doc(hidden)]
 #[derive(Debug)]
 pub struct Memory {
-    data: *mut [AtomicU8; MEM_SIZE],
-    phantom: PhantomData<Box<[AtomicU8; MEM_SIZE]>>,
+    data: *mut [AtomicU32; WORDS],
+    phantom: PhantomData<Box<[AtomicU32; WORDS]>>,
 }
 
 // We exclusively own and manage the memory
 unsafe impl Send for Memory {}
-// Reading and writing safety is guaranteed by the caller, the methods are unsafe
+// Every access is atomic, except through the raw views, whose callers guarantee exclusive use
 unsafe impl Sync for Memory {}
 
 impl Memory {
@@ -52,7 +62,7 @@ impl Memory {
         // we also already checked for a failed call
         // therefore this cast is valid
         let this = Self {
-            data: ptr.cast::<[_; MEM_SIZE]>(),
+            data: ptr.cast::<[_; WORDS]>(),
             phantom: PhantomData,
         };
 
@@ -61,10 +71,9 @@ impl Memory {
 
     #[cfg(unix)]
     pub fn new() -> Result<Self, MemError> {
-        use core::ptr::addr_eq;
-        use core::{mem::transmute, ptr::null_mut};
+        use core::ptr::{addr_eq, null_mut};
         use libc::{MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE, mmap};
-        use std::os::fd::BorrowedFd;
+        use std::sync::Arc;
 
         const INVALID_FD: i32 = -1;
 
@@ -90,25 +99,79 @@ impl Memory {
         // we also already checked for a failed call
         // therefore this cast is valid
         let this = Self {
-            data: ptr.cast::<[_; MEM_SIZE]>(),
+            data: ptr.cast::<[_; WORDS]>(),
             phantom: PhantomData,
         };
 
         Ok(this)
     }
 
-    fn slice(&self, addr: impl Into<AddressRange>) -> &[AtomicU8] {
-        let addr = addr.into();
+    /// The words covering `len` bytes from addr, each with the byte range inside it.
+    /// The caller checks that the range doesn't wrap
+    fn spans(&self, addr: BitSize, len: usize) -> impl Iterator<Item = (&AtomicU32, Range<usize>)> {
+        let first = addr as usize / 4;
+        let count = match len {
+            0 => 0,
+            _ => (addr as usize + len - 1) / 4 - first + 1,
+        };
 
         // SAFETY: addr is limited to BitSize, alloc is BitSize::MAX big
         // so it's within the alloc. Also, BitSize < isize::MAX (see assert above)
         const { assert!((BitSize::MAX as usize) <= isize::MAX as usize) }
-        let ptr = unsafe { self.data.cast::<AtomicU8>().add(addr.start as usize) };
+        let ptr = unsafe { self.data.cast::<AtomicU32>().add(first) };
+        let words = unsafe { slice::from_raw_parts(ptr, count) };
+
+        let mut offset = addr as usize % 4;
+        let mut left = len;
+
+        words.iter().map(move |word| {
+            let n = (4 - offset).min(left);
+            let range = offset..offset + n;
+
+            offset = 0;
+            left -= n;
+
+            (word, range)
+        })
+    }
+
+    /// Store the bytes of val in range, leaving the rest of the word alone
+    fn merge(word: &AtomicU32, val: u32, range: Range<usize>) {
+        if range.len() == 4 {
+            word.store(val, Ordering::Relaxed);
+            return;
+        }
+
+        let mask = ((1u64 << (range.end * 8)) - (1u64 << (range.start * 8))) as u32;
+        let _ = word.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+            Some(old & !mask | val & mask)
+        });
+    }
+
+    fn load_bytes(&self, addr: BitSize, buf: &mut [u8]) {
+        let mut buf = buf;
 
-        // do not wraparound since that would pointlessly cause a massive slice
-        let len = addr.end.saturating_sub(addr.start.saturating_sub(1));
+        for (word, range) in self.spans(addr, buf.len()) {
+            let bytes = word.load(Ordering::Relaxed).to_le_bytes();
+            let (head, rest) = buf.split_at_mut(range.len());
 
-        unsafe { slice::from_raw_parts(ptr, len as usize) }
+            head.copy_from_slice(&bytes[range]);
+            buf = rest;
+        }
+    }
+
+    fn store_bytes(&self, addr: BitSize, buf: &[u8]) {
+        let mut buf = buf;
+
+        for (word, range) in self.spans(addr, buf.len()) {
+            let (head, rest) = buf.split_at(range.len());
+
+            let mut bytes = [0; 4];
+            bytes[range.clone()].copy_from_slice(head);
+            Self::merge(word, u32::from_le_bytes(bytes), range);
+
+            buf = rest;
+        }
     }
 
     /// Write to an address.
@@ -117,29 +180,21 @@ impl Memory {
         val.to_le_bytes(&mut buf);
 
         // 0 is inclusive and 0+size-1 is also inclusive, so sub 1 is important here for the overflow check
-        let end = addr
-            .checked_add((size_of::<N>() as BitSize).saturating_sub(1))
+        addr.checked_add((size_of::<N>() as BitSize).saturating_sub(1))
             .ok_or(MemError::Overflow)?;
 
-        let data = self.slice(addr..=end);
-
-        for (a, v) in data.iter().zip(buf) {
-            a.store(v, Ordering::Relaxed);
-        }
+        self.store_bytes(addr, buf.as_ref());
 
         Ok(())
     }
 
     /// Read an address.
     pub fn read<N: FromBytes>(&self, addr: BitSize) -> Result<N, MemError> {
-        let end = addr
-            .checked_add((size_of::<N>() as BitSize).saturating_sub(1))
+        addr.checked_add((size_of::<N>() as BitSize).saturating_sub(1))
             .ok_or(MemError::Overflow)?;
 
-        let data = self.slice(addr..=end);
-
         let mut buf = N::Buf::default();
-        N::copy_from_atomic_slice(&mut buf, data);
+        self.load_bytes(addr, buf.as_mut());
 
         let n = N::from_le_bytes(&buf);
         Ok(n)
@@ -147,53 +202,69 @@ impl Memory {
 
     /// Starting at addr, copies buf.len bytes into buf
     pub fn memcpy(&self, addr: BitSize, buf: &mut [u8]) -> Result<(), MemError> {
-        let end = addr
-            .checked_add(buf.len().saturating_sub(1) as _)
+        addr.checked_add(buf.len().saturating_sub(1) as _)
             .ok_or(MemError::Overflow)?;
 
-        let data = self.slice(addr..=end);
-
-        for (a, b) in data.iter().zip(buf) {
-            *b = a.load(Ordering::Relaxed);
-        }
+        self.load_bytes(addr, buf);
 
         Ok(())
     }
 
     /// Write to mem using memcpy
     pub fn memwrite(&self, addr: BitSize, buf: &[u8]) -> Result<(), MemError> {
-        let end = addr
-            .checked_add(buf.len().saturating_sub(1) as _)
+        addr.checked_add(buf.len().saturating_sub(1) as _)
             .ok_or(MemError::Overflow)?;
 
-        let data = self.slice(addr..=end);
-
-        for (a, b) in data.iter().zip(buf) {
-            a.store(*b, Ordering::Relaxed);
-        }
+        self.store_bytes(addr, buf);
 
         Ok(())
     }
 
     /// Write val N to mem C times starting at addr
     pub fn memset(&self, addr: BitSize, val: BitSize, count: BitSize) -> Result<(), MemError> {
-        let end = addr
-            .checked_add(count.saturating_sub(1) as _)
+        addr.checked_add(count.saturating_sub(1) as _)
             .ok_or(MemError::Overflow)?;
 
-        let data = self.slice(addr..=end);
-
-        let val = val.to_le_bytes();
+        // the pattern starts at addr, not at the start of its word
+        let val = val.rotate_left(addr % 4 * 8);
 
-        for a in data.chunks(4) {
-            for (a, v) in a.iter().zip(val) {
-                a.store(v, Ordering::Relaxed);
-            }
+        for (word, range) in self.spans(addr, count as usize) {
+            Self::merge(word, val, range);
         }
 
         Ok(())
     }
 
+    /// Aligned word, for read-modify-write ops
+    fn word(&self, addr: BitSize) -> Result<&AtomicU32, MemError> {
+        if !addr.is_multiple_of(4) {
+            return Err(MemError::Misaligned(addr));
+        }
+
+        let (word, _) = self.spans(addr, 4).next().expect("one word");
+        Ok(word)
+    }
+
+    /// Store new if the word at addr is current. Returns the old value either way
+    pub fn cas(&self, addr: BitSize, current: u32, new: u32) -> Result<u32, MemError> {
+        let word = self.word(addr)?;
+        let old = word
+            .compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst)
+            .unwrap_or_else(|old| old);
+
+        Ok(old)
+    }
+
+    /// Wrapping add to the word at addr, returning the old value
+    pub fn fetch_add(&self, addr: BitSize, val: u32) -> Result<u32, MemError> {
+        Ok(self.word(addr)?.fetch_add(val, Ordering::SeqCst))
+    }
+
+    /// Replace the word at addr, returning the old value
+    pub fn swap(&self, addr: BitSize, val: u32) -> Result<u32, MemError> {
+        Ok(self.word(addr)?.swap(val, Ordering::SeqCst))
+    }
+
     /// Access raw mem
     ///
     /// # Safety
@@ -259,7 +330,7 @@ impl Memory {
     ///
     /// No other reads/writes must be happening, or views can exist, until this is finished
     #[cfg(unix)]
-    pub unsafe fn zeroize(&mut self) -> Result<(), MemError> {
+    pub unsafe fn zeroize(&self) -> Result<(), MemError> {
         let ptr = self.data.cast::<c_void>();
 
         // SAFETY:
@@ -302,7 +373,7 @@ impl Drop for Memory {
 }
 
 pub trait ToBytes {
-    type Buf: Default + IntoIterator<Item = u8>;
+    type Buf: Default + AsRef<[u8]>;
 
     fn to_ne_bytes(self, buf: &mut Self::Buf);
     fn to_le_bytes(self, buf: &mut Self::Buf);
@@ -335,9 +406,7 @@ macro_rules! impl_to_bytes {
 impl_to_bytes! { u8 i8 u16 i16 u32 i32 u64 i64 u128 i128 usize isize f32 f64 }
 
 pub trait FromBytes {
-    type Buf: Default;
-
-    fn copy_from_atomic_slice(buf: &mut Self::Buf, data: &[AtomicU8]);
+    type Buf: Default + AsMut<[u8]>;
 
     fn from_ne_bytes(buf: &Self::Buf) -> Self;
     fn from_le_bytes(buf: &Self::Buf) -> Self;
@@ -349,12 +418,6 @@ macro_rules! impl_from_bytes {
         impl FromBytes for $ty {
             type Buf = [u8; size_of::<Self>()];
 
-            fn copy_from_atomic_slice(buf: &mut Self::Buf, data: &[AtomicU8]) {
-                for (buf_byte, byte) in buf.iter_mut().zip(data) {
-                    *buf_byte = byte.load(Ordering::Relaxed);
-                }
-            }
-
             fn from_ne_bytes(buf: &Self::Buf) -> Self {
                 Self::from_ne_bytes(*buf)
             }
@@ -1,13 +1,15 @@
 use std::ptr;
 use std::time::Instant;
 
-use aspen::emulator::Emulator;
+use aspen::dev::Devices;
+use aspen::emulator::{Emulator, Engine};
 use sayuri::macros::stringify_raw;
 
 fn main() {
-    let mut emu = Emulator::new(&[]).expect("creation to succeed");
+    let run = |asm, engine| {
+        let mut emu =
+            Emulator::with_engine(&[], Devices::default(), engine).expect("creation to succeed");
 
-    let mut run = |asm| {
         let asm = format!("{asm}\n\n; auto inserted\nhlt");
 
         let data = match graft::assemble("<input>.asm", &asm) {
@@ -42,16 +44,17 @@ fn main() {
         emu.run().expect("run to succeed");
 
         let elapsed = start.elapsed();
+        let ns = elapsed.as_nanos() as f64 / emu.cpu.clk as f64;
 
         println!(
-            "instrs = {}, elapsed = {:?}, ns/instr = {:.3?}, mips = {:.3?}",
+            "{engine:?}: instrs = {}, elapsed = {:?}, ns/instr = {:.3?}, mips = {:.3?}",
             emu.cpu.clk,
             elapsed,
-            elapsed.as_nanos() as f64 / emu.cpu.clk as f64,
+            ns,
             emu.cpu.clk as f64 / elapsed.as_micros() as f64
         );
 
-        Ok::<(), ()>(())
+        ns
     };
 
     let code = stringify_raw! {
@@ -91,5 +94,14 @@ fn main() {
             hlt
     };
 
-    run(code).unwrap();
+    let interp = run(code, Engine::Interpreter);
+    let threaded = run(code, Engine::Threaded);
+
+    println!("threaded speedup = {:.2}x", interp / threaded);
+
+    #[cfg(feature = "jit")]
+    {
+        let jit = run(code, Engine::Jit);
+        println!("jit speedup = {:.2}x", interp / jit);
+    }
 }
@@ -95,11 +95,37 @@
     r31 => 0x1f
 }
 
+#subruledef ctrl
+{
+    tvec   => 0x00 ; trap handler address, 0 disables trapping
+    tepc   => 0x01 ; pc of the instruction that trapped
+    tcause => 0x02 ; trap cause
+    tval   => 0x03 ; faulting address
+    status => 0x04 ; cpu state flags, bit 0 = interrupts enabled, bit 1 = user mode,
+                   ; bit 2 = user mode before trap, bit 3 = user mode before interrupt,
+                   ; bit 4 = interrupts enabled before trap (traps disable them until trapret)
+    ie     => 0x05 ; enabled interrupt sources, bit 0 = timer, bit 1 = keyboard
+    ip     => 0x06 ; pending interrupt sources
+    ivec   => 0x07 ; interrupt handler address, 0 disables interrupts
+    iepc   => 0x08 ; pc to resume at after the interrupt handler
+    icause => 0x09 ; interrupt source being handled
+    timer  => 0x0a ; write N to fire the timer interrupt after N cycles, 0 disarms
+    ptbr   => 0x0b ; page table address, 0 turns translation off
+    hartid => 0x0c ; id of the running hart, 0 is the boot hart (read only, also in user mode)
+}
+
 #subruledef immediate_be
 {
     {immediate: i32}  => immediate
 }
 
+; signed offset from a base, written `+ 4` or `- 4`
+#subruledef offset
+{
+    + {o: i32} => o
+    - {o: i32} => -o
+}
+
 #ruledef immediate
 {
     {val: immediate_be} => val[7:0] @ val[15:8] @ val[23:16] @ val[31:24]
@@ -109,7 +135,9 @@
 {
     nop => 0x00000000
 
+    ; stop, with exit code a (0 without one)
     hlt => (0`2 @ 0b0 @ 0`5) @ 0x01 @ 0x00 @ 0x00
+    hlt {a: register} => (0`2 @ 0b0 @ 0`5) @ 0x01 @ a @ 0x00
 
     ; console (utf-8)
     pr {a: register}, {b: register}  => (0`2 @ 0b0 @ 0`5) @ 0x02 @ a @ b
@@ -138,6 +166,40 @@
     smem [{d: register}], {c: register}, {a: register} => (0`2 @ 0b0 @ d`5) @ 0x0c @ a @ c
     smem [{d: register}], {c: register}, {i: immediate} => (0`2 @ 0b0 @ d`5) @ 0x0c @ 0x00 @ c @ i
 
+    ; control registers
+    rdcr {d: register}, {c: ctrl} => (0`2 @ 0b0 @ d`5) @ 0x0d @ c @ 0x00
+    wrcr {c: ctrl}, {a: register} => (0`2 @ 0b0 @ c`5) @ 0x0e @ a @ 0x00
+    wrcr {c: ctrl}, {i: immediate} => (0`2 @ 0b1 @ c`5) @ 0x0e @ 0x00 @ 0x00 @ i
+
+    ; return from trap handler to tepc
+    trapret => (0`2 @ 0b0 @ 0`5) @ 0x0f @ 0x00 @ 0x00
+
+    ; interrupts
+    ei => (0`2 @ 0b0 @ 0`5) @ 0x10 @ 0x00 @ 0x00
+    di => (0`2 @ 0b0 @ 0`5) @ 0x11 @ 0x00 @ 0x00
+    ; return from interrupt handler to iepc, enabling interrupts
+    iret => (0`2 @ 0b0 @ 0`5) @ 0x12 @ 0x00 @ 0x00
+
+    ; set protection of the pages covering [a, b)
+    ; mask: 0b0001 = read, 0b0010 = write, 0b0100 = execute, 0b1000 = user
+    mprot {a: register}, {b: register}, {d: register} =>
+        (0`2 @ 0b0 @ d`5) @ 0x13 @ a @ b
+    mprot {a: register}, {b: register}, {i: immediate} =>
+        (0`2 @ 0b1 @ 0`5) @ 0x13 @ a @ b @ i
+
+    ; drop cached page translations after editing the page table
+    tlbflush => (0`2 @ 0b0 @ 0`5) @ 0x14 @ 0x00 @ 0x00
+
+    ; start parked hart a at address b, with a0 = d
+    ; the hart starts with fresh registers, so it needs its own sp
+    hstart {a: register}, {b: register}, {d: register} =>
+        (0`2 @ 0b0 @ d`5) @ 0x15 @ a @ b
+    hstart {a: register}, {b: register}, {i: immediate} =>
+        (0`2 @ 0b1 @ 0`5) @ 0x15 @ a @ b @ i
+
+    ; call host handler a7, results in a0 / a1
+    ecall => (0`2 @ 0b0 @ 0`5) @ 0x16 @ 0x00 @ 0x00
+
     ; ld mem
 
     ld {d: register}, [{a: register}] =>
@@ -155,6 +217,22 @@
     ld.b {d: register}, [{i: immediate}] =>
         (0`2 @ 0b1 @ d`5) @ 0x22 @ 0x00 @ 0x00 @ i
 
+    ; sign-extending
+
+    ld.ws {d: register}, [{a: register}] =>
+        (0`2 @ 0b0 @ d`5) @ 0x39 @ a @ 0x00
+    ld.ws {d: register}, [{i: immediate}] =>
+        (0`2 @ 0b1 @ d`5) @ 0x39 @ 0x00 @ 0x00 @ i
+    ld.ws {d: register}, [{a: register} {o: offset}] =>
+        (0`2 @ 0b1 @ d`5) @ 0x3b @ a @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+
+    ld.bs {d: register}, [{a: register}] =>
+        (0`2 @ 0b0 @ d`5) @ 0x3a @ a @ 0x00
+    ld.bs {d: register}, [{i: immediate}] =>
+        (0`2 @ 0b1 @ d`5) @ 0x3a @ 0x00 @ 0x00 @ i
+    ld.bs {d: register}, [{a: register} {o: offset}] =>
+        (0`2 @ 0b1 @ d`5) @ 0x3c @ a @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+
     ; ld storage
 
     pld {d: register}, [{a: register}] =>
@@ -189,11 +267,37 @@
     str.b [{d: register}], {i: immediate} =>
         (0`2 @ 0b1 @ d`5) @ 0x28 @ 0x00 @ 0x00 @ i
 
+    ; base register + offset, e.g. `ld t0, [s0 + 8]`
+
+    ld {d: register}, [{a: register} {o: offset}] =>
+        (0`2 @ 0b1 @ d`5) @ 0x30 @ a @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    ld.w {d: register}, [{a: register} {o: offset}] =>
+        (0`2 @ 0b1 @ d`5) @ 0x31 @ a @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    ld.b {d: register}, [{a: register} {o: offset}] =>
+        (0`2 @ 0b1 @ d`5) @ 0x32 @ a @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+
+    str [{d: register} {o: offset}], {a: register} =>
+        (0`2 @ 0b1 @ d`5) @ 0x33 @ a @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    str.w [{d: register} {o: offset}], {a: register} =>
+        (0`2 @ 0b1 @ d`5) @ 0x34 @ a @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    str.b [{d: register} {o: offset}], {a: register} =>
+        (0`2 @ 0b1 @ d`5) @ 0x35 @ a @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+
+    ; pc-relative, from the start of the instruction
+    ; `ld t0, [pc + data - $]` loads from the label data wherever the code is placed
+
+    ld {d: register}, [pc {o: offset}] =>
+        (0`2 @ 0b1 @ d`5) @ 0x36 @ 0x00 @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    ld.w {d: register}, [pc {o: offset}] =>
+        (0`2 @ 0b1 @ d`5) @ 0x37 @ 0x00 @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    ld.b {d: register}, [pc {o: offset}] =>
+        (0`2 @ 0b1 @ d`5) @ 0x38 @ 0x00 @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+
     ; st storage
 
     pstr [{d: register}], {a: register} =>
         (0`2 @ 0b0 @ d`5) @ 0x29 @ a @ 0x00
-    pstr st[{d: register}], {i: immediate} =>
+    pstr [{d: register}], {i: immediate} =>
         (0`2 @ 0b1 @ d`5) @ 0x29 @ 0x00 @ 0x00 @ i
 
     pstr.w [{d: register}], {a: register} =>
@@ -206,6 +310,28 @@
     pstr.b [{d: register}], {i: immediate} =>
         (0`2 @ 0b1 @ d`5) @ 0x2b @ 0x00 @ 0x00 @ i
 
+    ; atomics, on word aligned addresses only
+    ; each gives d the old value of [a]
+
+    ; if [a] == d, [a] = b
+    cas {d: register}, [{a: register}], {b: register} =>
+        (0`2 @ 0b0 @ d`5) @ 0x2c @ a @ b
+
+    ; [a] += b
+    amoadd {d: register}, [{a: register}], {b: register} =>
+        (0`2 @ 0b0 @ d`5) @ 0x2d @ a @ b
+    amoadd {d: register}, [{a: register}], {i: immediate} =>
+        (0`2 @ 0b1 @ d`5) @ 0x2d @ a @ 0x00 @ i
+
+    ; [a] = b
+    amoswap {d: register}, [{a: register}], {b: register} =>
+        (0`2 @ 0b0 @ d`5) @ 0x2e @ a @ b
+    amoswap {d: register}, [{a: register}], {i: immediate} =>
+        (0`2 @ 0b1 @ d`5) @ 0x2e @ a @ 0x00 @ i
+
+    ; order all memory accesses before it with all after it
+    fence => (0`2 @ 0b0 @ 0`5) @ 0x2f @ 0x00 @ 0x00
+
     ;
     ; math
     ;
@@ -265,6 +391,22 @@
     imul {d: register}, {a: register}, {i: immediate} =>
         (1`2 @ 0b1 @ d`5) @ 0x0a @ a @ 0x00 @ i
 
+    ; high 32 bits of the signed / unsigned product
+    mulh {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x30 @ a @ b
+    mulh {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x30 @ a @ 0x00 @ i
+    mulhu {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x31 @ a @ b
+    mulhu {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x31 @ a @ 0x00 @ i
+
+    ; d = a + b + c / a - b - c, then c = carry / borrow out
+    addc {d: register}, {a: register}, {b: register}, {c: register} =>
+        (1`2 @ 0b1 @ d`5) @ 0x32 @ a @ b @ c @ 0x00 @ 0x00 @ 0x00
+    subb {d: register}, {a: register}, {b: register}, {c: register} =>
+        (1`2 @ 0b1 @ d`5) @ 0x33 @ a @ b @ c @ 0x00 @ 0x00 @ 0x00
+
     div {d: register}, {a: register}, {b: register} =>
         (1`2 @ 0b0 @ d`5) @ 0x0b @ a @ b
     div {d: register}, {a: register}, {i: immediate} =>
@@ -349,6 +491,122 @@
     not {a: register}, {b: register} => asm { nor {a}, zr, {b} }
     not {a: register}, {b: immediate} => asm { nor {a}, zr, {b} }
 
+    ;
+    ; float
+    ;
+    ; registers hold f32 bit patterns, immediates are written as their bits too
+    ; (1.0 = 0x3f800000), except for itof / utof which take an integer
+
+    fadd {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x19 @ a @ b
+    fadd {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x19 @ a @ 0x00 @ i
+
+    fsub {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x1a @ a @ b
+    fsub {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x1a @ a @ 0x00 @ i
+
+    fmul {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x1b @ a @ b
+    fmul {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x1b @ a @ 0x00 @ i
+
+    fdiv {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x1c @ a @ b
+    fdiv {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x1c @ a @ 0x00 @ i
+
+    fsqrt {d: register}, {a: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x1d @ a @ 0x00
+
+    ; compares set d to 1 or 0, anything compared with NaN is 0
+    fse {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x1e @ a @ b
+    fse {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x1e @ a @ 0x00 @ i
+
+    fsl {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x1f @ a @ b
+    fsl {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x1f @ a @ 0x00 @ i
+
+    fsle {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x20 @ a @ b
+    fsle {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x20 @ a @ 0x00 @ i
+
+    ; signed / unsigned int to float
+    itof {d: register}, {a: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x21 @ a @ 0x00
+    itof {d: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x21 @ 0x00 @ 0x00 @ i
+    utof {d: register}, {a: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x22 @ a @ 0x00
+    utof {d: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x22 @ 0x00 @ 0x00 @ i
+
+    ; float to signed / unsigned int, truncating and saturating, NaN is 0
+    ftoi {d: register}, {a: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x23 @ a @ 0x00
+    ftou {d: register}, {a: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x24 @ a @ 0x00
+
+    fsg {d: register}, {a: register}, {b: register} => asm { fsl {d}, {b}, {a} }
+    fsge {d: register}, {a: register}, {b: register} => asm { fsle {d}, {b}, {a} }
+    fneg {d: register}, {a: register} => asm { xor {d}, {a}, 0x80000000 }
+    fabs {d: register}, {a: register} => asm { and {d}, {a}, 0x7fffffff }
+
+    ;
+    ; bits
+    ;
+
+    ; count leading / trailing zeros, 32 for 0
+    clz {d: register}, {a: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x25 @ a @ 0x00
+    ctz {d: register}, {a: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x26 @ a @ 0x00
+    popcnt {d: register}, {a: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x27 @ a @ 0x00
+
+    ; rotate by b mod 32
+    rol {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x28 @ a @ b
+    rol {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x28 @ a @ 0x00 @ i
+    ror {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x29 @ a @ b
+    ror {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x29 @ a @ 0x00 @ i
+
+    ; reverse the byte order
+    bswap {d: register}, {a: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x2a @ a @ 0x00
+
+    ; signed / unsigned
+    min {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x2b @ a @ b
+    min {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x2b @ a @ 0x00 @ i
+    max {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x2c @ a @ b
+    max {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x2c @ a @ 0x00 @ i
+    minu {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x2d @ a @ b
+    minu {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x2d @ a @ 0x00 @ i
+    maxu {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x2e @ a @ b
+    maxu {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x2e @ a @ 0x00 @ i
+
+    ; d = d != 0 ? a : b
+    csel {d: register}, {a: register}, {b: register} =>
+        (1`2 @ 0b0 @ d`5) @ 0x2f @ a @ b
+    csel {d: register}, {a: register}, {i: immediate} =>
+        (1`2 @ 0b1 @ d`5) @ 0x2f @ a @ 0x00 @ i
+
     ;
     ; cond
     ;
@@ -376,6 +634,20 @@
     ja {a: register}, {b:register}, {d: register}   => (2`2 @ 0b1 @ d`5) @ 0x0a @ a @ b
     ja {a: register}, {b:register}, {i: immediate}  => (2`2 @ 0b1 @ 0`5) @ 0x0a @ a @ b @ i
 
+    ; pc-relative, from the start of the instruction, e.g. `jmp pc + loop - $`
+
+    jmp pc {o: offset}                        => (2`2 @ 0b1 @ 0`5) @ 0x0b @ 0x00 @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    je {a: register}, {b:register}, pc {o: offset}  => (2`2 @ 0b1 @ 0`5) @ 0x0c @ a @ b @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    jne {a: register}, {b:register}, pc {o: offset} => (2`2 @ 0b1 @ 0`5) @ 0x0d @ a @ b @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    jl {a: register}, {b:register}, pc {o: offset}  => (2`2 @ 0b1 @ 0`5) @ 0x0e @ a @ b @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    jge {a: register}, {b:register}, pc {o: offset} => (2`2 @ 0b1 @ 0`5) @ 0x0f @ a @ b @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    jle {a: register}, {b:register}, pc {o: offset} => (2`2 @ 0b1 @ 0`5) @ 0x10 @ a @ b @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    jg {a: register}, {b:register}, pc {o: offset}  => (2`2 @ 0b1 @ 0`5) @ 0x11 @ a @ b @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    jb {a: register}, {b:register}, pc {o: offset}  => (2`2 @ 0b1 @ 0`5) @ 0x12 @ a @ b @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    jae {a: register}, {b:register}, pc {o: offset} => (2`2 @ 0b1 @ 0`5) @ 0x13 @ a @ b @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    jbe {a: register}, {b:register}, pc {o: offset} => (2`2 @ 0b1 @ 0`5) @ 0x14 @ a @ b @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+    ja {a: register}, {b:register}, pc {o: offset}  => (2`2 @ 0b1 @ 0`5) @ 0x15 @ a @ b @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
+
     ; zero instructions
 
     jez {a: register}, {d: register} => asm { je {a}, zr, {d} }
@@ -394,6 +666,9 @@
     ;
     ; stack
     ;
+    ; sp points at the last pushed word and moves by 4.
+    ; call pushes the old ra, ret pops it back
+    ;
 
     push {a: register} => (3`2 @ 0b0 @ 0`5) @ 0x00 @ a @ 0x00
 
@@ -401,6 +676,7 @@
 
     call {a: register}  => (3`2 @ 0b0 @ 0`5) @ 0x02 @ a @ 0x00
     call {i: immediate} => (3`2 @ 0b1 @ 0`5) @ 0x02 @ 0x00 @ 0x00 @ i
+    call pc {o: offset} => (3`2 @ 0b1 @ 0`5) @ 0x04 @ 0x00 @ 0x00 @ o[7:0] @ o[15:8] @ o[23:16] @ o[31:24]
 
     ret => (3`2 @ 0b0 @ 0`5) @ 0x03 @ 0x00 @ 0x00
 }
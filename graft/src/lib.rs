@@ -17,6 +17,11 @@ pub enum AsmError {
 }
 
 pub fn assemble(filename: &str, asm: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_with_symbols(filename, asm).map(|(data, _)| data)
+}
+
+/// Also returns the labels, one `name = 0xaddr` per line
+pub fn assemble_with_symbols(filename: &str, asm: &str) -> Result<(Vec<u8>, String), AsmError> {
     // quite sad we have to leak this cause of the api
     #[rustfmt::skip]
     let input_file = format!(r#"
@@ -35,6 +40,10 @@ pub fn assemble(filename: &str, asm: &str) -> Result<Vec<u8>, AsmError> {
     let assembly = asm::assemble(&mut report, &opts, &mut fileserver, &[filename]);
 
     let data = assembly.output.map(|o| o.format_binary());
+    let symbols = match (&assembly.decls, &assembly.defs) {
+        (Some(decls), Some(defs)) => decls.symbols.format_default(decls, defs),
+        _ => String::new(),
+    };
 
     if report.has_errors() {
         let mut errors = BufWriter::new(Vec::new());
@@ -51,5 +60,5 @@ pub fn assemble(filename: &str, asm: &str) -> Result<Vec<u8>, AsmError> {
         return Err(AsmError::Error(errors));
     }
 
-    data.ok_or(AsmError::NoOutput)
+    data.map(|d| (d, symbols)).ok_or(AsmError::NoOutput)
 }
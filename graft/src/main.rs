@@ -1,15 +1,15 @@
 use std::{env, fs, path::Path};
 
-use graft::assemble;
+use graft::assemble_with_symbols;
 
 fn main() {
     let Some(input_file) = env::args().nth(1) else {
-        println!("symasm <input.asm> <output>");
+        println!("symasm <input.asm> <output> [symbols]");
         return;
     };
 
     let Some(output_file) = env::args().nth(2) else {
-        println!("symasm <input.asm> <output>");
+        println!("symasm <input.asm> <output> [symbols]");
         return;
     };
 
@@ -28,7 +28,7 @@ fn main() {
     };
     let filename = &*filename.to_string_lossy();
 
-    let data = match assemble(filename, &input_file) {
+    let (data, symbols) = match assemble_with_symbols(filename, &input_file) {
         Ok(bin) => bin,
         Err(e) => {
             eprintln!("{e}");
@@ -41,5 +41,12 @@ fn main() {
         return;
     }
 
+    if let Some(symbols_file) = env::args().nth(3)
+        && let Err(e) = fs::write(&symbols_file, symbols)
+    {
+        eprintln!("failed to save symbols file:\n{e}");
+        return;
+    }
+
     println!("saved to {output_file}");
 }